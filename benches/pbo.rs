@@ -0,0 +1,29 @@
+use std::fs::{File, create_dir};
+use std::io::{Write};
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use tempfile::{tempdir};
+
+use armake2::pbo::*;
+
+fn bench_pbo_write(c: &mut Criterion) {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    create_dir(&addon).unwrap();
+
+    for i in 0..1000 {
+        File::create(addon.join(format!("fn_function{}.sqf", i))).unwrap().write_all(b"params [\"_unit\"];\nhint str _unit;\n").unwrap();
+    }
+
+    let pbo = PBO::from_directory(addon, false, &Vec::new(), &Vec::new()).unwrap();
+    let output = dir.path().join("addon.pbo");
+
+    c.bench_function("pbo_write", move |b| b.iter(|| {
+        let mut file = File::create(&output).unwrap();
+
+        pbo.write(&mut file).unwrap();
+    }));
+}
+
+criterion_group!(benches, bench_pbo_write);
+criterion_main!(benches);
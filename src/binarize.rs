@@ -1,9 +1,9 @@
-//! Functions for calling BI's binarize.exe (on Windows)
+//! Functions for calling BI's binarize.exe (on Windows, or through Wine elsewhere)
 
-use std::env::{var, temp_dir};
-use std::fs::{File, create_dir_all, remove_dir_all};
+use std::env::{var, var_os, split_paths, temp_dir};
+use std::fs::{File, copy, create_dir_all, remove_dir_all};
 use std::io::{Read, Write, Cursor, Error};
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 
 #[cfg(windows)]
@@ -12,7 +12,10 @@ use winreg::RegKey;
 use winreg::enums::*;
 
 use crate::*;
+use crate::config::*;
 use crate::error::*;
+use crate::p3d::*;
+use crate::preprocess::{find_absolute_path, pathsep};
 
 #[cfg(windows)]
 fn find_binarize_exe() -> Result<PathBuf, Error> {
@@ -28,6 +31,74 @@ fn find_binarize_exe() -> Result<PathBuf, Error> {
     unreachable!();
 }
 
+/// How binarize.exe is run
+enum Runner {
+    Native(PathBuf),
+    Wine { wine: PathBuf, exe: PathBuf },
+}
+
+impl Runner {
+    /// Finds binarize.exe in the registry on Windows. Elsewhere, the given path or the
+    /// `BINARIZE_EXE` environment variable is used, if `wine` is in the `PATH`.
+    fn find(exe: Option<&Path>) -> Result<Runner, Error> {
+        if cfg!(windows) {
+            let binarize_exe = find_binarize_exe().prepend_error("Failed to find BI's binarize.exe:")?;
+            if !binarize_exe.exists() {
+                return Err(error!("BI's binarize.exe found in registry, but doesn't exist."));
+            }
+
+            return Ok(Runner::Native(binarize_exe));
+        }
+
+        let exe = match exe {
+            Some(exe) => exe.to_path_buf(),
+            None => PathBuf::from(var("BINARIZE_EXE").map_err(|_| error!("binarize.exe is only available on windows or through Wine with BINARIZE_EXE set. Use rapify to binarize configs."))?)
+        };
+        if !exe.exists() {
+            return Err(error!("binarize.exe not found at {:?}.", exe));
+        }
+
+        let wine = var_os("PATH").and_then(|paths| split_paths(&paths).map(|p| p.join("wine")).find(|p| p.is_file()))
+            .ok_or_else(|| error!("Wine is required to run binarize.exe on this system, but wasn't found in the PATH."))?;
+
+        Ok(Runner::Wine { wine, exe })
+    }
+
+    fn command(&self) -> Command {
+        match self {
+            Runner::Native(exe) => Command::new(exe),
+            Runner::Wine { wine, exe } => {
+                let mut command = Command::new(wine);
+                command.arg(exe);
+                command
+            }
+        }
+    }
+
+    /// Returns the path as seen by binarize.exe, translated with `winepath` when using Wine.
+    fn path(&self, path: &Path) -> Result<String, Error> {
+        match self {
+            Runner::Native(_) => Ok(path.to_str().unwrap().to_string()),
+            Runner::Wine { wine, .. } => {
+                let output = Command::new(wine.with_file_name("winepath")).arg("-w").arg(path).output()
+                    .prepend_error("Failed to run winepath:")?;
+                if !output.status.success() {
+                    return Err(error!("winepath failed to translate {:?}.", path));
+                }
+
+                Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+            }
+        }
+    }
+}
+
+/// Returns whether binarize.exe can be used on this system, see `binarize_with_options`.
+///
+/// Always true on Windows, where a missing binarize.exe is reported as an error when binarizing.
+pub fn is_available(exe: Option<&Path>) -> bool {
+    cfg!(windows) || Runner::find(exe).is_ok()
+}
+
 fn create_temp_directory(name: &str) -> Result<PathBuf, Error> {
     let dir = temp_dir();
     let mut i = 0;
@@ -45,28 +116,139 @@ fn create_temp_directory(name: &str) -> Result<PathBuf, Error> {
     Ok(path)
 }
 
-/// Binarizes the given path with BI's binarize.exe (Only available on Windows).
-pub fn binarize(input: &PathBuf) -> Result<Cursor<Box<[u8]>>, Error> {
-    if !cfg!(windows) {
-        return Err(error!("binarize.exe is only available on windows. Use rapify to binarize configs."));
+/// Returns the textures referenced by the material (`.rvmat`) at the given path.
+///
+/// Procedural textures (starting with `#`) are not included since they don't refer to files.
+pub fn material_dependencies(path: &PathBuf) -> Result<Vec<String>, Error> {
+    let mut file = File::open(path).prepend_error(format!("Failed to open {:?}:", path))?;
+    let config = Config::read(&mut file, Some(path.clone()), &Vec::new()).prepend_error("Failed to parse material:")?;
+
+    let mut textures: Vec<String> = Vec::new();
+    for texture in config.find_strings("texture") {
+        if texture.is_empty() || texture.starts_with('#') || textures.contains(&texture) { continue; }
+
+        textures.push(texture);
     }
 
-    let binarize_exe = find_binarize_exe().prepend_error("Failed to find BI's binarize.exe:")?;
-    if !binarize_exe.exists() {
-        return Err(error!("BI's binarize.exe found in registry, but doesn't exist."));
+    Ok(textures)
+}
+
+/// Returns the textures and materials referenced by the faces of the P3D at the given path.
+///
+/// Procedural textures (starting with `#`) are not included since they don't refer to files.
+pub fn p3d_dependencies(path: &PathBuf) -> Result<Vec<String>, Error> {
+    let mut file = File::open(path).prepend_error(format!("Failed to open {:?}:", path))?;
+    let p3d = P3D::read(&mut file).prepend_error("Failed to read P3D:")?;
+
+    let mut dependencies: Vec<String> = Vec::new();
+    for face in p3d.lods.iter().flat_map(|lod| lod.faces.iter()) {
+        for dependency in &[&face.texture, &face.material] {
+            if dependency.is_empty() || dependency.starts_with('#') || dependencies.contains(dependency) { continue; }
+
+            dependencies.push(dependency.to_string());
+        }
     }
 
-    let input_dir = PathBuf::from(input.parent().unwrap());
+    Ok(dependencies)
+}
+
+/// Copies the textures and materials referenced by the P3D at `input`, and the textures of those
+/// materials, into `target`, so binarize.exe can find them there.
+///
+/// The dependencies are searched in the include folders like absolute includes and keep their
+/// path relative to `target`. Dependencies that can't be found are reported as warnings and
+/// returned.
+pub fn extract_dependencies(input: &PathBuf, includefolders: &[PathBuf], target: &Path) -> Result<Vec<String>, Error> {
+    let mut dependencies = p3d_dependencies(input)?;
+    let mut unresolved: Vec<String> = Vec::new();
+
+    let mut i = 0;
+    while i < dependencies.len() {
+        let dependency = dependencies[i].clone();
+        i += 1;
+
+        let source = match find_absolute_path(&dependency, includefolders) {
+            Some(source) => source,
+            None => {
+                warning(format!("Dependency \"{}\" of {:?} not found.", dependency, input), Some("unresolved-dependency"), (None, None));
+                unresolved.push(dependency);
+                continue;
+            }
+        };
+
+        let destination = target.join(dependency.trim_start_matches('\\').replace("\\", pathsep()));
+        create_dir_all(destination.parent().unwrap()).prepend_error("Failed to create dependency folder:")?;
+        copy(&source, &destination).prepend_error(format!("Failed to copy dependency {:?}:", source))?;
+
+        if dependency.to_lowercase().ends_with(".rvmat") {
+            for texture in material_dependencies(&source)? {
+                if !dependencies.contains(&texture) {
+                    dependencies.push(texture);
+                }
+            }
+        }
+    }
+
+    Ok(unresolved)
+}
+
+/// Binarizes the given path with BI's binarize.exe (Only available on Windows, or through Wine with
+/// `BINARIZE_EXE` set).
+pub fn binarize(input: &PathBuf) -> Result<Cursor<Box<[u8]>>, Error> {
+    binarize_with_options(input, None, &[])
+}
+
+/// Same as `binarize`, with additional options.
+///
+/// `exe` is run through Wine instead of the one in `BINARIZE_EXE` on systems other than Windows,
+/// it is ignored on Windows. The dependencies of P3Ds are searched in `includefolders`, see
+/// `extract_dependencies`.
+pub fn binarize_with_options(input: &PathBuf, exe: Option<&Path>, includefolders: &[PathBuf]) -> Result<Cursor<Box<[u8]>>, Error> {
+    let runner = Runner::find(exe)?;
+
     let name = input.file_name().unwrap().to_str().unwrap().to_string();
     let tempdir = create_temp_directory(&name).prepend_error("Failed to create tempfolder:")?;
+    let mut texturedir: Option<PathBuf> = None;
+
+    let result = run_binarize(&runner, input, includefolders, &tempdir, &mut texturedir);
+
+    // the temp directories are removed even if binarizing failed
+    let cleanup = remove_dir_all(&tempdir).and_then(|_| texturedir.as_ref().map_or(Ok(()), remove_dir_all));
+    let buffer = result?;
+    cleanup.prepend_error("Failed to remove temp directory:")?;
+
+    Ok(Cursor::new(buffer.into_boxed_slice()))
+}
+
+// Runs binarize.exe with the output in `tempdir` and returns the result. For P3Ds, the
+// dependencies are extracted into a directory that is stored in `texturedir`.
+fn run_binarize(runner: &Runner, input: &PathBuf, includefolders: &[PathBuf], tempdir: &Path, texturedir: &mut Option<PathBuf>) -> Result<Vec<u8>, Error> {
+    let input_dir = PathBuf::from(input.parent().unwrap());
+    let name = input.file_name().unwrap().to_str().unwrap().to_string();
+
+    let mut args: Vec<String> = vec!["-norecurse".to_string(), "-always".to_string(), "-silent".to_string(), "-maxProcesses=0".to_string()];
+
+    if name.to_lowercase().ends_with(".p3d") {
+        let dir = create_temp_directory(&format!("{}_textures", name)).prepend_error("Failed to create tempfolder:")?;
+        *texturedir = Some(dir.clone());
+
+        if !includefolders.is_empty() {
+            extract_dependencies(input, includefolders, &dir).prepend_error("Failed to extract dependencies:")?;
+        }
+        args.push(format!("-textures={}", runner.path(&dir)?));
+    }
+
+    args.push(runner.path(&input_dir)?);
+    args.push(runner.path(tempdir)?);
+    args.push(name);
 
     let piped = var("BIOUTPUT").unwrap_or_else(|_| "0".to_string()) == "1";
 
-    let binarize_output = Command::new(binarize_exe)
-        .args(&["-norecurse", "-always", "-silent", "-maxProcesses=0", input_dir.to_str().unwrap(), tempdir.to_str().unwrap(), input.file_name().unwrap().to_str().unwrap()])
+    let binarize_output = runner.command()
+        .args(&args)
         .stdout(if piped { Stdio::inherit() } else { Stdio::null() })
         .stderr(if piped { Stdio::inherit() } else { Stdio::null() })
-        .output().unwrap();
+        .output().prepend_error("Failed to run binarize.exe:")?;
 
     if !binarize_output.status.success() {
         let msg = match binarize_output.status.code() {
@@ -81,23 +263,18 @@ pub fn binarize(input: &PathBuf) -> Result<Cursor<Box<[u8]>>, Error> {
     let result_path = tempdir.join(input.strip_prefix(&input_dir).unwrap());
     let mut buffer: Vec<u8> = Vec::new();
 
-    {
-        let mut file = File::open(result_path).prepend_error("Failed to open binarize.exe output:")?;
-        file.read_to_end(&mut buffer).prepend_error("Failed to read binarize.exe output:")?;
-    }
-
-    remove_dir_all(&tempdir).prepend_error("Failed to remove temp directory:")?;
+    let mut file = File::open(result_path).prepend_error("Failed to open binarize.exe output:")?;
+    file.read_to_end(&mut buffer).prepend_error("Failed to read binarize.exe output:")?;
 
-    Ok(Cursor::new(buffer.into_boxed_slice()))
+    Ok(buffer)
 }
 
-/// Binarizes the given path using BI's binarize.exe (on Windows) and writes it to the output.
-pub fn cmd_binarize(input: PathBuf, output: PathBuf) -> Result<(), Error> {
-    if !cfg!(windows) {
-        return Err(error!("binarize.exe is only available on windows. Use rapify to binarize configs."));
-    }
-
-    let cursor = binarize(&input)?;
+/// Binarizes the given path using BI's binarize.exe (on Windows, or through Wine with `exe` or
+/// `BINARIZE_EXE`) and writes it to the output.
+///
+/// The dependencies of P3Ds are searched in `includefolders`.
+pub fn cmd_binarize(input: PathBuf, output: PathBuf, exe: Option<PathBuf>, includefolders: &[PathBuf]) -> Result<(), Error> {
+    let cursor = binarize_with_options(&input, exe.as_deref(), includefolders)?;
     let mut file = File::create(output).prepend_error("Failed to open output:")?;
     file.write_all(cursor.get_ref()).prepend_error("Failed to write result to file:")?;
 
@@ -1,11 +1,16 @@
 //! Functions for rapifying and derapifying Arma configs
 
-use std::cmp::{min};
+use std::collections::{HashMap};
+use std::fs::{File};
 use std::io::{Read, Seek, Write, SeekFrom, Error, Cursor, BufReader, BufWriter};
 use std::iter::{Sum};
 use std::path::PathBuf;
+use std::str::{FromStr};
+use std::time::{Duration, Instant};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Serialize};
+use serde_json::{Map, Number, Value};
 
 use crate::*;
 use crate::io::*;
@@ -37,19 +42,74 @@ pub mod config_grammar {
 #[derive(Debug)]
 pub struct Config {
     root_body: ConfigClass,
+    enums: Vec<(String, i32)>,
 }
 
 /// Config class
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ConfigClass {
     parent: String,
     is_external: bool,
     is_deletion: bool,
     entries: Option<Vec<(String, ConfigEntry)>>,
+    comments: Vec<ConfigComment>,
+    /// Offset of the definition in the preprocessed input, for classes that were parsed
+    position: Option<usize>,
+}
+
+/// Comment in an unrapified config, written before the entry at `index` or after it on the
+/// same line if `inline` is set
+#[derive(Debug, Clone)]
+struct ConfigComment {
+    index: usize,
+    text: String,
+    inline: bool,
+}
+
+/// Source location of a class definition, see `Config::class_origins`
+#[derive(Debug, Serialize)]
+pub struct ClassOrigin {
+    /// Names of the class and its enclosing classes, starting at the root
+    pub path: Vec<String>,
+    /// File the class is defined in, `None` if it is the input without a known path
+    pub file: Option<PathBuf>,
+    /// Line of the class definition (starting at 1)
+    pub line: u32,
+}
+
+// Item in a class body while parsing, entries with their position and inline comment
+enum BodyItem {
+    Entry(usize, (String, ConfigEntry), Option<String>),
+    Comment(String),
+    Empty,
+}
+
+// Warning or error found in a value while parsing. Values are parsed inside rule alternatives the
+// grammar may backtrack from, so these are only reported once the entry containing them is used.
+enum ParseIssue {
+    Warning(usize, String, &'static str),
+    Error(usize, String),
+}
+
+impl ParseIssue {
+    // Splits a value that failed to parse into the default and the error.
+    fn from_result<T>(result: Result<T, (usize, String)>, default: T) -> (T, Vec<ParseIssue>) {
+        match result {
+            Ok(value) => (value, Vec::new()),
+            Err((pos, message)) => (default, vec![ParseIssue::Error(pos, message)])
+        }
+    }
+
+    fn report(self, warnings: &mut Vec<(usize, String, Option<&'static str>)>, errors: &mut Vec<(usize, String)>) {
+        match self {
+            ParseIssue::Warning(pos, message, name) => warnings.push((pos, message, Some(name))),
+            ParseIssue::Error(pos, message) => errors.push((pos, message))
+        }
+    }
 }
 
 /// Config entry
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ConfigEntry {
     /// String entry
     StringEntry(String),
@@ -63,15 +123,59 @@ pub enum ConfigEntry {
     ClassEntry(ConfigClass),
 }
 
+/// Kind of a config entry, as returned by `Config::entry_type`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigEntryKind {
+    /// Class with a body
+    Class,
+    /// String entry
+    String,
+    /// Int entry
+    Int,
+    /// Float entry
+    Float,
+    /// Array entry
+    Array,
+    /// Class deletion (`delete Foo;`)
+    Deletion,
+    /// External class reference (`class Foo;`)
+    External,
+}
+
+/// Engine version to write rapified configs for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum TargetVersion {
+    /// ArmA: Armed Assault, doesn't support array expansions (`foo[] += {...}`)
+    Arma1,
+    /// Arma 2 and Operation Arrowhead
+    Arma2,
+    /// Arma 3
+    #[default]
+    Arma3,
+}
+
+impl FromStr for TargetVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<TargetVersion, Error> {
+        match s.to_lowercase().as_str() {
+            "arma1" => Ok(TargetVersion::Arma1),
+            "arma2" => Ok(TargetVersion::Arma2),
+            "arma3" => Ok(TargetVersion::Arma3),
+            _ => Err(error!("Unknown target version \"{}\", expected arma1, arma2 or arma3.", s))
+        }
+    }
+}
+
 /// Config array
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ConfigArray {
     is_expansion: bool,
     elements: Vec<ConfigArrayElement>,
 }
 
 /// Config array element
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ConfigArrayElement {
     /// String element
     StringElement(String),
@@ -83,6 +187,31 @@ pub enum ConfigArrayElement {
     ArrayElement(ConfigArray),
 }
 
+// Unquoted values that look like macro names or invocations are most likely macros that weren't
+// defined, rather than intentionally unquoted strings.
+fn is_unexpanded_macro(value: &str) -> bool {
+    let name_end = value.find(|c: char| c.is_whitespace() || c == '(').unwrap_or(value.len());
+    let (name, arguments) = value.split_at(name_end);
+    let arguments = arguments.trim_start();
+
+    !name.starts_with(|c: char| c.is_ascii_digit()) &&
+        name.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_') &&
+        name.chars().any(|c| c.is_ascii_uppercase()) &&
+        (arguments.is_empty() || (arguments.starts_with('(') && arguments.ends_with(')')))
+}
+
+// via the shortest representation, so that 1.56 doesn't become 1.559999942779541
+fn json_float(f: f32) -> Value {
+    match format!("{:?}", f).parse().ok().and_then(Number::from_f64) {
+        Some(n) => Value::Number(n),
+        None => Value::Null
+    }
+}
+
+fn sqf_string(s: &str) -> String {
+    format!("\"{}\"", s.replace("\"", "\"\""))
+}
+
 impl ConfigArrayElement {
     fn rapified_length(&self) -> usize {
         match self {
@@ -96,6 +225,60 @@ impl ConfigArrayElement {
 }
 
 impl ConfigArray {
+    /// Creates a new array with the given elements.
+    ///
+    /// If `is_expansion` is set, the array is appended to an inherited array (`foo[] += {...}`)
+    /// instead of overwriting it (`foo[] = {...}`).
+    pub fn new(elements: Vec<ConfigArrayElement>, is_expansion: bool) -> ConfigArray {
+        ConfigArray {
+            is_expansion,
+            elements,
+        }
+    }
+
+    /// Returns the elements of the array.
+    pub fn elements(&self) -> &[ConfigArrayElement] {
+        &self.elements
+    }
+
+    /// Returns true if the array is appended to an inherited array (`+=`).
+    pub fn is_expansion(&self) -> bool {
+        self.is_expansion
+    }
+
+    /// Sets whether the array is appended to an inherited array (`+=`) or overwrites it (`=`).
+    pub fn set_expansion(&mut self, is_expansion: bool) {
+        self.is_expansion = is_expansion;
+    }
+
+    fn to_json(&self) -> Value {
+        let elements: Vec<Value> = self.elements.iter().map(|e| match e {
+            ConfigArrayElement::StringElement(s) => Value::String(s.clone()),
+            ConfigArrayElement::FloatElement(f) => json_float(*f),
+            ConfigArrayElement::IntElement(i) => Value::from(*i),
+            ConfigArrayElement::ArrayElement(a) => a.to_json(),
+        }).collect();
+
+        if self.is_expansion {
+            let mut object = Map::new();
+            object.insert("$append".to_string(), Value::Array(elements));
+            Value::Object(object)
+        } else {
+            Value::Array(elements)
+        }
+    }
+
+    fn to_sqf(&self) -> String {
+        let elements: Vec<String> = self.elements.iter().map(|e| match e {
+            ConfigArrayElement::StringElement(s) => sqf_string(s),
+            ConfigArrayElement::FloatElement(f) => format!("{:?}", f),
+            ConfigArrayElement::IntElement(i) => format!("{}", i),
+            ConfigArrayElement::ArrayElement(a) => a.to_sqf(),
+        }).collect();
+
+        format!("[{}]", elements.join(", "))
+    }
+
     fn write<O: Write>(&self, output: &mut O) -> Result<(), Error> {
         output.write_all(b"{")?;
         for (key, value) in self.elements.iter().enumerate() {
@@ -179,6 +362,66 @@ impl ConfigArray {
 }
 
 impl ConfigEntry {
+    /// Returns the value if this is an int entry.
+    pub fn as_int(&self) -> Option<i32> {
+        match self {
+            ConfigEntry::IntEntry(i) => Some(*i),
+            _ => None
+        }
+    }
+
+    /// Returns the value if this is a float entry.
+    pub fn as_float(&self) -> Option<f32> {
+        match self {
+            ConfigEntry::FloatEntry(f) => Some(*f),
+            _ => None
+        }
+    }
+
+    /// Returns the value if this is a string entry.
+    pub fn as_string(&self) -> Option<&str> {
+        match self {
+            ConfigEntry::StringEntry(s) => Some(s),
+            _ => None
+        }
+    }
+
+    /// Returns the array if this is an array entry.
+    pub fn as_array(&self) -> Option<&ConfigArray> {
+        match self {
+            ConfigEntry::ArrayEntry(a) => Some(a),
+            _ => None
+        }
+    }
+
+    /// Returns the class if this is a class entry, including external classes and deletions.
+    pub fn as_class(&self) -> Option<&ConfigClass> {
+        match self {
+            ConfigEntry::ClassEntry(c) => Some(c),
+            _ => None
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        match self {
+            ConfigEntry::StringEntry(s) => Value::String(s.clone()),
+            ConfigEntry::FloatEntry(f) => json_float(*f),
+            ConfigEntry::IntEntry(i) => Value::from(*i),
+            ConfigEntry::ArrayEntry(a) => a.to_json(),
+            ConfigEntry::ClassEntry(c) => c.to_json(),
+        }
+    }
+
+    fn to_sqf(&self) -> String {
+        match self {
+            ConfigEntry::StringEntry(s) => sqf_string(s),
+            ConfigEntry::FloatEntry(f) => format!("{:?}", f),
+            ConfigEntry::IntEntry(i) => format!("{}", i),
+            ConfigEntry::ArrayEntry(a) => a.to_sqf(),
+            ConfigEntry::ClassEntry(c) => c.to_sqf(),
+        }
+    }
+
     // without the name
     fn rapified_length(&self) -> usize {
         match self {
@@ -198,48 +441,310 @@ impl ConfigEntry {
 }
 
 impl ConfigClass {
-    fn write<O: Write>(&self, mut output: &mut O, level: i32) -> Result<(), Error> {
+    fn find(&self, path: &[&str]) -> Option<&ConfigEntry> {
+        let (name, rest) = path.split_first()?;
+        let (_, entry) = self.entries.as_ref()?.iter().rev().find(|(key, _)| key.eq_ignore_ascii_case(name))?;
+
+        if rest.is_empty() {
+            return Some(entry);
+        }
+
+        match entry {
+            ConfigEntry::ClassEntry(c) => c.find(rest),
+            _ => None
+        }
+    }
+
+    fn remove(&mut self, path: &[&str]) -> bool {
+        let (name, rest) = match path.split_first() {
+            Some(split) => split,
+            None => { return false; }
+        };
+        let entries = match &mut self.entries {
+            Some(entries) => entries,
+            None => { return false; }
+        };
+
+        if !rest.is_empty() {
+            return match entries.iter_mut().rev().find(|(key, _)| key.eq_ignore_ascii_case(name)) {
+                Some((_, ConfigEntry::ClassEntry(c))) => c.remove(rest),
+                _ => false
+            };
+        }
+
+        // remove all entries of that name, so an earlier duplicate doesn't take its place
+        let indices: Vec<usize> = entries.iter().enumerate().filter(|(_, (key, _))| key.eq_ignore_ascii_case(name)).map(|(i, _)| i).collect();
+        for index in indices.iter().rev() {
+            entries.remove(*index);
+
+            self.comments.retain(|c| c.index != *index);
+            for comment in self.comments.iter_mut().filter(|c| c.index > *index) {
+                comment.index -= 1;
+            }
+        }
+
+        !indices.is_empty()
+    }
+
+    fn to_json(&self) -> Value {
+        let mut object = Map::new();
+
+        if !self.parent.is_empty() {
+            object.insert("$parent".to_string(), Value::String(self.parent.clone()));
+        }
+        if self.is_external {
+            object.insert("$external".to_string(), Value::Bool(true));
+        }
+        if self.is_deletion {
+            object.insert("$deletion".to_string(), Value::Bool(true));
+        }
+
+        // names are case-insensitive, only the last of several entries with the same name is kept
+        let last: HashMap<String, usize> = self.entries().iter().enumerate()
+            .map(|(i, (key, _))| (key.to_lowercase(), i))
+            .collect();
+        for (i, (key, value)) in self.entries().iter().enumerate() {
+            if last[&key.to_lowercase()] == i {
+                object.insert(key.clone(), value.to_json());
+            }
+        }
+
+        Value::Object(object)
+    }
+
+    fn to_sqf(&self) -> String {
+        let pairs: Vec<String> = self.entries().iter()
+            .filter(|(_, value)| !matches!(value, ConfigEntry::ClassEntry(c) if c.is_deletion))
+            .map(|(key, value)| format!("[{}, {}]", sqf_string(key), value.to_sqf()))
+            .collect();
+
+        format!("[{}]", pairs.join(", "))
+    }
+
+    fn find_strings(&self, name: &str, result: &mut Vec<String>) {
+        if let Some(entries) = &self.entries {
+            for (key, value) in entries {
+                match value {
+                    ConfigEntry::StringEntry(s) if key.eq_ignore_ascii_case(name) => result.push(s.clone()),
+                    ConfigEntry::ClassEntry(c) => c.find_strings(name, result),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Finds the class that `self` inherits from. `scopes` are the enclosing classes, innermost
+    /// last, and the parent is searched in them from the inside out. Returns the index of the
+    /// scope the parent was found in along with its name and class.
+    fn resolve_parent<'a>(&self, scopes: &[&'a ConfigClass]) -> Option<(usize, &'a str, &'a ConfigClass)> {
+        for (index, scope) in scopes.iter().enumerate().rev() {
+            let found = scope.entries.as_ref()?.iter().rev().find_map(|(key, entry)| match entry {
+                ConfigEntry::ClassEntry(c) if !c.is_deletion && !std::ptr::eq(c, self) && key.eq_ignore_ascii_case(&self.parent) => Some((key.as_str(), c)),
+                _ => None
+            });
+
+            if let Some((name, class)) = found {
+                return Some((index, name, class));
+            }
+        }
+
+        None
+    }
+
+    /// Returns the inheritance chain of the class, starting with `name`, e.g. `C -> B -> A`.
+    fn inheritance_chain(&self, name: &str, scopes: &[&ConfigClass]) -> String {
+        let mut chain: Vec<String> = vec![name.to_string()];
+        let mut class = self;
+        let mut scopes = scopes;
+
+        while !class.parent.is_empty() {
+            if chain.len() > 64 {
+                chain.push("... (cyclic)".to_string());
+                break;
+            }
+
+            match class.resolve_parent(scopes) {
+                Some((index, name, parent)) => {
+                    if parent.is_external {
+                        chain.push(format!("{} (external)", name));
+                        break;
+                    }
+
+                    chain.push(name.to_string());
+                    class = parent;
+                    scopes = &scopes[..=index];
+                },
+                None => {
+                    chain.push(format!("{} (unresolved)", class.parent));
+                    break;
+                }
+            }
+        }
+
+        chain.join(" -> ")
+    }
+
+    fn write_tree<'a, O: Write>(&'a self, output: &mut O, scopes: &mut Vec<&'a ConfigClass>, parents: bool) -> Result<(), Error> {
+        let entries = match &self.entries {
+            Some(entries) => entries,
+            None => { return Ok(()); }
+        };
+
+        scopes.push(self);
+
+        for (name, entry) in entries {
+            let class = match entry {
+                ConfigEntry::ClassEntry(c) => c,
+                _ => continue
+            };
+
+            let indent = "    ".repeat(scopes.len() - 1);
+            if class.is_deletion {
+                writeln!(output, "{}delete {}", indent, name)?;
+            } else if class.is_external {
+                writeln!(output, "{}{} (external)", indent, name)?;
+            } else if parents {
+                writeln!(output, "{}{}", indent, class.inheritance_chain(name, scopes))?;
+            } else if class.parent.is_empty() {
+                writeln!(output, "{}{}", indent, name)?;
+            } else {
+                writeln!(output, "{}{} : {}", indent, name, class.parent)?;
+            }
+
+            class.write_tree(output, scopes, parents)?;
+        }
+
+        scopes.pop();
+
+        Ok(())
+    }
+
+    fn class_origins(&self, preprocessed: &str, info: &PreprocessInfo, path: &mut Vec<String>, result: &mut Vec<ClassOrigin>) {
+        let entries = match &self.entries {
+            Some(entries) => entries,
+            None => { return; }
+        };
+
+        for (name, entry) in entries {
+            if let ConfigEntry::ClassEntry(c) = entry {
+                path.push(name.clone());
+
+                if let Some((_, Some(origin))) = c.position.map(|p| origin_at(preprocessed, info, p)) {
+                    result.push(ClassOrigin { path: path.clone(), file: origin.1.clone(), line: origin.0 });
+                }
+                c.class_origins(preprocessed, info, path, result);
+
+                path.pop();
+            }
+        }
+    }
+
+    fn check_target(&self, target: TargetVersion, path: &mut Vec<String>) -> Result<(), Error> {
+        let entries = match &self.entries {
+            Some(entries) => entries,
+            None => { return Ok(()); }
+        };
+
+        for (name, entry) in entries {
+            path.push(name.clone());
+
+            match entry {
+                ConfigEntry::ArrayEntry(a) if a.is_expansion && target < TargetVersion::Arma2 => {
+                    return Err(error!("Array expansion \"{}[] += ...\" is not supported by {:?}.", path.join(" >> "), target));
+                },
+                ConfigEntry::ClassEntry(c) => c.check_target(target, path)?,
+                _ => {}
+            }
+
+            path.pop();
+        }
+
+        Ok(())
+    }
+
+    /// Creates a new class without entries, inheriting from `parent` if it is not empty.
+    pub fn new(parent: &str) -> ConfigClass {
+        ConfigClass {
+            parent: parent.to_string(),
+            is_external: false,
+            is_deletion: false,
+            entries: Some(Vec::new()),
+            comments: Vec::new(),
+            position: None,
+        }
+    }
+
+    /// Returns the name of the parent class, or an empty string if the class doesn't inherit.
+    pub fn parent(&self) -> &str {
+        &self.parent
+    }
+
+    /// Returns the entries of the class in order. External classes and deletions have none.
+    pub fn entries(&self) -> &[(String, ConfigEntry)] {
+        match &self.entries {
+            Some(entries) => entries,
+            None => &[]
+        }
+    }
+
+    /// Appends an entry to the class.
+    pub fn add_entry(&mut self, name: &str, entry: ConfigEntry) {
+        self.entries.get_or_insert_with(Vec::new).push((name.to_string(), entry));
+    }
+
+    fn write_comments<O: Write>(&self, output: &mut O, index: usize, level: i32, indent: &str) -> Result<(), Error> {
+        for comment in self.comments.iter().filter(|c| c.index == index && !c.inline) {
+            output.write_all(indent.repeat(level as usize).as_bytes())?;
+            output.write_all(comment.text.as_bytes())?;
+            output.write_all(b"\n")?;
+        }
+
+        Ok(())
+    }
+
+    fn write<O: Write>(&self, mut output: &mut O, level: i32, indent: &str) -> Result<(), Error> {
         match &self.entries {
             Some(entries) => {
-                if level > 0 && !entries.is_empty() {
+                if level > 0 && (!entries.is_empty() || !self.comments.is_empty()) {
                     output.write_all(b"\n")?;
                 }
-                for (key, value) in entries {
-                    output.write_all(String::from("    ").repeat(level as usize).as_bytes())?;
+                for (index, (key, value)) in entries.iter().enumerate() {
+                    self.write_comments(output, index, level, indent)?;
+                    output.write_all(indent.repeat(level as usize).as_bytes())?;
 
                     match value {
                         ConfigEntry::ClassEntry(ref c) => {
                             if c.is_deletion {
-                                output.write_all(format!("delete {};\n", key).as_bytes())?;
+                                output.write_all(format!("delete {};", key).as_bytes())?;
                             } else if c.is_external {
-                                output.write_all(format!("class {};\n", key).as_bytes())?;
+                                output.write_all(format!("class {};", key).as_bytes())?;
                             } else {
                                 let parent = if c.parent == "" { String::from("") } else { format!(": {}", c.parent) };
                                 match &c.entries {
                                     Some(entries) => {
-                                        if !entries.is_empty() {
+                                        if !entries.is_empty() || !c.comments.is_empty() {
                                             output.write_all(format!("class {}{} {{", key, parent).as_bytes())?;
-                                            c.write(output, level + 1)?;
-                                            output.write_all(String::from("    ").repeat(level as usize).as_bytes())?;
-                                            output.write_all(b"};\n")?;
+                                            c.write(output, level + 1, indent)?;
+                                            output.write_all(indent.repeat(level as usize).as_bytes())?;
+                                            output.write_all(b"};")?;
                                         } else {
-                                            output.write_all(format!("class {}{} {{}};\n", key, parent).as_bytes())?;
+                                            output.write_all(format!("class {}{} {{}};", key, parent).as_bytes())?;
                                         }
                                     },
                                     None => {
-                                        output.write_all(format!("class {}{} {{}};\n", key, parent).as_bytes())?;
+                                        output.write_all(format!("class {}{} {{}};", key, parent).as_bytes())?;
                                     },
                                 }
                             }
                         },
                         ConfigEntry::StringEntry(s) => {
-                            output.write_all(format!("{} = \"{}\";\n", key, s.replace("\r", "\\r").replace("\n", "\\n").replace("\"", "\"\"")).as_bytes())?;
+                            output.write_all(format!("{} = \"{}\";", key, s.replace("\r", "\\r").replace("\n", "\\n").replace("\"", "\"\"")).as_bytes())?;
                         },
                         ConfigEntry::FloatEntry(f) => {
-                            output.write_all(format!("{} = {:?};\n", key, f).as_bytes())?;
+                            output.write_all(format!("{} = {:?};", key, f).as_bytes())?;
                         },
                         ConfigEntry::IntEntry(i) => {
-                            output.write_all(format!("{} = {};\n", key, i).as_bytes())?;
+                            output.write_all(format!("{} = {};", key, i).as_bytes())?;
                         },
                         ConfigEntry::ArrayEntry(ref a) => {
                             if a.is_expansion {
@@ -248,10 +753,17 @@ impl ConfigClass {
                                 output.write_all(format!("{}[] = ", key).as_bytes())?;
                             }
                             a.write(&mut output)?;
-                            output.write_all(b";\n")?;
+                            output.write_all(b";")?;
                         },
                     }
+
+                    for comment in self.comments.iter().filter(|c| c.index == index && c.inline) {
+                        output.write_all(b" ")?;
+                        output.write_all(comment.text.as_bytes())?;
+                    }
+                    output.write_all(b"\n")?;
                 }
+                self.write_comments(output, entries.len(), level, indent)?;
             },
             None => {}
         }
@@ -353,15 +865,17 @@ impl ConfigClass {
         Ok(written)
     }
 
-    fn read_rapified<I: Read + Seek>(input: &mut I, level: u32) -> Result<ConfigClass, Error> {
+    // `start` is the stream position of the rapified config's magic, all offsets in the file are
+    // relative to it
+    fn read_rapified<I: Read + Seek>(input: &mut I, level: u32, start: u64) -> Result<ConfigClass, Error> {
         let mut fp = 0;
         if level == 0 {
-            input.seek(SeekFrom::Start(16))?;
+            input.seek(SeekFrom::Start(start + 16))?;
         } else {
             let classbody_fp: u32 = input.read_u32::<LittleEndian>()?;
 
             fp = input.seek(SeekFrom::Current(0))?;
-            input.seek(SeekFrom::Start(classbody_fp.into()))?;
+            input.seek(SeekFrom::Start(start + u64::from(classbody_fp)))?;
         }
 
         let parent = input.read_cstring()?;
@@ -374,7 +888,7 @@ impl ConfigClass {
             if entry_type == 0 {
                 let name = input.read_cstring()?;
 
-                let class_entry = ConfigClass::read_rapified(input, level + 1)
+                let class_entry = ConfigClass::read_rapified(input, level + 1, start)
                     .prepend_error(format!("Failed to read rapified class \"{}\":", name))?;
                 entries.push((name, ConfigEntry::ClassEntry(class_entry)));
             } else if entry_type == 1 {
@@ -405,8 +919,10 @@ impl ConfigClass {
                 let class_entry = ConfigClass {
                     parent: String::from(""),
                     is_external: entry_type == 3,
-                    is_deletion: entry_type == 5,
-                    entries: None
+                    is_deletion: entry_type == 4,
+                    entries: None,
+                    comments: Vec::new(),
+                    position: None
                 };
 
                 entries.push((name.clone(), ConfigEntry::ClassEntry(class_entry)));
@@ -424,14 +940,126 @@ impl ConfigClass {
             is_external: false,
             is_deletion: false,
             entries: Some(entries),
+            comments: Vec::new(),
+            position: None,
         })
     }
 }
 
 impl Config {
+    /// Creates a config with the given class as its root.
+    pub fn new(root_body: ConfigClass) -> Config {
+        Config { root_body, enums: Vec::new() }
+    }
+
+    /// Returns the enum constants defined by the config.
+    ///
+    /// Only rapified configs can contain enums, they are not written when rapifying.
+    pub fn enums(&self) -> &[(String, i32)] {
+        &self.enums
+    }
+
+    /// Returns the kind of the entry at the given path of class names, ending with the name of the
+    /// entry itself, or `None` if there is no such entry.
+    pub fn entry_type(&self, path: &[&str]) -> Option<ConfigEntryKind> {
+        Some(match self.root_body.find(path)? {
+            ConfigEntry::ClassEntry(c) if c.is_deletion => ConfigEntryKind::Deletion,
+            ConfigEntry::ClassEntry(c) if c.is_external => ConfigEntryKind::External,
+            ConfigEntry::ClassEntry(_) => ConfigEntryKind::Class,
+            ConfigEntry::StringEntry(_) => ConfigEntryKind::String,
+            ConfigEntry::IntEntry(_) => ConfigEntryKind::Int,
+            ConfigEntry::FloatEntry(_) => ConfigEntryKind::Float,
+            ConfigEntry::ArrayEntry(_) => ConfigEntryKind::Array,
+        })
+    }
+
+    /// Returns the entry at the given path of class names, ending with the name of the entry
+    /// itself, or `None` if there is no such entry. Names are matched case-insensitively.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use armake2::config::Config;
+    /// let config = Config::from_string(String::from("class CfgPatches { class foo { requiredVersion = 1.56; }; };"), None, &Vec::new()).unwrap();
+    ///
+    /// let version = config.get(&["CfgPatches", "foo", "requiredVersion"]).and_then(|e| e.as_float());
+    /// assert_eq!(Some(1.56), version);
+    /// ```
+    pub fn get(&self, path: &[&str]) -> Option<&ConfigEntry> {
+        self.root_body.find(path)
+    }
+
+    /// Removes the entry or class at the given path, looked up like in `get`, and returns whether
+    /// there was one. If the class contains several entries of that name, all of them are removed.
+    pub fn remove(&mut self, path: &[&str]) -> bool {
+        self.root_body.remove(path)
+    }
+
+    /// Returns the entry at the given path as an SQF value, or `None` if there is no such entry.
+    ///
+    /// Classes become arrays of `["name", value]` pairs (`[["foo", 1], ["bar", [1, 2]]]`), which
+    /// can be passed to `createHashMapFromArray`. Nested classes are nested the same way and
+    /// deletions are left out. An empty path exports the whole config.
+    pub fn to_sqf(&self, path: &[&str]) -> Option<String> {
+        if path.is_empty() {
+            return Some(self.root_body.to_sqf());
+        }
+
+        self.get(path).map(|e| e.to_sqf())
+    }
+
+    /// Returns the config as a JSON value.
+    ///
+    /// Classes become objects and arrays become JSON arrays. Since entry names can't contain `$`,
+    /// keys starting with it are used for the parent class (`$parent`), external classes
+    /// (`$external`) and deletions (`$deletion`). Array expansions (`foo[] += {...}`) become
+    /// objects with the elements under `$append`.
+    ///
+    /// If a class contains several entries with the same name (ignoring case), like a class that
+    /// is defined twice, only the last one is included, since that is the one the game uses.
+    pub fn to_json(&self) -> Value {
+        self.root_body.to_json()
+    }
+
+    /// Returns the values of all string entries with the given name (case-insensitive) in any
+    /// class of the config.
+    pub fn find_strings(&self, name: &str) -> Vec<String> {
+        let mut result = Vec::new();
+        self.root_body.find_strings(name, &mut result);
+        result
+    }
+
+    /// Returns the file and line every class of the config is defined in, if the config was
+    /// parsed from `preprocessed` with `info` by `from_preprocessed`. External classes, deletions
+    /// and classes of rapified configs have no location and are left out.
+    pub fn class_origins(&self, preprocessed: &str, info: &PreprocessInfo) -> Vec<ClassOrigin> {
+        let mut result = Vec::new();
+        self.root_body.class_origins(preprocessed, info, &mut Vec::new(), &mut result);
+        result
+    }
+
+    /// Checks that the config only uses features supported by the given engine version when
+    /// rapified.
+    pub fn check_target(&self, target: TargetVersion) -> Result<(), Error> {
+        self.root_body.check_target(target, &mut Vec::new())
+    }
+
+    /// Writes the class hierarchy of the config to the output, one class per line.
+    ///
+    /// If `parents` is set, each class is followed by its full inheritance chain, resolving
+    /// parents in the enclosing classes and marking external or unresolved ones.
+    pub fn write_tree<O: Write>(&self, output: &mut O, parents: bool) -> Result<(), Error> {
+        self.root_body.write_tree(output, &mut Vec::new(), parents)
+    }
+
     /// Writes the config (unrapified) to the output.
     pub fn write<O: Write>(&self, output: &mut O) -> Result<(), Error> {
-        self.root_body.write(output, 0)
+        self.write_indented(output, "    ")
+    }
+
+    /// Writes the config (unrapified) to the output, using the given string for indentation.
+    pub fn write_indented<O: Write>(&self, output: &mut O, indent: &str) -> Result<(), Error> {
+        self.root_body.write(output, 0, indent)
     }
 
     /// Returns the unrapified config as a string.
@@ -481,23 +1109,64 @@ impl Config {
     /// messages. `includefolders` are the folders searched for absolute includes and should usually at
     /// least include the current working directory.
     pub fn read<I: Read>(input: &mut I, path: Option<PathBuf>, includefolders: &[PathBuf]) -> Result<Config, Error> {
+        Self::read_with_options(input, path, includefolders, &PreprocessOptions::default())
+    }
+
+    /// Same as `read`, but with additional options for the preprocessor.
+    ///
+    /// If `options.keep_comments` is set, comments are attached to the surrounding entries and
+    /// written again by `write`. They are lost when rapifying.
+    pub fn read_with_options<I: Read>(input: &mut I, path: Option<PathBuf>, includefolders: &[PathBuf], options: &PreprocessOptions) -> Result<Config, Error> {
+        Self::read_timed(input, path, includefolders, options).map(|(config, _)| config)
+    }
+
+    // Same as `read_with_options`, also returning the time spent reading and preprocessing the
+    // input and the time spent parsing it, for build profiles.
+    pub(crate) fn read_timed<I: Read>(input: &mut I, path: Option<PathBuf>, includefolders: &[PathBuf], options: &PreprocessOptions) -> Result<(Config, (Duration, Duration)), Error> {
+        let start = Instant::now();
         let mut buffer = String::new();
         input.read_to_string(&mut buffer).prepend_error("Failed to read input file:")?;
 
-        let (preprocessed, info) = preprocess(buffer, path, includefolders).prepend_error("Failed to preprocess config:")?;
+        let (preprocessed, info) = preprocess_with_options(buffer, path, includefolders, options).prepend_error("Failed to preprocess config:")?;
+        let preprocessing = start.elapsed();
 
+        let start = Instant::now();
+        let config = Self::from_preprocessed(&preprocessed, &info)?;
+
+        Ok((config, (preprocessing, start.elapsed())))
+    }
+
+    /// Parses an already preprocessed config.
+    ///
+    /// `info` is the info struct returned by `preprocess` for this input and is used to map
+    /// warnings and errors to their original locations.
+    pub fn from_preprocessed(preprocessed: &str, info: &PreprocessInfo) -> Result<Config, Error> {
         let mut warnings: Vec<(usize, String, Option<&'static str>)> = Vec::new();
+        let mut errors: Vec<(usize, String)> = Vec::new();
 
-        let result = config_grammar::config(&preprocessed, &mut warnings).format_error(&info, &preprocessed);
+        let result = config_grammar::config(preprocessed, &mut warnings, &mut errors).format_error(info, preprocessed);
+
+        if result.is_ok() {
+            if let Some((pos, msg)) = errors.first() {
+                let (line, origin) = origin_at(preprocessed, info, *pos);
+                let file = match origin.and_then(|o| o.1.as_ref()) {
+                    Some(path) => format!("{}:", path.to_str().unwrap()),
+                    None => "".to_string()
+                };
+                // the line in the preprocessed input if its origin is unknown
+                let lineno = origin.map_or(line as u32 + 1, |o| o.0);
+
+                return Err(error!("In line {}{}:\n\n  {}\n\n{}", file, lineno, preprocessed.lines().nth(line).unwrap_or("").trim(), msg));
+            }
+        }
 
         for w in warnings {
 
             let location = if !warning_suppressed(w.2) {
-                let mut line = preprocessed[..w.0].chars().filter(|c| c == &'\n').count();
-                let file = info.line_origins[min(line, info.line_origins.len()) - 1].1.as_ref().map(|p| p.to_str().unwrap().to_string());
-                line = info.line_origins[min(line, info.line_origins.len()) - 1].0 as usize + 1;
-
-                (file, Some(line as u32))
+                match origin_at(preprocessed, info, w.0) {
+                    (_, Some(origin)) => (origin.1.as_ref().map(|p| p.to_str().unwrap().to_string()), Some(origin.0)),
+                    (_, None) => (None, None)
+                }
             } else {
                 (None, None)
             };
@@ -519,8 +1188,12 @@ impl Config {
     }
 
     /// Reads the rapified config from input.
+    ///
+    /// The config is read starting at the current position of the input, which doesn't have to be
+    /// the start of the stream.
     pub fn read_rapified<I: Read + Seek>(input: &mut I) -> Result<Config, Error> {
         let mut reader = BufReader::new(input);
+        let start = reader.stream_position()?;
 
         let mut buffer = [0; 4];
         reader.read_exact(&mut buffer)?;
@@ -529,30 +1202,125 @@ impl Config {
             return Err(error!("File doesn't seem to be a rapified config."));
         }
 
+        reader.seek(SeekFrom::Start(start + 12))?;
+        let enum_offset = reader.read_u32::<LittleEndian>()?;
+
+        let root_body = ConfigClass::read_rapified(&mut reader, 0, start)?;
+
+        // the classes don't depend on the enums, so a broken enum table is skipped
+        reader.seek(SeekFrom::Start(start + u64::from(enum_offset)))?;
+        let enums = read_enums(&mut reader).unwrap_or_else(|e| {
+            warning(format!("Failed to read enums, ignoring them: {}", e), Some("invalid-enums"), (None, None));
+            Vec::new()
+        });
+
         Ok(Config {
-            root_body: ConfigClass::read_rapified(&mut reader, 0)?
+            root_body,
+            enums,
         })
     }
 }
 
+fn read_enums<I: Read>(input: &mut I) -> Result<Vec<(String, i32)>, Error> {
+    let num_enums = input.read_u32::<LittleEndian>()?;
+    let mut enums: Vec<(String, i32)> = Vec::new();
+
+    for _i in 0..num_enums {
+        let name = input.read_cstring()?;
+        let value = input.read_i32::<LittleEndian>()?;
+        enums.push((name, value));
+    }
+
+    Ok(enums)
+}
+
+// Line in the preprocessed input and its origin for an offset in it, if the info has any
+fn origin_at<'a>(preprocessed: &str, info: &'a PreprocessInfo, position: usize) -> (usize, Option<&'a (u32, Option<PathBuf>)>) {
+    let line = preprocessed[..position].chars().filter(|c| c == &'\n').count();
+    (line, info.line_origins.get(line).or_else(|| info.line_origins.last()))
+}
+
 /// Reads input, preprocesses and rapifies it and writes to output.
 ///
 /// `path` is the path to the input if it is known and is used for relative includes and error
 /// messages. `includefolders` are the folders searched for absolute includes and should usually at
-/// least include the current working directory.
-pub fn cmd_rapify<I: Read, O: Write>(input: &mut I, output: &mut O, path: Option<PathBuf>, includefolders: &[PathBuf]) -> Result<(), Error> {
-    let config = Config::read(input, path, includefolders)?;
+/// least include the current working directory. If `dump_path` is given, the preprocessed config
+/// is also written to that file before parsing. `options` are passed on to the preprocessor.
+/// Configs using features that `target` doesn't support are rejected.
+pub fn cmd_rapify<I: Read, O: Write>(input: &mut I, output: &mut O, path: Option<PathBuf>, includefolders: &[PathBuf], dump_path: Option<PathBuf>, options: &PreprocessOptions, target: TargetVersion) -> Result<(), Error> {
+    let buffer = read_text(input, options.encoding).prepend_error("Failed to read input file:")?;
+
+    let (preprocessed, info) = preprocess_with_options(buffer, path, includefolders, options).prepend_error("Failed to preprocess config:")?;
+
+    if let Some(dump_path) = dump_path {
+        let mut file = File::create(dump_path).prepend_error("Failed to create preprocessed output file:")?;
+        file.write_all(preprocessed.as_bytes()).prepend_error("Failed to write preprocessed output file:")?;
+    }
+
+    if let Some(ref line_map) = options.line_map {
+        info.write_line_map_file(line_map)?;
+    }
+
+    let config = Config::from_preprocessed(&preprocessed, &info)?;
+    config.check_target(target)?;
+
+    if let Some(ref class_map) = options.class_map {
+        let file = File::create(class_map).prepend_error("Failed to create class map file:")?;
+        serde_json::to_writer_pretty(file, &config.class_origins(&preprocessed, &info)).map_err(|e| error!("{}", e)).prepend_error("Failed to write class map file:")?;
+    }
 
     config.write_rapified(output).prepend_error("Failed to write rapified config:")?;
 
     Ok(())
 }
 
+/// Reads a config and writes its class hierarchy to output.
+///
+/// The input can be rapified or not, unrapified configs are preprocessed first. `path` and
+/// `includefolders` are used for includes like in `cmd_rapify`. If `parents` is set, the full
+/// inheritance chain of each class is shown.
+pub fn cmd_tree<I: Read, O: Write>(input: &mut I, output: &mut O, path: Option<PathBuf>, includefolders: &[PathBuf], parents: bool) -> Result<(), Error> {
+    let config = read_any(input, path, includefolders)?;
+
+    config.write_tree(output, parents).prepend_error("Failed to write output:")
+}
+
+/// Reads a config and writes it to output as JSON, see `Config::to_json`.
+///
+/// Like with `cmd_tree`, the input can be rapified or not.
+pub fn cmd_config2json<I: Read, O: Write>(input: &mut I, output: &mut O, path: Option<PathBuf>, includefolders: &[PathBuf]) -> Result<(), Error> {
+    let config = read_any(input, path, includefolders)?;
+
+    serde_json::to_writer_pretty(&mut *output, &config.to_json()).map_err(|e| error!("{}", e)).prepend_error("Failed to write output:")?;
+    output.write_all(b"\n")?;
+
+    Ok(())
+}
+
+fn read_any<I: Read>(input: &mut I, path: Option<PathBuf>, includefolders: &[PathBuf]) -> Result<Config, Error> {
+    let mut buffer: Vec<u8> = Vec::new();
+    input.read_to_end(&mut buffer).prepend_error("Failed to read input file:")?;
+
+    if buffer.starts_with(b"\0raP") {
+        Config::read_rapified(&mut Cursor::new(buffer)).prepend_error("Failed to read rapified config:")
+    } else {
+        let text = decode_text(&buffer, TextEncoding::Auto)?;
+        Config::from_string(text, path, includefolders)
+    }
+}
+
 /// Reads input, derapifies it and writes to output.
-pub fn cmd_derapify<I: Read + Seek, O: Write>(input: &mut I, output: &mut O) -> Result<(), Error> {
+///
+/// `indent` is the string used for each level of indentation, `encoding` the encoding of the
+/// output.
+pub fn cmd_derapify<I: Read + Seek, O: Write>(input: &mut I, output: &mut O, indent: &str, encoding: TextEncoding) -> Result<(), Error> {
     let config = Config::read_rapified(input).prepend_error("Failed to read rapified config:")?;
 
-    config.write(output).prepend_error("Failed to derapify config:")?;
+    let mut buffer: Vec<u8> = Vec::new();
+    config.write_indented(&mut buffer, indent).prepend_error("Failed to derapify config:")?;
+
+    let text = String::from_utf8(buffer).map_err(|e| error!("Config contains invalid UTF-8: {}", e))?;
+    output.write_all(&encode_text(&text, encoding)).prepend_error("Failed to write output:")?;
 
     Ok(())
 }
@@ -5,15 +5,40 @@ use std::collections::{HashMap, HashSet};
 use std::fmt::{Display};
 use std::io::{Error};
 use std::path::{PathBuf};
+use std::sync::{Mutex, MutexGuard};
 
 use colored::*;
 
 use crate::config::*;
 use crate::preprocess::*;
 
-pub static mut WARNINGS_MAXIMUM: u32 = 10;
-static mut WARNINGS_RAISED: Option<HashMap<String, u32>> = None;
-pub static mut WARNINGS_MUTED: Option<HashSet<String>> = None;
+struct WarningState {
+    maximum: u32,
+    raised: Option<HashMap<String, u32>>,
+    muted: Option<HashSet<String>>,
+}
+
+static WARNINGS: Mutex<WarningState> = Mutex::new(WarningState {
+    maximum: 10,
+    raised: None,
+    muted: None,
+});
+
+fn warning_state() -> MutexGuard<'static, WarningState> {
+    // a panic while printing a warning doesn't leave the counts inconsistent
+    WARNINGS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Enables warnings, except for the named ones in `muted`. If `muted` is `None`, all warnings
+/// are disabled and not counted, which is the default.
+pub fn set_muted_warnings(muted: Option<HashSet<String>>) {
+    warning_state().muted = muted;
+}
+
+/// Sets how often each named warning is printed before further ones are suppressed.
+pub fn set_warnings_maximum(maximum: u32) {
+    warning_state().maximum = maximum;
+}
 
 #[macro_export]
 macro_rules! error {
@@ -105,24 +130,24 @@ fn format_parse_error(line: &str, file: String, line_number: usize, column_numbe
 }
 
 pub fn warning<M: AsRef<[u8]> + Display>(msg: M, name: Option<&'static str>, location: (Option<M>,Option<u32>)) {
-    unsafe {
-        if WARNINGS_MUTED.is_none() {
-            return;
-        }
+    {
+        let mut state = warning_state();
+        let state = &mut *state;
 
-        if WARNINGS_RAISED.is_none() {
-            WARNINGS_RAISED = Some(HashMap::new());
-        }
+        let muted = match state.muted {
+            Some(ref muted) => muted,
+            None => { return; }
+        };
 
         if let Some(name) = name {
-            let raised = WARNINGS_RAISED.as_ref().unwrap().get(name).unwrap_or(&0);
-            WARNINGS_RAISED.as_mut().unwrap().insert(name.to_string(), raised + 1);
+            let raised = state.raised.get_or_insert_with(HashMap::new).entry(name.to_string()).or_insert(0);
+            *raised += 1;
 
-            if raised >= &WARNINGS_MAXIMUM {
+            if *raised > state.maximum {
                 return;
             }
 
-            if WARNINGS_MUTED.as_ref().unwrap().contains(name) {
+            if muted.contains(name) {
                 return;
             }
         }
@@ -151,43 +176,46 @@ pub fn warning_suppressed(name: Option<&'static str>) -> bool {
         return false;
     }
 
-    unsafe {
-        if WARNINGS_MUTED.is_none() {
-            return true;
-        }
+    let state = warning_state();
 
-        if WARNINGS_MUTED.as_ref().unwrap().contains(name.unwrap()) {
-            return true;
-        }
+    match state.muted {
+        Some(ref muted) if muted.contains(name.unwrap()) => { return true; },
+        Some(_) => {},
+        None => { return true; }
+    }
 
-        if WARNINGS_RAISED.is_some() {
-            let raised = WARNINGS_RAISED.as_ref().unwrap().get(name.unwrap()).unwrap_or(&0);
-            raised >= &WARNINGS_MAXIMUM
-        } else {
-            false
-        }
+    match state.raised {
+        Some(ref raised) => raised.get(name.unwrap()).unwrap_or(&0) >= &state.maximum,
+        None => false
     }
 }
 
+/// Returns how often each named warning was raised so far, including suppressed ones.
+pub fn warnings_raised() -> HashMap<String, u32> {
+    warning_state().raised.clone().unwrap_or_default()
+}
+
 pub fn print_warning_summary() {
-    unsafe {
-        if WARNINGS_RAISED.is_none() || WARNINGS_MUTED.is_none() {
-            return;
+    // collected first, warning() needs the lock as well
+    let excesses: Vec<(String, u32)> = {
+        let state = warning_state();
+
+        match (&state.raised, &state.muted) {
+            (Some(raised), Some(muted)) => raised.iter()
+                .filter(|(name, raised)| !muted.contains(*name) && **raised > state.maximum)
+                .map(|(name, raised)| (name.clone(), *raised - state.maximum))
+                .collect(),
+            _ => { return; }
         }
+    };
 
-        for (name, raised) in WARNINGS_RAISED.as_ref().unwrap().iter() {
-            if WARNINGS_MUTED.as_ref().unwrap().contains(name) { continue; }
-
-            if *raised <= WARNINGS_MAXIMUM { continue; }
-            let excess = *raised - WARNINGS_MAXIMUM;
-
-            if excess > 1 {
-                warning(format!("{} warnings of type \"{}\" were suppressed to prevent spam. Use \"-w {}\" to disable these warnings entirely.",
-                    excess, name, name), None, (None, None));
-            } else {
-                warning(format!("{} warning of type \"{}\" was suppressed to prevent spam. Use \"-w {}\" to disable these warnings entirely.",
-                    excess, name, name), None, (None, None));
-            }
+    for (name, excess) in excesses {
+        if excess > 1 {
+            warning(format!("{} warnings of type \"{}\" were suppressed to prevent spam. Use \"-w {}\" to disable these warnings entirely.",
+                excess, name, name), None, (None, None));
+        } else {
+            warning(format!("{} warning of type \"{}\" was suppressed to prevent spam. Use \"-w {}\" to disable these warnings entirely.",
+                excess, name, name), None, (None, None));
         }
     }
 }
@@ -1,6 +1,11 @@
-use std::fs::{File};
+use std::fs::{File, remove_file, rename};
 use std::io;
 use std::io::{Read, Seek, Write, Stdout, Cursor};
+use std::ffi::{OsString};
+use std::path::{Path, PathBuf};
+use std::str::{FromStr};
+
+use encoding_rs::{WINDOWS_1252, UTF_16BE, UTF_16LE};
 
 pub enum Input {
     File(File),
@@ -46,6 +51,159 @@ impl Write for Output {
     }
 }
 
+/// Opens the file at `path` for reading, with the path in the error if that fails.
+pub fn open_file(path: &Path) -> io::Result<File> {
+    File::open(path).map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", path, e)))
+}
+
+/// Creates or truncates the file at `path` for writing, with the path in the error if that fails.
+pub fn create_file(path: &Path) -> io::Result<File> {
+    File::create(path).map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", path, e)))
+}
+
+// Temporary output file that is removed when dropped unless it was kept, so it is also cleaned
+// up when writing panics
+struct TempFile {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.keep {
+            // the original error is more useful than one from cleaning up
+            let _ = remove_file(&self.path);
+        }
+    }
+}
+
+/// Calls `f` with a temporary file next to `path`, which is renamed to `path` if `f` succeeds and
+/// removed otherwise, so a failed command never leaves a truncated output file behind.
+///
+/// If `keep_on_error` is set, the partial output is moved to `path` even if `f` fails.
+pub fn write_atomically<F: FnOnce(&mut Output) -> io::Result<()>>(path: &Path, keep_on_error: bool, f: F) -> io::Result<()> {
+    let file_name = path.file_name().ok_or_else(|| error!("Invalid output path: {:?}", path))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(".tmp");
+    let mut temp = TempFile { path: path.with_file_name(temp_name), keep: false };
+
+    let mut output = Output::File(File::create(&temp.path)?);
+    let result = f(&mut output).and_then(|_| output.flush());
+    drop(output);
+
+    if result.is_ok() || keep_on_error {
+        rename(&temp.path, path)?;
+        temp.keep = true;
+    }
+
+    result
+}
+
+/// Text encoding used for reading and writing configs and scripts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TextEncoding {
+    /// Detect from BOM, fall back to UTF-8 if valid and Windows-1252 otherwise
+    #[default]
+    Auto,
+    /// UTF-8
+    Utf8,
+    /// UTF-16 (little endian unless a big endian BOM is present)
+    Utf16,
+    /// Windows-1252
+    Cp1252,
+}
+
+impl FromStr for TextEncoding {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<TextEncoding> {
+        match s.to_lowercase().replace("-", "").as_str() {
+            "auto" => Ok(TextEncoding::Auto),
+            "utf8" => Ok(TextEncoding::Utf8),
+            "utf16" => Ok(TextEncoding::Utf16),
+            "cp1252" | "windows1252" => Ok(TextEncoding::Cp1252),
+            _ => Err(error!("Unknown encoding \"{}\", expected auto, utf8, utf16 or cp1252.", s))
+        }
+    }
+}
+
+/// Line ending used when writing text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Newline {
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    Crlf,
+}
+
+impl FromStr for Newline {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Newline> {
+        match s.to_lowercase().as_str() {
+            "lf" => Ok(Newline::Lf),
+            "crlf" => Ok(Newline::Crlf),
+            _ => Err(error!("Unknown newline style \"{}\", expected lf or crlf.", s))
+        }
+    }
+}
+
+/// Converts all line endings in text to the given style.
+pub fn normalize_newlines(text: &str, newline: Newline) -> String {
+    let text = text.replace("\r\n", "\n");
+
+    match newline {
+        Newline::Lf => text,
+        Newline::Crlf => text.replace("\n", "\r\n")
+    }
+}
+
+/// Decodes text in the given encoding, removing any BOM.
+pub fn decode_text(bytes: &[u8], encoding: TextEncoding) -> io::Result<String> {
+    let encoding = match encoding {
+        TextEncoding::Auto if bytes.starts_with(&[0xff, 0xfe]) || bytes.starts_with(&[0xfe, 0xff]) => TextEncoding::Utf16,
+        TextEncoding::Auto if std::str::from_utf8(bytes).is_ok() => TextEncoding::Utf8,
+        TextEncoding::Auto => TextEncoding::Cp1252,
+        encoding => encoding
+    };
+
+    match encoding {
+        TextEncoding::Utf8 => {
+            let bytes = if bytes.starts_with(&[0xef, 0xbb, 0xbf]) { &bytes[3..] } else { bytes };
+            String::from_utf8(bytes.to_vec()).map_err(|e| error!("Input is not valid UTF-8: {}", e))
+        },
+        TextEncoding::Utf16 => {
+            let (text, _, malformed) = if bytes.starts_with(&[0xfe, 0xff]) { UTF_16BE.decode(bytes) } else { UTF_16LE.decode(bytes) };
+            if malformed { return Err(error!("Input is not valid UTF-16")); }
+            Ok(text.into_owned())
+        },
+        _ => Ok(WINDOWS_1252.decode(bytes).0.into_owned())
+    }
+}
+
+/// Encodes text in the given encoding. UTF-16 is written as little endian with a BOM.
+pub fn encode_text(text: &str, encoding: TextEncoding) -> Vec<u8> {
+    match encoding {
+        TextEncoding::Auto | TextEncoding::Utf8 => text.as_bytes().to_vec(),
+        TextEncoding::Utf16 => {
+            let mut bytes = vec![0xff, 0xfe];
+            for unit in text.encode_utf16() {
+                bytes.extend_from_slice(&unit.to_le_bytes());
+            }
+            bytes
+        },
+        TextEncoding::Cp1252 => WINDOWS_1252.encode(text).0.into_owned()
+    }
+}
+
+/// Reads all of input and decodes it in the given encoding.
+pub fn read_text<I: Read>(input: &mut I, encoding: TextEncoding) -> io::Result<String> {
+    let mut buffer: Vec<u8> = Vec::new();
+    input.read_to_end(&mut buffer)?;
+    decode_text(&buffer, encoding)
+}
+
 pub trait ReadExt: Read {
     fn read_cstring(&mut self) -> io::Result<String>;
     fn read_compressed_int(&mut self) -> io::Result<u32>;
@@ -63,7 +221,9 @@ impl<T: Read> ReadExt for T {
             }
         }
 
-        Ok(String::from_utf8(bytes).unwrap())
+        String::from_utf8(bytes).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Invalid UTF-8 in string {:?}.", String::from_utf8_lossy(e.as_bytes())))
+        })
     }
 
     fn read_compressed_int(&mut self) -> io::Result<u32> {
@@ -4,6 +4,8 @@ pub mod binarize;
 pub mod config;
 pub mod error;
 pub mod io;
+pub mod lzo;
+pub mod lzss;
 pub mod p3d;
 pub mod pbo;
 pub mod preprocess;
@@ -0,0 +1,169 @@
+//! Functions for the LZO1X compression used for PAA mipmaps and arrays in ODOL P3Ds
+//!
+//! The ODOL reader in `p3d` uses `decompress_prefix` for compressed arrays. There is no PAA reader
+//! yet, `decompress` can be used on the data of a mipmap directly.
+
+use std::io::{Error};
+
+struct Decompressor<'a> {
+    input: &'a [u8],
+    position: usize,
+    output: Vec<u8>,
+    expected_size: usize,
+}
+
+// Position in the LZO1X state machine, named after the labels of the reference decompressor
+enum State {
+    Start,
+    FirstLiteralRun,
+    Match(usize),
+    MatchNext(usize),
+}
+
+impl<'a> Decompressor<'a> {
+    fn byte(&mut self) -> Result<usize, Error> {
+        let byte = *self.input.get(self.position).ok_or_else(|| error!("Compressed data ended unexpectedly."))?;
+        self.position += 1;
+        Ok(byte as usize)
+    }
+
+    // lengths that don't fit into the instruction are continued with zero bytes, each adding 255
+    fn run_length(&mut self) -> Result<usize, Error> {
+        let mut length = 0;
+        loop {
+            match self.byte()? {
+                0 => { length += 255; },
+                byte => { return Ok(length + byte); }
+            }
+        }
+    }
+
+    fn literals(&mut self, length: usize) -> Result<(), Error> {
+        let end = self.position + length;
+        if end > self.input.len() {
+            return Err(error!("Compressed data ended unexpectedly."));
+        }
+
+        self.output.extend_from_slice(&self.input[self.position..end]);
+        self.position = end;
+        self.check_size()
+    }
+
+    fn copy_match(&mut self, distance: usize, length: usize) -> Result<(), Error> {
+        if distance == 0 || distance > self.output.len() {
+            return Err(error!("Invalid back reference {} bytes before position {}.", distance, self.output.len()));
+        }
+
+        // byte by byte, since the match may overlap the bytes it produces
+        let start = self.output.len() - distance;
+        for i in 0..length {
+            let byte = self.output[start + i];
+            self.output.push(byte);
+        }
+        self.check_size()
+    }
+
+    fn check_size(&self) -> Result<(), Error> {
+        if self.output.len() > self.expected_size {
+            return Err(error!("Decompressed data is larger than the expected {} bytes.", self.expected_size));
+        }
+        Ok(())
+    }
+
+    // the low two bits of the last instruction byte are the number of literals after a match
+    fn after_match(last: usize) -> State {
+        match last & 3 {
+            0 => State::Start,
+            n => State::MatchNext(n)
+        }
+    }
+}
+
+/// Decompresses the given data, which has to decompress to exactly `expected_size` bytes.
+pub fn decompress(input: &[u8], expected_size: usize) -> Result<Vec<u8>, Error> {
+    decompress_prefix(input, expected_size).map(|(output, _)| output)
+}
+
+/// Decompresses the compressed data at the start of `input`, which has to decompress to exactly
+/// `expected_size` bytes. Returns the decompressed data and the number of bytes read from `input`.
+pub fn decompress_prefix(input: &[u8], expected_size: usize) -> Result<(Vec<u8>, usize), Error> {
+    let mut d = Decompressor {
+        input,
+        position: 0,
+        output: Vec::new(),
+        expected_size,
+    };
+
+    let mut state = match input.first() {
+        Some(first) if *first > 17 => {
+            d.position = 1;
+            let length = *first as usize - 17;
+            if length < 4 {
+                State::MatchNext(length)
+            } else {
+                d.literals(length)?;
+                State::FirstLiteralRun
+            }
+        },
+        _ => State::Start
+    };
+
+    loop {
+        state = match state {
+            State::Start => {
+                let t = d.byte()?;
+                if t >= 16 {
+                    State::Match(t)
+                } else {
+                    let length = if t == 0 { 15 + d.run_length()? } else { t };
+                    d.literals(length + 3)?;
+                    State::FirstLiteralRun
+                }
+            },
+            State::FirstLiteralRun => {
+                let t = d.byte()?;
+                if t >= 16 {
+                    State::Match(t)
+                } else {
+                    let distance = 1 + 0x0800 + (t >> 2) + (d.byte()? << 2);
+                    d.copy_match(distance, 3)?;
+                    Decompressor::after_match(t)
+                }
+            },
+            State::Match(t) => {
+                let (distance, length, last) = if t >= 64 {
+                    let distance = 1 + ((t >> 2) & 7) + (d.byte()? << 3);
+                    (distance, (t >> 5) + 1, t)
+                } else if t >= 32 {
+                    let length = match t & 31 { 0 => 31 + d.run_length()?, n => n };
+                    let (low, high) = (d.byte()?, d.byte()?);
+                    (1 + (low >> 2) + (high << 6), length + 2, low)
+                } else if t >= 16 {
+                    let length = match t & 7 { 0 => 7 + d.run_length()?, n => n };
+                    let (low, high) = (d.byte()?, d.byte()?);
+                    let distance = ((t & 8) << 11) + (low >> 2) + (high << 6);
+                    if distance == 0 {
+                        break;
+                    }
+                    (distance + 0x4000, length + 2, low)
+                } else {
+                    let distance = 1 + (t >> 2) + (d.byte()? << 2);
+                    (distance, 2, t)
+                };
+
+                d.copy_match(distance, length)?;
+                Decompressor::after_match(last)
+            },
+            State::MatchNext(length) => {
+                d.literals(length)?;
+                State::Match(d.byte()?)
+            }
+        };
+    }
+
+    if d.output.len() != expected_size {
+        return Err(error!("Decompressed data is {} bytes long instead of the expected {} bytes.", d.output.len(), expected_size));
+    }
+
+    Ok((d.output, d.position))
+}
@@ -0,0 +1,136 @@
+//! Functions for the LZSS compression used in PBOs
+
+use std::io::{Cursor, Error, Read};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::error::*;
+
+const WINDOW_SIZE: usize = 4095;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 18;
+const MAX_CANDIDATES: usize = 256;
+const HASH_SIZE: usize = 1 << 12;
+
+fn hash(bytes: &[u8]) -> usize {
+    ((bytes[0] as usize) << 8 ^ (bytes[1] as usize) << 4 ^ bytes[2] as usize) & (HASH_SIZE - 1)
+}
+
+fn insert(input: &[u8], pos: usize, head: &mut [Option<usize>], prev: &mut [Option<usize>]) {
+    if pos + MIN_MATCH <= input.len() {
+        let h = hash(&input[pos..]);
+        prev[pos] = head[h];
+        head[h] = Some(pos);
+    }
+}
+
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0u32, |sum, b| sum.wrapping_add(u32::from(*b)))
+}
+
+/// Compresses the given data, including the trailing checksum.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output: Vec<u8> = Vec::with_capacity(input.len() + input.len() / 8 + 5);
+    let mut head: Vec<Option<usize>> = vec![None; HASH_SIZE];
+    let mut prev: Vec<Option<usize>> = vec![None; input.len()];
+
+    let mut pos = 0;
+    while pos < input.len() {
+        let flag_index = output.len();
+        output.push(0);
+
+        for bit in 0..8 {
+            if pos >= input.len() { break; }
+
+            let mut best_len = 0;
+            let mut best_dist = 0;
+
+            if pos + MIN_MATCH <= input.len() {
+                let max_len = MAX_MATCH.min(input.len() - pos);
+                let mut candidate = head[hash(&input[pos..])];
+                let mut checked = 0;
+
+                while let Some(c) = candidate {
+                    if pos - c > WINDOW_SIZE || checked >= MAX_CANDIDATES { break; }
+
+                    let len = input[c..].iter().zip(&input[pos..pos + max_len]).take_while(|(a, b)| a == b).count();
+                    if len > best_len {
+                        best_len = len;
+                        best_dist = pos - c;
+                        if len == max_len { break; }
+                    }
+
+                    candidate = prev[c];
+                    checked += 1;
+                }
+            }
+
+            if best_len >= MIN_MATCH {
+                output.push((best_dist & 0xff) as u8);
+                output.push((((best_dist >> 4) & 0xf0) | (best_len - MIN_MATCH)) as u8);
+
+                for i in pos..(pos + best_len) {
+                    insert(input, i, &mut head, &mut prev);
+                }
+                pos += best_len;
+            } else {
+                output[flag_index] |= 1 << bit;
+                output.push(input[pos]);
+
+                insert(input, pos, &mut head, &mut prev);
+                pos += 1;
+            }
+        }
+    }
+
+    output.write_u32::<LittleEndian>(checksum(input)).unwrap();
+
+    output
+}
+
+/// Decompresses the given data to `expected_size` bytes.
+///
+/// If the data is followed by a checksum, it is verified against the decompressed data.
+pub fn decompress(input: &[u8], expected_size: usize) -> Result<Vec<u8>, Error> {
+    // the expected size comes from PBO headers, so it is only trusted as far as the input can
+    // plausibly expand
+    let mut output: Vec<u8> = Vec::with_capacity(expected_size.min(input.len().saturating_mul(8)));
+    let mut reader = Cursor::new(input);
+
+    while output.len() < expected_size {
+        let flags = reader.read_u8().prepend_error("Compressed data ended unexpectedly:")?;
+
+        for bit in 0..8 {
+            if output.len() >= expected_size { break; }
+
+            if flags & (1 << bit) != 0 {
+                output.push(reader.read_u8().prepend_error("Compressed data ended unexpectedly:")?);
+                continue;
+            }
+
+            let mut pointer = [0; 2];
+            reader.read_exact(&mut pointer).prepend_error("Compressed data ended unexpectedly:")?;
+
+            let distance = pointer[0] as usize | ((pointer[1] as usize & 0xf0) << 4);
+            let length = (pointer[1] as usize & 0x0f) + MIN_MATCH;
+            if distance == 0 {
+                return Err(error!("Invalid back reference at position {} of compressed data.", reader.position() - 2));
+            }
+            let start = output.len() as isize - distance as isize;
+
+            for i in 0..length {
+                if output.len() >= expected_size { break; }
+
+                let position = start + i as isize;
+                let byte = if position < 0 { b' ' } else { output[position as usize] };
+                output.push(byte);
+            }
+        }
+    }
+
+    if input.len() - reader.position() as usize >= 4 && reader.read_u32::<LittleEndian>()? != checksum(&output) {
+        return Err(error!("Checksum of decompressed data doesn't match"));
+    }
+
+    Ok(output)
+}
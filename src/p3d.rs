@@ -1,8 +1,10 @@
-use std::io::{Read, Seek, Write, Error, BufReader, BufWriter};
+use std::collections::{BTreeSet};
+use std::io::{Read, Seek, Write, Error, BufReader, BufWriter, Cursor};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use linked_hash_map::LinkedHashMap;
 
+use crate::error::*;
 use crate::io::*;
 
 #[derive(Debug, Default)]
@@ -204,6 +206,55 @@ impl LOD {
         })
     }
 
+    // Only reads the data up to and including the materials. The textures and materials are
+    // returned as one face without vertices each, since the actual faces aren't read.
+    fn read_odol<I: Read>(input: &mut I, version: u32, resolution: f32) -> Result<LOD, Error> {
+        // proxies
+        for _i in 0..input.read_u32::<LittleEndian>()? {
+            input.read_cstring()?;
+            skip(input, 12 * 4 + 4 * 4)?;
+        }
+
+        // sub skeleton to skeleton
+        skip_array(input, 4)?;
+
+        // skeleton to sub skeleton
+        for _i in 0..input.read_u32::<LittleEndian>()? {
+            skip_array(input, 4)?;
+        }
+
+        // vertex count, face area, hints, bounding box and sphere
+        skip(input, 4)?;
+        if version >= 51 {
+            skip(input, 4)?;
+        }
+        skip(input, 2 * 4 + 10 * 4)?;
+
+        let mut faces: Vec<Face> = Vec::new();
+
+        for _i in 0..input.read_u32::<LittleEndian>()? {
+            let mut face = Face::new();
+            face.texture = input.read_cstring()?;
+            faces.push(face);
+        }
+
+        for _i in 0..input.read_u32::<LittleEndian>()? {
+            let mut face = Face::new();
+            face.material = read_embedded_material(input)?;
+            faces.push(face);
+        }
+
+        Ok(LOD {
+            version_major: 0,
+            version_minor: 0,
+            resolution,
+            points: Vec::new(),
+            face_normals: Vec::new(),
+            faces,
+            taggs: LinkedHashMap::new(),
+        })
+    }
+
     fn write<O: Write>(&self, output: &mut O) -> Result<(), Error> {
         output.write_all(b"P3DM")?;
         output.write_u32::<LittleEndian>(self.version_major)?;
@@ -245,21 +296,319 @@ impl LOD {
     }
 }
 
+fn skip<I: Read>(input: &mut I, length: u64) -> Result<(), Error> {
+    let skipped = std::io::copy(&mut input.take(length), &mut std::io::sink())?;
+    if skipped < length {
+        return Err(error!("Unexpected end of file."));
+    }
+    Ok(())
+}
+
+// Size of `count` elements of `element_size` bytes each, with the count read from the file.
+fn array_size(count: u32, element_size: u64) -> Result<u64, Error> {
+    u64::from(count).checked_mul(element_size).ok_or_else(|| error!("Array of {} elements is too large.", count))
+}
+
+// Skips an array prefixed with its number of elements.
+fn skip_array<I: Read>(input: &mut I, element_size: u64) -> Result<(), Error> {
+    let count = input.read_u32::<LittleEndian>()?;
+    skip(input, array_size(count, element_size)?)
+}
+
+// Skips an array prefixed with its number of elements that is LZO compressed if it is large
+// enough or, starting with version 64, if the flag following the count is set.
+fn skip_compressed_array(input: &mut Cursor<&[u8]>, version: u32, element_size: u64) -> Result<(), Error> {
+    let count = input.read_u32::<LittleEndian>()?;
+    let size = array_size(count, element_size)?;
+    if size == 0 {
+        return Ok(());
+    }
+
+    let compressed = if version >= 64 { input.read_u8()? != 0 } else { size >= 1024 };
+    if !compressed {
+        return skip(input, size);
+    }
+
+    // the compressed length isn't stored, so the data has to be decompressed to find its end
+    let position = input.position() as usize;
+    let data: &[u8] = input.get_ref();
+    let (_, length) = crate::lzo::decompress_prefix(&data[position.min(data.len())..], size as usize)
+        .prepend_error("Failed to decompress array:")?;
+    input.set_position((position + length) as u64);
+    Ok(())
+}
+
+// Reads a material embedded in an ODOL LOD and returns its name.
+fn read_embedded_material<I: Read>(input: &mut I) -> Result<String, Error> {
+    let name = input.read_cstring()?;
+    let version = input.read_u32::<LittleEndian>()?;
+
+    // colors, specular power, shaders, main light and fog mode
+    skip(input, 6 * 4 * 4 + 4 + 4 * 4)?;
+
+    if version == 3 {
+        skip(input, 1)?;
+    }
+    if version >= 6 {
+        input.read_cstring()?;
+    }
+    if version >= 4 {
+        skip(input, 2 * 4)?;
+    }
+
+    let num_stages = if version > 4 { input.read_u32::<LittleEndian>()? } else { 0 };
+    let num_texgens = if version > 8 { input.read_u32::<LittleEndian>()? } else { num_stages };
+
+    let read_stage_texture = |input: &mut I| -> Result<(), Error> {
+        if version >= 5 {
+            skip(input, 4)?;
+        }
+        input.read_cstring()?;
+        if version >= 8 {
+            skip(input, 4)?;
+        }
+        if version >= 11 {
+            skip(input, 1)?;
+        }
+        Ok(())
+    };
+
+    for _i in 0..num_stages {
+        read_stage_texture(input)?;
+    }
+
+    // UV source and transformation matrix
+    skip(input, array_size(num_texgens, 4 + 12 * 4)?)?;
+
+    if version >= 10 {
+        read_stage_texture(input)?;
+    }
+
+    Ok(name)
+}
+
+// Skips the skeleton stored in the model info of ODOL files.
+fn skip_odol_skeleton(input: &mut Cursor<&[u8]>, version: u32) -> Result<(), Error> {
+    let name = input.read_cstring()?;
+    if name.is_empty() {
+        return Ok(());
+    }
+
+    // discrete flag
+    skip(input, 1)?;
+
+    for _i in 0..input.read_u32::<LittleEndian>()? {
+        // bone and parent name
+        input.read_cstring()?;
+        input.read_cstring()?;
+    }
+
+    if version > 40 {
+        // pivots name
+        input.read_cstring()?;
+    }
+
+    Ok(())
+}
+
+// Skips the model info of ODOL files, which precedes the animations and LOD addresses.
+fn skip_odol_model_info(input: &mut Cursor<&[u8]>, version: u32, num_lods: u32) -> Result<(), Error> {
+    // special flags, spheres, remarks, hints, aiming center, colors, view density, bounding box
+    skip(input, 6 * 4 + 3 * 4 + 3 * 4 + 2 * 3 * 4)?;
+    if version >= 70 {
+        // LOD density coefficient
+        skip(input, 4)?;
+    }
+    if version >= 71 {
+        // draw importance
+        skip(input, 4)?;
+    }
+    if version >= 52 {
+        // visual bounding box
+        skip(input, 2 * 3 * 4)?;
+    }
+
+    // bounding, geometry and mass centers, inverse inertia, auto center and occlusion flags
+    skip(input, 3 * 3 * 4 + 9 * 4 + 4)?;
+    if version >= 73 {
+        // AI covers flag
+        skip(input, 1)?;
+    }
+
+    // thermal properties, alpha and shadow settings, animated flag
+    skip(input, 4 * 4 + 2 * 4 + 1 + 4 + 1 + 4 + 1)?;
+
+    skip_odol_skeleton(input, version).prepend_error("Failed to read skeleton:")?;
+
+    // map type, masses and armor
+    skip(input, 1)?;
+    skip_compressed_array(input, version, 4)?;
+    skip(input, 4 * 4)?;
+    if version >= 72 {
+        // explosion shielding
+        skip(input, 4)?;
+    }
+
+    // special LOD indices
+    if version >= 53 {
+        skip(input, 1)?;
+    }
+    if version >= 54 {
+        skip(input, 1)?;
+    }
+    skip(input, 12)?;
+
+    // minimum shadow LOD, blend flag
+    skip(input, 4 + 1)?;
+
+    // class and damage properties, frequent flag, unknown
+    input.read_cstring()?;
+    input.read_cstring()?;
+    skip(input, 1 + 4)?;
+
+    if version >= 57 {
+        // preferred shadow volume, shadow buffer and visual shadow buffer LODs
+        skip(input, array_size(num_lods, 3 * 4)?)?;
+    }
+
+    Ok(())
+}
+
+// Skips the animations of ODOL files, which follow the model info if present.
+fn skip_odol_animations(input: &mut Cursor<&[u8]>, version: u32) -> Result<(), Error> {
+    let mut types: Vec<u32> = Vec::new();
+
+    for _i in 0..input.read_u32::<LittleEndian>()? {
+        let animation_type = input.read_u32::<LittleEndian>()?;
+        types.push(animation_type);
+
+        // name, source, phases and values
+        input.read_cstring()?;
+        input.read_cstring()?;
+        skip(input, 4 * 4)?;
+        if version >= 56 {
+            // period and initial phase
+            skip(input, 2 * 4)?;
+        }
+        // source address
+        skip(input, 4)?;
+
+        match animation_type {
+            // rotation and translation: angles or offsets
+            0..=7 => skip(input, 2 * 4)?,
+            // direct: axis position and direction, angle and axis offset
+            8 => skip(input, 2 * 3 * 4 + 2 * 4)?,
+            // hide: hide and unhide values
+            9 => skip(input, if version >= 55 { 2 * 4 } else { 4 })?,
+            _ => { return Err(error!("Unknown animation type {}.", animation_type)); }
+        }
+    }
+
+    let num_resolutions = input.read_u32::<LittleEndian>()?;
+
+    // animations of every bone
+    for _i in 0..num_resolutions {
+        for _j in 0..input.read_u32::<LittleEndian>()? {
+            skip_array(input, 4)?;
+        }
+    }
+
+    // bone and axis of every animation
+    for _i in 0..num_resolutions {
+        for animation_type in &types {
+            let bone = input.read_i32::<LittleEndian>()?;
+            if bone != -1 && *animation_type != 8 && *animation_type != 9 {
+                skip(input, 2 * 3 * 4)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
 impl P3D {
+    /// Reads a P3D in either the editable MLOD or the binarized ODOL format.
+    ///
+    /// For ODOL files (versions 50 to 73), only the LOD resolutions and the textures and
+    /// materials are read. Each LOD contains one face without vertices for every texture and
+    /// material, so they can be listed the same way as for MLOD files.
     #[allow(dead_code)]
     pub fn read<I: Read + Seek>(input: &mut I) -> Result<P3D, Error> {
         let mut reader = BufReader::new(input);
 
         let mut buffer = [0; 4];
         reader.read_exact(&mut buffer)?;
-        assert_eq!(&buffer, b"MLOD");
 
+        match &buffer {
+            b"MLOD" => P3D::read_mlod(&mut reader),
+            b"ODOL" => P3D::read_odol(&mut reader),
+            _ => Err(error!("Unknown P3D format {:?}.", String::from_utf8_lossy(&buffer)))
+        }
+    }
+
+    fn read_odol<I: Read>(input: &mut I) -> Result<P3D, Error> {
+        // LOD addresses are relative to the start of the file
+        let mut data = b"ODOL".to_vec();
+        input.read_to_end(&mut data)?;
+        let mut cursor = Cursor::new(&data[..]);
+        cursor.set_position(4);
+
+        let version = cursor.read_u32::<LittleEndian>()?;
+        if !(50..=73).contains(&version) {
+            return Err(error!("ODOL version {} is not supported.", version));
+        }
+
+        if version >= 59 {
+            // application ID
+            cursor.read_u32::<LittleEndian>()?;
+        }
+        if version >= 58 {
+            // prefix
+            cursor.read_cstring()?;
+        }
+
+        let num_lods = cursor.read_u32::<LittleEndian>()?;
+        let mut resolutions: Vec<f32> = Vec::new();
+        for _i in 0..num_lods {
+            resolutions.push(cursor.read_f32::<LittleEndian>()?);
+        }
+
+        skip_odol_model_info(&mut cursor, version, num_lods).prepend_error("Failed to read model info:")?;
+
+        if cursor.read_u8()? != 0 {
+            skip_odol_animations(&mut cursor, version).prepend_error("Failed to read animations:")?;
+        }
+
+        let mut starts: Vec<u64> = Vec::new();
+        for _i in 0..num_lods {
+            starts.push(u64::from(cursor.read_u32::<LittleEndian>()?));
+        }
+
+        let mut lods: Vec<LOD> = Vec::new();
+        for (i, start) in starts.iter().enumerate() {
+            let end = u64::from(cursor.read_u32::<LittleEndian>()?);
+            if start >= &end || end > data.len() as u64 {
+                return Err(error!("LOD {} has the invalid address range {}..{} in a file of {} bytes.", i, start, end, data.len()));
+            }
+
+            let mut lod_cursor = Cursor::new(&data[..end as usize]);
+            lod_cursor.set_position(*start);
+            lods.push(LOD::read_odol(&mut lod_cursor, version, resolutions[i]).map_err(|e| error!("Failed to read LOD {}: {}", i, e))?);
+        }
+
+        Ok(P3D {
+            version,
+            lods,
+        })
+    }
+
+    fn read_mlod<I: Read + Seek>(reader: &mut I) -> Result<P3D, Error> {
         let version = reader.read_u32::<LittleEndian>()?;
         let num_lods = reader.read_u32::<LittleEndian>()?;
         let mut lods: Vec<LOD> = Vec::with_capacity(num_lods as usize);
 
         for _i in 0..num_lods {
-            lods.push(LOD::read(&mut reader)?);
+            lods.push(LOD::read(reader)?);
         }
 
         Ok(P3D {
@@ -283,3 +632,26 @@ impl P3D {
         Ok(())
     }
 }
+
+/// Lists the textures and materials used by every LOD of the P3D.
+///
+/// Each LOD is written as a `LOD <resolution>` line, followed by one `texture <path>` or
+/// `material <path>` line per dependency, sorted and without procedural textures.
+pub fn cmd_p3d_info<I: Read + Seek, O: Write>(input: &mut I, output: &mut O) -> Result<(), Error> {
+    let p3d = P3D::read(input).prepend_error("Failed to read P3D:")?;
+
+    for lod in &p3d.lods {
+        let textures: BTreeSet<&str> = lod.faces.iter().map(|f| f.texture.as_str()).filter(|t| !t.is_empty() && !t.starts_with('#')).collect();
+        let materials: BTreeSet<&str> = lod.faces.iter().map(|f| f.material.as_str()).filter(|m| !m.is_empty() && !m.starts_with('#')).collect();
+
+        writeln!(output, "LOD {}", lod.resolution)?;
+        for texture in textures {
+            writeln!(output, "    texture {}", texture)?;
+        }
+        for material in materials {
+            writeln!(output, "    material {}", material)?;
+        }
+    }
+
+    Ok(())
+}
@@ -1,27 +1,241 @@
-use std::collections::{HashMap};
+use std::borrow::{Cow};
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::{OsStr};
+use std::fmt;
 use std::fs::{File, create_dir_all, read_dir};
-use std::io::{Read, Write, Seek, SeekFrom, Error, Cursor};
-use std::path::{PathBuf};
+use std::io::{BufWriter, Read, Write, Seek, SeekFrom, Error, Cursor, copy, sink};
+use std::path::{Path, PathBuf};
+use std::str::{FromStr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use linked_hash_map::{LinkedHashMap};
 use openssl::hash::{Hasher, MessageDigest};
-use regex::{Regex};
+use serde::{Serialize};
 
 use crate::error::*;
 use crate::io::*;
 use crate::config::*;
 use crate::preprocess::*;
 use crate::binarize;
+use crate::lzss;
+
+/// PBO file header
+#[derive(Serialize)]
+pub struct PBOHeader {
+    /// Path of the file inside the PBO
+    pub filename: String,
+    /// Packing method, 0 for uncompressed files
+    pub packing_method: u32,
+    /// Size of the file before packing
+    pub original_size: u32,
+    /// Unused
+    pub reserved: u32,
+    /// File timestamp
+    pub timestamp: u32,
+    /// Size of the file data in the PBO
+    pub data_size: u32,
+}
+
+/// Packing method of a PBO entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackingMethod {
+    /// Data is stored as-is
+    Uncompressed,
+    /// Data is LZSS-compressed (`Cprs`)
+    Compressed,
+    /// Header extension entry (`Vers`)
+    Product,
+    /// Data is encrypted (`Encr`), used by some official PBOs
+    Encrypted,
+    /// Any other value
+    Unknown(u32),
+}
+
+impl PackingMethod {
+    /// Returns the packing method for the raw value from a PBO header.
+    pub fn from_u32(value: u32) -> PackingMethod {
+        match value {
+            0 => PackingMethod::Uncompressed,
+            0x4370_7273 => PackingMethod::Compressed,
+            0x5665_7273 => PackingMethod::Product,
+            0x456e_6372 => PackingMethod::Encrypted,
+            value => PackingMethod::Unknown(value),
+        }
+    }
+
+    /// Returns the raw value used in PBO headers.
+    pub fn to_u32(self) -> u32 {
+        match self {
+            PackingMethod::Uncompressed => 0,
+            PackingMethod::Compressed => 0x4370_7273,
+            PackingMethod::Product => 0x5665_7273,
+            PackingMethod::Encrypted => 0x456e_6372,
+            PackingMethod::Unknown(value) => value,
+        }
+    }
+}
+
+impl fmt::Display for PackingMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PackingMethod::Uncompressed => f.pad("none"),
+            PackingMethod::Compressed => f.pad("lzss"),
+            PackingMethod::Product => f.pad("product"),
+            PackingMethod::Encrypted => f.pad("encrypted"),
+            PackingMethod::Unknown(value) => f.pad(&format!("{:#010x}", value)),
+        }
+    }
+}
+
+/// Order of the files when writing a PBO
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FileOrder {
+    /// Sorted by lowercased name
+    #[default]
+    Lexical,
+    /// In the order of `PBO::files`
+    Insertion,
+}
+
+/// How symbolic links are handled when packing a directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkMode {
+    /// Pack the contents of the link target
+    #[default]
+    Follow,
+    /// Leave links out of the PBO
+    Skip,
+    /// Fail on the first link
+    Error,
+}
+
+impl FromStr for SymlinkMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<SymlinkMode, Error> {
+        match s.to_lowercase().as_str() {
+            "follow" => Ok(SymlinkMode::Follow),
+            "skip" => Ok(SymlinkMode::Skip),
+            "error" => Ok(SymlinkMode::Error),
+            _ => Err(error!("Unknown symlink mode \"{}\", expected follow, skip or error.", s))
+        }
+    }
+}
 
-struct PBOHeader {
-    filename: String,
-    packing_method: u32,
-    original_size: u32,
-    reserved: u32,
-    timestamp: u32,
-    data_size: u32,
+/// `mod.cpp` entries and the header extensions they are taken from, in order of precedence
+const MOD_CPP_ENTRIES: &[(&str, &[&str])] = &[
+    ("name", &["name", "product", "prefix"]),
+    ("author", &["author"]),
+    ("version", &["version"]),
+];
+
+/// Header extension added to packed and built PBOs, with the armake2 version as value
+const VERSION_EXTENSION: &str = "armake";
+
+/// Size of the buffer used when writing PBOs, to avoid many small writes for the header block.
+const WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// File extensions that are not compressed by default because the formats are compressed already
+pub const PRECOMPRESSED_EXTENSIONS: &[&str] = &["paa", "pac", "ogg", "wss", "jpg", "png", "lip"];
+
+/// What happens to a file when building a PBO
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileAction {
+    /// Rapify the file as a config
+    Rapify,
+    /// Binarize the file with binarize.exe, only possible on Windows
+    Binarize,
+    /// Copy the file as it is
+    Copy,
+}
+
+/// Decides what happens to files when building a PBO, based on their extensions. Extensions
+/// without an action are copied.
+///
+/// # Examples
+///
+/// ```
+/// # use std::path::Path;
+/// # use armake2::pbo::{ExtensionClassifier, FileAction};
+/// let mut classifier = ExtensionClassifier::default();
+/// classifier.set("myext", FileAction::Rapify);
+///
+/// assert_eq!(FileAction::Rapify, classifier.classify(Path::new("data/foo.myext")));
+/// assert_eq!(FileAction::Rapify, classifier.classify(Path::new("config.cpp")));
+/// assert_eq!(FileAction::Copy, classifier.classify(Path::new("script.sqf")));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ExtensionClassifier {
+    actions: HashMap<String, FileAction>,
+}
+
+impl Default for ExtensionClassifier {
+    /// Rapifies `cpp` and `rvmat` files and binarizes `p3d` and `rtm` files.
+    fn default() -> ExtensionClassifier {
+        let mut classifier = ExtensionClassifier::new();
+        classifier.set("cpp", FileAction::Rapify);
+        classifier.set("rvmat", FileAction::Rapify);
+        classifier.set("p3d", FileAction::Binarize);
+        classifier.set("rtm", FileAction::Binarize);
+        classifier
+    }
+}
+
+impl ExtensionClassifier {
+    /// Creates a classifier that copies all files.
+    pub fn new() -> ExtensionClassifier {
+        ExtensionClassifier {
+            actions: HashMap::new(),
+        }
+    }
+
+    /// Sets the action for files with the given extension (without the dot, case-insensitive).
+    pub fn set(&mut self, extension: &str, action: FileAction) {
+        self.actions.insert(extension.trim_start_matches('.').to_lowercase(), action);
+    }
+
+    /// Returns the action for the given file.
+    pub fn classify(&self, path: &Path) -> FileAction {
+        path.extension()
+            .and_then(|e| self.actions.get(&e.to_string_lossy().to_lowercase()))
+            .cloned()
+            .unwrap_or(FileAction::Copy)
+    }
+
+    /// Reads the actions from a config containing the arrays `rapify[]`, `binarize[]` and
+    /// `copy[]` with extensions, which override the default actions.
+    ///
+    /// ```text
+    /// rapify[] = {"myext"};
+    /// copy[] = {"rvmat"};
+    /// ```
+    pub fn from_config(config: &Config) -> Result<ExtensionClassifier, Error> {
+        let mut classifier = ExtensionClassifier::default();
+
+        for (key, action) in &[("rapify", FileAction::Rapify), ("binarize", FileAction::Binarize), ("copy", FileAction::Copy)] {
+            let entry = match config.get(&[key]) {
+                Some(entry) => entry,
+                None => { continue; }
+            };
+
+            let array = entry.as_array().ok_or_else(|| error!("\"{}\" has to be an array of extensions.", key))?;
+            for element in array.elements() {
+                match element {
+                    ConfigArrayElement::StringElement(extension) => classifier.set(extension, *action),
+                    _ => { return Err(error!("\"{}\" has to be an array of extensions.", key)); }
+                }
+            }
+        }
+
+        Ok(classifier)
+    }
+
+    /// Reads the actions from the config file at the given path, see `from_config`.
+    pub fn read(path: &Path) -> Result<ExtensionClassifier, Error> {
+        let config = Config::read(&mut File::open(path)?, Some(path.to_path_buf()), &Vec::new())?;
+        ExtensionClassifier::from_config(&config)
+    }
 }
 
 /// PBO file
@@ -42,17 +256,173 @@ struct PBOHeader {
 /// ```
 pub struct PBO {
     pub files: LinkedHashMap<String, Cursor<Box<[u8]>>>,
-    pub header_extensions: HashMap<String, String>,
-    headers: Vec<PBOHeader>,
-    /// only defined when reading existing PBOs, for created PBOs this is calculated during writing
-    /// and included in the output
+    pub header_extensions: LinkedHashMap<String, String>,
+    /// headers as read from an existing PBO or generated from the source files; only the
+    /// timestamps are used when writing
+    pub headers: Vec<PBOHeader>,
+    /// whether to compress files when writing
+    pub compress: bool,
+    /// extensions of files that are stored uncompressed even if `compress` is set
+    pub uncompressed_extensions: Vec<String>,
+    /// order of the files when writing
+    pub file_order: FileOrder,
+    /// only defined when reading existing PBOs; `write` always calculates a new checksum from the
+    /// current files and includes it in the output
     pub checksum: Option<Vec<u8>>,
+    /// whether the prefix was set to the folder name, because the directory the PBO was created
+    /// from had no `$PBOPREFIX$` file or an empty prefix in it
+    pub prefix_from_folder_name: bool,
+}
+
+impl Default for PBO {
+    fn default() -> PBO {
+        PBO::new()
+    }
+}
+
+/// Additional options for `cmd_build` and `cmd_pack`
+#[derive(Default)]
+pub struct PackOptions {
+    /// Compress files when writing the PBO
+    pub compress: bool,
+    /// Extensions of files to store uncompressed, replaces `PRECOMPRESSED_EXTENSIONS` if not empty
+    pub uncompressed: Vec<String>,
+    /// Path to write a JSON build report to
+    pub report: Option<PathBuf>,
+    /// Path the PBO is written to, used in the report
+    pub target: Option<PathBuf>,
+    /// Fail instead of using the folder name if no prefix is set or it is empty
+    pub require_prefix: bool,
+    /// Read rapified configs back when building and fail if they can't be read or change
+    pub validate_configs: bool,
+    /// Decides which files are rapified, binarized or copied when building
+    pub classifier: ExtensionClassifier,
+    /// How symbolic links in the source directory are handled
+    pub symlinks: SymlinkMode,
+    /// Don't add the `armake` header extension with the version that built the PBO
+    pub omit_version_extension: bool,
+    /// binarize.exe to run through Wine on systems other than Windows, see
+    /// `binarize::binarize_with_options`
+    pub binarize_exe: Option<PathBuf>,
+    /// Build byte-identical PBOs from the same files, see `PBO::make_reproducible`
+    pub reproducible: bool,
+}
+
+/// Time spent in the phases of building PBOs, as returned by `cmd_build`, `cmd_pack` and
+/// `cmd_build_all`
+#[derive(Debug, Default, Clone)]
+pub struct BuildProfile {
+    /// Listing the files in the source directory
+    pub file_walking: Duration,
+    /// Reading and preprocessing configs
+    pub preprocessing: Duration,
+    /// Parsing and rapifying preprocessed configs
+    pub rapifying: Duration,
+    /// Running binarize.exe
+    pub binarizing: Duration,
+    /// Compressing files and writing the PBO
+    pub writing: Duration,
+}
+
+impl BuildProfile {
+    fn time<T, F: FnOnce() -> T>(phase: &mut Duration, f: F) -> T {
+        let start = Instant::now();
+        let result = f();
+        *phase += start.elapsed();
+        result
+    }
+
+    fn add(&mut self, other: &BuildProfile) {
+        self.file_walking += other.file_walking;
+        self.preprocessing += other.preprocessing;
+        self.rapifying += other.rapifying;
+        self.binarizing += other.binarizing;
+        self.writing += other.writing;
+    }
+
+    /// Writes one line with the time in seconds for every phase to output.
+    pub fn write<O: Write>(&self, output: &mut O) -> Result<(), Error> {
+        let phases = [
+            ("file walking", &self.file_walking),
+            ("preprocessing", &self.preprocessing),
+            ("rapifying", &self.rapifying),
+            ("binarizing", &self.binarizing),
+            ("writing", &self.writing),
+        ];
+
+        for (label, duration) in phases.iter() {
+            writeln!(output, "{:<14} {:>8.3}s", label, duration.as_secs_f64())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Summary of a PBO build, written for the `--report` option
+#[derive(Serialize)]
+pub struct BuildReport {
+    /// Path the PBO was written to, if any
+    pub output: Option<PathBuf>,
+    /// Number of files in the PBO
+    pub files: usize,
+    /// Total size of all files before compression
+    pub total_size: u64,
+    /// Number of files for each file extension
+    pub extensions: BTreeMap<String, usize>,
+    /// Time the build took in seconds
+    pub elapsed: f64,
+    /// Number of times each warning was raised
+    pub warnings: BTreeMap<String, u32>,
+}
+
+impl BuildReport {
+    /// Creates a report for the given PBO.
+    pub fn new(pbo: &PBO, output: Option<PathBuf>, elapsed: Duration) -> BuildReport {
+        let mut extensions: BTreeMap<String, usize> = BTreeMap::new();
+        for name in pbo.files.keys() {
+            let file_name = name.rsplit('\\').next().unwrap();
+            let extension = match file_name.rfind('.') {
+                Some(index) => file_name[index + 1..].to_lowercase(),
+                None => String::new()
+            };
+            *extensions.entry(extension).or_insert(0) += 1;
+        }
+
+        BuildReport {
+            output,
+            files: pbo.files.len(),
+            total_size: pbo.files.values().map(|c| c.get_ref().len() as u64).sum(),
+            extensions,
+            elapsed: elapsed.as_secs_f64(),
+            warnings: warnings_raised().into_iter().collect(),
+        }
+    }
+
+    /// Writes the report as JSON to the given path.
+    pub fn write(&self, path: &PathBuf) -> Result<(), Error> {
+        let file = File::create(path).prepend_error("Failed to create report file:")?;
+        serde_json::to_writer_pretty(file, self).map_err(|e| error!("{}", e))
+    }
 }
 
 impl PBOHeader {
+    /// Returns the packing method of the entry.
+    pub fn method(&self) -> PackingMethod {
+        PackingMethod::from_u32(self.packing_method)
+    }
+
     fn read<I: Read>(input: &mut I) -> Result<PBOHeader, Error> {
+        let mut filename: Vec<u8> = Vec::new();
+        loop {
+            match input.read_u8()? {
+                0 => break,
+                b => filename.push(b)
+            }
+        }
+
         Ok(PBOHeader {
-            filename: input.read_cstring()?,
+            // filenames are not necessarily UTF-8, and garbage headers contain arbitrary bytes
+            filename: decode_text(&filename, TextEncoding::Auto)?,
             packing_method: input.read_u32::<LittleEndian>()?,
             original_size: input.read_u32::<LittleEndian>()?,
             reserved: input.read_u32::<LittleEndian>()?,
@@ -70,19 +440,49 @@ impl PBOHeader {
         output.write_u32::<LittleEndian>(self.data_size)?;
         Ok(())
     }
+
+    /// Whether this is a junk header (e.g. left by obfuscation tools) rather than a real file:
+    /// its name contains characters that aren't allowed in paths, or it is a header extension
+    /// header after the first one.
+    fn is_garbage(&self) -> bool {
+        self.method() == PackingMethod::Product || self.filename.chars().any(|c| c.is_control() || "<>\"|?*".contains(c))
+    }
 }
 
-fn matches_glob(s: &str, pattern: &str) -> bool {
-    if let Some(index) = pattern.find('*') {
-        if s[..index] != pattern[..index] { return false; }
+/// Matches a file name against a glob pattern, treating `/` and `\` alike.
+///
+/// `?` matches any single character except a separator. `*` matches any number of characters,
+/// including separators, so `*.bak` excludes backups in all folders. `**` does the same, but
+/// `**/` also matches no folder at all, so `**/*.psd` matches `foo.psd` and `data\foo.psd`.
+pub(crate) fn matches_glob(s: &str, pattern: &str) -> bool {
+    let s: Vec<char> = s.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+
+    glob_match(&s, &pattern)
+}
 
-        for i in (index+1)..(s.len()-1) {
-            if matches_glob(&s[i..].to_string(), &pattern[(index+1)..].to_string()) { return true; }
-        }
+fn glob_match(s: &[char], pattern: &[char]) -> bool {
+    let is_separator = |c: &char| *c == '/' || *c == '\\';
 
-        false
-    } else {
-        s == pattern
+    match pattern {
+        [] => s.is_empty(),
+        ['*', '*', rest @ ..] => {
+            if let [separator, after @ ..] = rest {
+                if is_separator(separator) && glob_match(s, after) {
+                    return true;
+                }
+            }
+            (0..=s.len()).any(|i| glob_match(&s[i..], rest))
+        },
+        ['*', rest @ ..] => (0..=s.len()).any(|i| glob_match(&s[i..], rest)),
+        ['?', rest @ ..] => match s {
+            [c, s_rest @ ..] => !is_separator(c) && glob_match(s_rest, rest),
+            [] => false
+        },
+        [p, rest @ ..] => match s {
+            [c, s_rest @ ..] => (c == p || (is_separator(c) && is_separator(p))) && glob_match(s_rest, rest),
+            [] => false
+        }
     }
 }
 
@@ -94,193 +494,599 @@ fn file_allowed(name: &str, exclude_patterns: &[String]) -> bool {
     true
 }
 
-impl PBO {
-    /// Reads an existing PBO from input.
-    pub fn read<I: Read>(input: &mut I) -> Result<PBO, Error> {
-        let mut headers: Vec<PBOHeader> = Vec::new();
-        let mut first = true;
-        let mut header_extensions: HashMap<String, String> = HashMap::new();
+/// Reader that feeds everything it reads into an optional hasher.
+struct HashingReader<'a, I: Read> {
+    inner: &'a mut I,
+    hasher: Option<Hasher>,
+}
 
-        loop {
-            let header = PBOHeader::read(input)?;
-            // todo: garbage filter
+impl<'a, I: Read> Read for HashingReader<'a, I> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = self.inner.read(buf)?;
+        if let Some(ref mut hasher) = self.hasher {
+            hasher.update(&buf[..n]).unwrap();
+        }
+        Ok(n)
+    }
+}
 
-            if header.packing_method == 0x5665_7273 {
-                if !first { unreachable!(); }
+// Header block and file data of a PBO as written, see `PBO::layout`
+type Layout<'a> = (Vec<u8>, Vec<Cow<'a, [u8]>>);
+
+// The header extensions, with the prefix first, and the file headers as written to a PBO.
+fn header_block(header_extensions: &LinkedHashMap<String, String>, headers: &[PBOHeader]) -> Result<Vec<u8>, Error> {
+    let mut block: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+
+    let ext_header = PBOHeader {
+        filename: "".to_string(),
+        packing_method: PackingMethod::Product.to_u32(),
+        original_size: 0,
+        reserved: 0,
+        timestamp: 0,
+        data_size: 0,
+    };
+    ext_header.write(&mut block)?;
+
+    if let Some(prefix) = header_extensions.get("prefix") {
+        block.write_all(b"prefix\0")?;
+        block.write_cstring(prefix)?;
+    }
 
-                loop {
-                    let s = input.read_cstring()?;
-                    if s.is_empty() { break; }
+    for (key, value) in header_extensions.iter() {
+        if key == "prefix" { continue; }
 
-                    header_extensions.insert(s, input.read_cstring()?);
-                }
-            } else if header.filename == "" {
-                break;
+        block.write_cstring(key)?;
+        block.write_cstring(value)?;
+    }
+    block.write_cstring("".to_string())?;
+
+    for header in headers {
+        header.write(&mut block)?;
+    }
+
+    let header = PBOHeader {
+        packing_method: 0,
+        ..ext_header
+    };
+    header.write(&mut block)?;
+
+    Ok(block.into_inner())
+}
+
+// SHA1 over the header block and file data of a PBO, as stored at its end.
+fn checksum(headers: &[u8], data: &[Cow<'_, [u8]>]) -> Vec<u8> {
+    let mut h = Hasher::new(MessageDigest::sha1()).unwrap();
+
+    h.update(headers).unwrap();
+    for file in data {
+        h.update(file).unwrap();
+    }
+
+    h.finish().unwrap().to_vec()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn read_header_table<I: Read>(input: &mut I) -> Result<(LinkedHashMap<String, String>, Vec<PBOHeader>), Error> {
+    let mut headers: Vec<PBOHeader> = Vec::new();
+    let mut first = true;
+    let mut header_extensions: LinkedHashMap<String, String> = LinkedHashMap::new();
+
+    loop {
+        let header = PBOHeader::read(input)?;
+
+        if header.method() == PackingMethod::Product {
+            let mut extensions: LinkedHashMap<String, String> = LinkedHashMap::new();
+            loop {
+                let s = input.read_cstring()?;
+                if s.is_empty() { break; }
+
+                extensions.insert(s, input.read_cstring()?);
+            }
+
+            if first {
+                header_extensions = extensions;
             } else {
+                warning("Skipping additional header extensions.", Some("garbage-header"), (None, None));
                 headers.push(header);
             }
+        } else if header.is_garbage() {
+            warning(format!("Skipping invalid PBO header {:?}.", header.filename), Some("garbage-header"), (None, None));
+            headers.push(header);
+        } else if header.filename == "" {
+            break;
+        } else {
+            headers.push(header);
+        }
+
+        first = false;
+    }
 
-            first = false;
+    Ok((header_extensions, headers))
+}
+
+// Skips the data of a junk header, which still occupies `data_size` bytes in the data block.
+fn skip_data<I: Read>(input: &mut I, header: &PBOHeader) -> Result<(), Error> {
+    let skipped = copy(&mut input.take(u64::from(header.data_size)), &mut sink())?;
+    if skipped < u64::from(header.data_size) {
+        return Err(error!("Unexpected end of PBO while skipping the data of invalid header {:?} ({} bytes)", header.filename, header.data_size));
+    }
+    Ok(())
+}
+
+// Fails if the data of any header extends past the end of the input, which starts at the current
+// position.
+fn check_data_sizes<I: Seek>(input: &mut I, headers: &[PBOHeader]) -> Result<(), Error> {
+    let position = input.stream_position()?;
+    let end = input.seek(SeekFrom::End(0))?;
+    input.seek(SeekFrom::Start(position))?;
+
+    let mut offset = position;
+    for header in headers {
+        offset += u64::from(header.data_size);
+        if offset > end {
+            return Err(error!("Data of {:?} ({} bytes) extends past the end of the PBO, only {} bytes remain.",
+                header.filename, header.data_size, end - (offset - u64::from(header.data_size))));
+        }
+    }
+
+    Ok(())
+}
+
+impl PBO {
+    /// Creates an empty PBO without files or header extensions.
+    ///
+    /// Files can be added with `add_file`, the checksum is calculated when writing.
+    pub fn new() -> PBO {
+        PBO {
+            files: LinkedHashMap::new(),
+            header_extensions: LinkedHashMap::new(),
+            headers: Vec::new(),
+            compress: false,
+            uncompressed_extensions: PRECOMPRESSED_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+            file_order: FileOrder::default(),
+            checksum: None,
+            prefix_from_folder_name: false,
         }
+    }
+
+    /// Reads an existing PBO from input.
+    ///
+    /// The trailing checksum is read but not verified, see `read_checked`.
+    pub fn read<I: Read>(input: &mut I) -> Result<PBO, Error> {
+        PBO::read_verified(input, false)
+    }
+
+    /// Reads an existing PBO from input and verifies its trailing SHA1 checksum.
+    ///
+    /// Fails if the checksum is missing or doesn't match the header block and file data.
+    pub fn read_checked<I: Read>(input: &mut I) -> Result<PBO, Error> {
+        PBO::read_verified(input, true)
+    }
+
+    fn read_verified<I: Read>(input: &mut I, verify: bool) -> Result<PBO, Error> {
+        let mut reader = HashingReader {
+            inner: input,
+            hasher: if verify { Some(Hasher::new(MessageDigest::sha1()).unwrap()) } else { None },
+        };
+
+        let (header_extensions, mut headers) = read_header_table(&mut reader)?;
 
         let mut files: LinkedHashMap<String, Cursor<Box<[u8]>>> = LinkedHashMap::new();
         for header in &headers {
-            let mut buffer: Box<[u8]> = vec![0; header.data_size as usize].into_boxed_slice();
-            input.read_exact(&mut buffer)?;
+            if header.is_garbage() {
+                skip_data(&mut reader, header)?;
+                continue;
+            }
+
+            // the size is read from the file, so let the buffer grow instead of trusting it
+            let mut buffer: Vec<u8> = Vec::new();
+            reader.by_ref().take(u64::from(header.data_size)).read_to_end(&mut buffer)?;
+            if buffer.len() < header.data_size as usize {
+                return Err(error!("Unexpected end of PBO while reading {} ({} bytes)", header.filename, header.data_size));
+            }
+            let mut buffer = buffer.into_boxed_slice();
+
+            if header.method() == PackingMethod::Compressed {
+                buffer = lzss::decompress(&buffer, header.original_size as usize)
+                    .prepend_error(format!("Failed to decompress {}:", header.filename))?.into_boxed_slice();
+            }
+
             files.insert(header.filename.clone(), Cursor::new(buffer));
         }
 
+        headers.retain(|h| !h.is_garbage());
+
+        let digest = reader.hasher.map(|mut h| h.finish().unwrap());
+        let input = reader.inner;
+
         input.bytes().next();
-        let mut checksum = vec![0; 20];
-        input.read_exact(&mut checksum)?;
+        let mut checksum = Vec::with_capacity(20);
+        input.take(20).read_to_end(&mut checksum)?;
+
+        let checksum = match checksum.len() {
+            20 => Some(checksum),
+            0 if verify => { return Err(error!("PBO has no checksum.")); },
+            0 => {
+                warning("PBO has no checksum.", Some("missing-checksum"), (None, None));
+                None
+            },
+            _ => { return Err(error!("Incomplete PBO checksum ({} of 20 bytes)", checksum.len())); }
+        };
+
+        if let (Some(digest), Some(checksum)) = (&digest, &checksum) {
+            if digest[..] != checksum[..] {
+                return Err(error!("PBO checksum mismatch (stored {}, computed {})", to_hex(checksum), to_hex(digest)));
+            }
+        }
 
         Ok(PBO {
             files,
             header_extensions,
             headers,
-            checksum: Some(checksum),
+            compress: false,
+            uncompressed_extensions: PRECOMPRESSED_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+            file_order: FileOrder::default(),
+            checksum,
+            prefix_from_folder_name: false,
         })
     }
 
+    /// Returns the name, absolute offset and data size of every file in the PBO read from input.
+    ///
+    /// Only the header table is read, so this can be used to index PBOs without reading the file
+    /// data. The offsets point to the data as stored in the PBO, which may be compressed.
+    pub fn entry_offsets<I: Read + Seek>(input: &mut I) -> Result<Vec<(String, u64, u32)>, Error> {
+        let (_, headers) = read_header_table(input)?;
+        check_data_sizes(input, &headers)?;
+
+        let mut offset = input.stream_position()?;
+        let mut offsets = Vec::with_capacity(headers.len());
+        for header in headers {
+            offset += u64::from(header.data_size);
+            if header.is_garbage() { continue; }
+
+            offsets.push((header.filename, offset - u64::from(header.data_size), header.data_size));
+        }
+
+        Ok(offsets)
+    }
+
+    /// Reads a single file from the PBO in input without loading the other files.
+    ///
+    /// Only the header table and the data of the requested file are read. Compressed files are
+    /// decompressed. Like `get_file`, the name is matched case-insensitively.
+    pub fn extract_file<I: Read + Seek>(input: &mut I, name: &str) -> Result<Vec<u8>, Error> {
+        let (_, headers) = read_header_table(input)?;
+
+        let normalized = normalize_path(name);
+
+        let mut offset = input.stream_position()?;
+        for (i, header) in headers.iter().enumerate() {
+            if header.is_garbage() || normalize_path(&header.filename) != normalized {
+                offset += u64::from(header.data_size);
+                continue;
+            }
+
+            // only the data up to the requested file has to be present
+            check_data_sizes(input, &headers[..=i])?;
+
+            let mut buffer: Vec<u8> = vec![0; header.data_size as usize];
+            input.seek(SeekFrom::Start(offset))?;
+            input.read_exact(&mut buffer)?;
+
+            if header.method() == PackingMethod::Compressed {
+                buffer = lzss::decompress(&buffer, header.original_size as usize)
+                    .prepend_error(format!("Failed to decompress {}:", header.filename))?;
+            }
+
+            return Ok(buffer);
+        }
+
+        Err(file_not_found(headers.iter().filter(|h| !h.is_garbage()).map(|h| h.filename.as_str()), name))
+    }
+
     /// Constructs a PBO from a directory with optional binarization.
     ///
     /// `exclude_patterns` contains glob patterns to exclude from the PBO, `includefolders` contain
     /// paths to search for absolute includes and should generally include the current working
     /// directory.
-    pub fn from_directory(directory: PathBuf, mut binarize: bool, exclude_patterns: &[String], includefolders: &[PathBuf]) -> Result<PBO, Error> {
-        let file_list = list_files(&directory)?;
+    ///
+    /// Nothing is binarized or rapified if the directory contains a `$NOBIN$` or `$NOBIN-NOTEST$`
+    /// file. Such a file in a subfolder only applies to the files in that subfolder, which are
+    /// copied as-is. The `-NOTEST` variant is meant for tools testing the binarized files and is
+    /// handled like `$NOBIN$` here. The marker files themselves are packed like any other file.
+    pub fn from_directory(directory: PathBuf, binarize: bool, exclude_patterns: &[String], includefolders: &[PathBuf]) -> Result<PBO, Error> {
+        PBO::from_directory_with_options(directory, binarize, exclude_patterns, includefolders, &PackOptions::default())
+    }
+
+    /// Same as `from_directory`, but with additional options.
+    pub fn from_directory_with_options(directory: PathBuf, binarize: bool, exclude_patterns: &[String], includefolders: &[PathBuf], options: &PackOptions) -> Result<PBO, Error> {
+        PBO::from_directory_timed(directory, binarize, exclude_patterns, includefolders, options, &mut BuildProfile::default())
+    }
+
+    // Same as `from_directory_with_options`, adding the time spent in each phase to `profile`.
+    fn from_directory_timed(directory: PathBuf, mut binarize: bool, exclude_patterns: &[String], includefolders: &[PathBuf], options: &PackOptions, profile: &mut BuildProfile) -> Result<PBO, Error> {
+        let file_list = BuildProfile::time(&mut profile.file_walking, || list_files(&directory, options.symlinks))?;
         let mut files: LinkedHashMap<String, Cursor<Box<[u8]>>> = LinkedHashMap::new();
-        let mut header_extensions: HashMap<String,String> = HashMap::new();
+        let mut header_extensions: LinkedHashMap<String, String> = LinkedHashMap::new();
+        let mut prefix_file: Option<PathBuf> = None;
+        let mut timestamps: HashMap<String, u32> = HashMap::new();
 
         if directory.join("$NOBIN$").exists() || directory.join("$NOBIN-NOTEST$").exists() {
             binarize = false;
         }
 
+        let binarize_available = binarize && binarize::is_available(options.binarize_exe.as_deref());
+
         for path in file_list {
+            let nobin = binarize && in_nobin_folder(&directory, &path);
+            let is_binarizable = !nobin && options.classifier.classify(&path) == FileAction::Binarize;
+            let action = if binarize && !nobin { options.classifier.classify(&path) } else { FileAction::Copy };
+
             let mut relative = path.strip_prefix(&directory).unwrap().to_path_buf();
-            if binarize && relative.file_name() == Some(OsStr::new("config.cpp")) {
+            if action == FileAction::Rapify && relative.file_name() == Some(OsStr::new("config.cpp")) {
                 relative = relative.with_file_name("config.bin");
             }
 
             let mut name: String = relative.to_str().unwrap().replace("/", "\\");
-            let is_binarizable = Regex::new(".(rtm|p3d)$").unwrap().is_match(&name);
 
             if !file_allowed(&name, &exclude_patterns) { continue; }
 
+            if let Some(stem) = name.strip_suffix(".p3do") {
+                name = format!("{}.p3d", stem);
+            }
+
             let mut file = File::open(&path)?;
 
+            let modified = file.metadata()?.modified()?;
+            let timestamp = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(0);
+            timestamps.insert(name.clone(), timestamp);
+
             if name == "$PBOPREFIX$" {
+                prefix_file = Some(path.clone());
+
                 let mut content = String::new();
                 file.read_to_string(&mut content)?;
-                for l in content.lines() {
-                    if l.is_empty() { break; }
-
-                    let eq: Vec<String> = l.split('=').map(|s| s.to_string()).collect();
-                    if eq.len() == 1 {
-                        header_extensions.insert("prefix".to_string(), l.to_string());
-                    } else {
-                        header_extensions.insert(eq[0].clone(), eq[1].clone());
-                    }
+                for (key, value) in parse_prefix_file(&content) {
+                    insert_header_extension(&mut header_extensions, key, value, Some(&path));
+                }
+            } else if action == FileAction::Rapify {
+                let (config, (preprocessing, parsing)) = Config::read_timed(&mut file, Some(path.clone()), includefolders, &PreprocessOptions::default())
+                    .prepend_error("Failed to parse config:")?;
+                profile.preprocessing += preprocessing;
+                profile.rapifying += parsing;
+
+                let cursor = BuildProfile::time(&mut profile.rapifying, || config.to_cursor())?;
+
+                if options.validate_configs {
+                    validate_rapified(&cursor).prepend_error(format!("Failed to validate rapified {:?}:", relative))?;
                 }
-            } else if binarize && vec!["cpp", "rvmat"].contains(&path.extension().unwrap_or_else(|| OsStr::new("")).to_str().unwrap()) {
-                let config = Config::read(&mut file, Some(path.clone()), includefolders).prepend_error("Failed to parse config:")?;
-                let cursor = config.to_cursor()?;
 
                 files.insert(name, cursor);
-            } else if cfg!(windows) && binarize && is_binarizable {
-                let cursor = binarize::binarize(&path).prepend_error(format!("Failed to binarize {:?}:", relative).to_string())?;
+            } else if binarize_available && action == FileAction::Binarize {
+                let cursor = BuildProfile::time(&mut profile.binarizing, || binarize::binarize_with_options(&path, options.binarize_exe.as_deref(), includefolders))
+                    .prepend_error(format!("Failed to binarize {:?}:", relative).to_string())?;
 
                 files.insert(name, cursor);
             } else {
-                if is_binarizable && !cfg!(windows) {
-                    warning("On non-Windows systems binarize.exe cannot be used; file will be copied as-is.", Some("non-windows-binarization"), (Some(&relative.to_str().unwrap()), None));
+                if is_binarizable && !cfg!(windows) && !binarize_available {
+                    warning("On non-Windows systems binarize.exe can only be used through Wine with BINARIZE_EXE set; file will be copied as-is.", Some("non-windows-binarization"), (Some(&relative.to_str().unwrap()), None));
                 }
 
                 let mut buffer: Vec<u8> = Vec::new();
                 file.read_to_end(&mut buffer)?;
 
-                name = Regex::new(".p3do$").unwrap().replace_all(&name, ".p3d").to_string();
-
                 files.insert(name, Cursor::new(buffer.into_boxed_slice()));
             }
         }
 
-        if header_extensions.get("prefix").is_none() {
+        let prefix_from_folder_name = header_extensions.get("prefix").map(|p| p.trim().is_empty()).unwrap_or(true);
+        if prefix_from_folder_name {
             let prefix: String = directory.file_name().unwrap().to_str().unwrap().to_string();
+
+            if let Some(path) = prefix_file {
+                warning(format!("Prefix file is empty, using folder name \"{}\" as prefix.", prefix), Some("empty-prefix"), (Some(path.to_str().unwrap().to_string()), None));
+            }
+
             header_extensions.insert("prefix".to_string(), prefix);
         }
 
+        let headers: Vec<PBOHeader> = files.iter().map(|(name, cursor)| PBOHeader {
+            filename: name.clone(),
+            packing_method: 0,
+            original_size: cursor.get_ref().len() as u32,
+            reserved: 0,
+            timestamp: timestamps[name],
+            data_size: cursor.get_ref().len() as u32,
+        }).collect();
+
         Ok(PBO {
             files,
             header_extensions,
-            headers: Vec::new(),
+            headers,
+            compress: false,
+            uncompressed_extensions: PRECOMPRESSED_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+            file_order: FileOrder::default(),
             checksum: None,
+            prefix_from_folder_name,
         })
     }
 
     /// Writes PBO to output.
     pub fn write<O: Write>(&self, output: &mut O) -> Result<(), Error> {
-        let mut headers: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        let (headers, data) = self.layout()?;
 
-        let ext_header = PBOHeader {
-            filename: "".to_string(),
-            packing_method: 0x5665_7273,
-            original_size: 0,
-            reserved: 0,
-            timestamp: 0,
-            data_size: 0,
-        };
-        ext_header.write(&mut headers)?;
+        let mut output = BufWriter::with_capacity(WRITE_BUFFER_SIZE, output);
 
-        if let Some(prefix) = self.header_extensions.get("prefix") {
-            headers.write_all(b"prefix\0")?;
-            headers.write_cstring(prefix)?;
+        output.write_all(&headers)?;
+        for file in &data {
+            output.write_all(file)?;
         }
 
-        for (key, value) in self.header_extensions.iter() {
-            if key == "prefix" { continue; }
+        output.write_all(&[0])?;
+        output.write_all(&checksum(&headers, &data))?;
+        output.flush()?;
 
-            headers.write_cstring(key)?;
-            headers.write_cstring(value)?;
-        }
-        headers.write_cstring("".to_string())?;
+        Ok(())
+    }
 
+    /// Returns the SHA1 checksum that `write` appends to the PBO, without writing it.
+    ///
+    /// Unlike the `checksum` field, this is always calculated from the current files.
+    pub fn checksum(&self) -> Vec<u8> {
+        // writing to memory can't fail
+        let (headers, data) = self.layout().unwrap();
+
+        checksum(&headers, &data)
+    }
+
+    // The header block and the data of every file, possibly compressed, as they are written.
+    fn layout(&self) -> Result<Layout<'_>, Error> {
         let mut files_sorted: Vec<(String,&Cursor<Box<[u8]>>)> = self.files.iter().map(|(a,b)| (a.clone(),b)).collect();
-        files_sorted.sort_by(|a, b| a.0.to_lowercase().cmp(&b.0.to_lowercase()));
+        if self.file_order == FileOrder::Lexical {
+            files_sorted.sort_by(|a, b| a.0.to_lowercase().cmp(&b.0.to_lowercase()));
+        }
+
+        let timestamps: HashMap<&str, u32> = self.headers.iter().map(|h| (h.filename.as_str(), h.timestamp)).collect();
 
+        let mut compressed: HashMap<String, Vec<u8>> = HashMap::new();
         for (name, cursor) in &files_sorted {
-            let header = PBOHeader {
+            if self.should_compress(name) {
+                let data = lzss::compress(cursor.get_ref());
+
+                // Store incompressible files as-is instead of growing them
+                if data.len() < cursor.get_ref().len() {
+                    compressed.insert(name.clone(), data);
+                }
+            }
+        }
+
+        let headers: Vec<PBOHeader> = files_sorted.iter().map(|(name, cursor)| {
+            let (packing_method, data_size) = match compressed.get(name) {
+                Some(data) => (PackingMethod::Compressed.to_u32(), data.len() as u32),
+                None => (0, cursor.get_ref().len() as u32)
+            };
+
+            PBOHeader {
                 filename: name.clone(),
-                packing_method: 0,
+                packing_method,
                 original_size: cursor.get_ref().len() as u32,
                 reserved: 0,
-                timestamp: 0,
-                data_size: cursor.get_ref().len() as u32,
-            };
+                timestamp: timestamps.get(name.as_str()).cloned().unwrap_or(0),
+                data_size,
+            }
+        }).collect();
+
+        let data = files_sorted.iter().map(|(name, cursor)| match compressed.remove(name) {
+            Some(data) => Cow::Owned(data),
+            None => Cow::Borrowed(&cursor.get_ref()[..])
+        }).collect();
+
+        Ok((header_block(&self.header_extensions, &headers)?, data))
+    }
 
-            header.write(&mut headers)?;
+    /// Returns the file with the given name, ignoring case and treating `/` and `\` alike.
+    ///
+    /// Use `files` directly for exact lookups.
+    pub fn get_file(&self, name: &str) -> Option<&Cursor<Box<[u8]>>> {
+        self.files.get(self.stored_name(name)?)
+    }
+
+    /// Returns the name a file is stored under, looked up like `get_file`.
+    pub fn stored_name<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        if self.files.contains_key(name) {
+            return Some(name);
         }
 
-        let header = PBOHeader {
-            packing_method: 0,
-            ..ext_header
+        let name = normalize_path(name);
+        self.files.keys().find(|n| normalize_path(n) == name).map(|n| n.as_str())
+    }
+
+    /// Returns the content of the file with the given name as text, looked up like `get_file`.
+    ///
+    /// The content is decoded as UTF-8, replacing invalid sequences, and a leading BOM is removed.
+    pub fn get_text(&self, name: &str) -> Option<Cow<'_, str>> {
+        let bytes: &[u8] = self.get_file(name)?.get_ref();
+        let bytes = if bytes.starts_with(&[0xef, 0xbb, 0xbf]) { &bytes[3..] } else { bytes };
+
+        Some(String::from_utf8_lossy(bytes))
+    }
+
+    /// Returns the config entry at the given path from the PBO's `config.bin`.
+    ///
+    /// Returns `None` if the PBO has no `config.bin`, it can't be read or there is no such entry.
+    pub fn get_config_value(&self, class_path: &[&str]) -> Option<ConfigEntry> {
+        let cursor = self.get_file("config.bin")?;
+        let config = Config::read_rapified(&mut Cursor::new(cursor.get_ref())).ok()?;
+
+        config.get(class_path).cloned()
+    }
+
+    /// Adds a file with the given name and content to the PBO, replacing any existing file with
+    /// the same name. Names are compared like in `get_file`, the file is stored under the given
+    /// name.
+    pub fn add_file(&mut self, name: String, data: Vec<u8>) {
+        self.remove_file(&name);
+        self.files.insert(name, Cursor::new(data.into_boxed_slice()));
+    }
+
+    /// Removes the file with the given name from the PBO, returning whether it existed. Names are
+    /// compared like in `get_file`.
+    pub fn remove_file(&mut self, name: &str) -> bool {
+        let stored = match self.stored_name(name) {
+            Some(stored) => stored.to_string(),
+            None => { return false; }
         };
-        header.write(&mut headers)?;
 
-        let mut h = Hasher::new(MessageDigest::sha1()).unwrap();
+        self.headers.retain(|h| h.filename != stored);
+        self.files.remove(&stored).is_some()
+    }
 
-        output.write_all(headers.get_ref())?;
-        h.update(headers.get_ref()).unwrap();
+    /// Sets the header extension `key` to `value`, e.g. `prefix`.
+    ///
+    /// New extensions are written after the existing ones, changing the value of an existing
+    /// extension keeps its position.
+    pub fn set_header_extension(&mut self, key: &str, value: &str) {
+        match self.header_extensions.get_mut(key) {
+            Some(existing) => { *existing = value.to_string(); },
+            None => { self.header_extensions.insert(key.to_string(), value.to_string()); }
+        }
+    }
 
-        for (_, cursor) in &files_sorted {
-            output.write_all(cursor.get_ref())?;
-            h.update(cursor.get_ref()).unwrap();
+    /// Removes everything from the PBO that depends on the machine or time it was built on, so
+    /// building the same files with the same options gives byte-identical output:
+    ///
+    /// - all file timestamps are set to 0
+    /// - the header extensions are sorted by key (`prefix` is always written first)
+    /// - `/` in file names and the prefix is replaced with `\`
+    /// - the files are written in lexical order
+    ///
+    /// The `armake` header extension still contains the version that built the PBO.
+    pub fn make_reproducible(&mut self) {
+        let files: Vec<(String, Cursor<Box<[u8]>>)> = self.files.drain().collect();
+        self.files = files.into_iter().map(|(name, cursor)| (name.replace("/", "\\"), cursor)).collect();
+
+        for header in &mut self.headers {
+            header.filename = header.filename.replace("/", "\\");
+            header.timestamp = 0;
         }
 
-        output.write_all(&[0])?;
-        output.write_all(&*h.finish().unwrap())?;
+        let mut header_extensions: Vec<(String, String)> = self.header_extensions.drain().collect();
+        header_extensions.sort();
+        self.header_extensions = header_extensions.into_iter()
+            .map(|(key, value)| if key == "prefix" { (key, value.replace("/", "\\")) } else { (key, value) })
+            .collect();
 
-        Ok(())
+        self.file_order = FileOrder::Lexical;
+    }
+
+    fn should_compress(&self, name: &str) -> bool {
+        if !self.compress { return false; }
+
+        !has_extension(name, &self.uncompressed_extensions)
     }
 
     /// Returns the PBO as a `Cursor`.
@@ -294,13 +1100,82 @@ impl PBO {
     }
 }
 
-fn list_files(directory: &PathBuf) -> Result<Vec<PathBuf>, Error> {
+fn normalize_path(name: &str) -> String {
+    name.replace("/", "\\").to_lowercase()
+}
+
+// Error for a missing file, suggesting similar names.
+fn file_not_found<'a, N: Iterator<Item = &'a str>>(names: N, name: &str) -> Error {
+    let suggestions = similar_names(names, name);
+    if suggestions.is_empty() {
+        error!("File \"{}\" not found in PBO.", name)
+    } else {
+        error!("File \"{}\" not found in PBO. Did you mean: {}?", name, suggestions.join(", "))
+    }
+}
+
+// Up to three of the names that are closest to the given one, ignoring case, for typos.
+fn similar_names<'a, N: Iterator<Item = &'a str>>(names: N, name: &str) -> Vec<&'a str> {
+    let normalized = normalize_path(name);
+    let file_name = normalized.rsplit('\\').next().unwrap();
+    let max_distance = (normalized.chars().count() / 4).max(2);
+
+    let mut similar: Vec<(usize, &str)> = names
+        .filter_map(|n| {
+            let candidate = normalize_path(n);
+            let distance = edit_distance(&candidate, &normalized);
+
+            if distance <= max_distance || candidate.rsplit('\\').next().unwrap() == file_name {
+                Some((distance, n))
+            } else {
+                None
+            }
+        })
+        .collect();
+    similar.sort();
+
+    similar.into_iter().take(3).map(|(_, n)| n).collect()
+}
+
+// Levenshtein distance between the two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+fn has_extension(name: &str, extensions: &[String]) -> bool {
+    let extension = name.rsplit('.').next().unwrap_or("").to_lowercase();
+    extensions.iter().any(|e| e.trim_start_matches('.').to_lowercase() == extension)
+}
+
+fn list_files(directory: &PathBuf, symlinks: SymlinkMode) -> Result<Vec<PathBuf>, Error> {
     let mut files: Vec<PathBuf> = Vec::new();
 
     for entry in read_dir(directory)? {
-        let path = entry?.path();
+        let entry = entry?;
+        let path = entry.path();
+
+        if entry.file_type()?.is_symlink() {
+            match symlinks {
+                SymlinkMode::Follow => {},
+                SymlinkMode::Skip => { continue; },
+                SymlinkMode::Error => { return Err(error!("{:?} is a symbolic link.", path)); }
+            }
+        }
+
         if path.is_dir() {
-            for f in list_files(&path)? {
+            for f in list_files(&path, symlinks)? {
                 files.push(f);
             }
         } else {
@@ -311,91 +1186,546 @@ fn list_files(directory: &PathBuf) -> Result<Vec<PathBuf>, Error> {
     Ok(files)
 }
 
-pub fn cmd_inspect<I: Read>(input: &mut I) -> Result<(), Error> {
+// Whether a file or folder in the directory, or the directory itself, was modified after the
+// given time. Folders are included since deleting a file only changes its folder.
+fn modified_after(directory: &PathBuf, symlinks: SymlinkMode, threshold: SystemTime) -> Result<bool, Error> {
+    if directory.metadata()?.modified()? > threshold {
+        return Ok(true);
+    }
+
+    for entry in read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        if entry.file_type()?.is_symlink() {
+            match symlinks {
+                SymlinkMode::Follow => {},
+                SymlinkMode::Skip => { continue; },
+                SymlinkMode::Error => { return Err(error!("{:?} is a symbolic link.", path)); }
+            }
+        }
+
+        let changed = if path.is_dir() {
+            modified_after(&path, symlinks, threshold)?
+        } else {
+            path.metadata()?.modified()? > threshold
+        };
+
+        if changed {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+// Packed size as a percentage of the original size.
+fn format_ratio(data_size: u64, original_size: u64) -> String {
+    if original_size == 0 {
+        return "-".to_string();
+    }
+
+    format!("{}%", data_size * 100 / original_size)
+}
+
+/// Reads a PBO and writes a list of its header extensions and files to output.
+///
+/// If `plain` is set, only the files are listed with one tab-separated line per file containing
+/// the path, packing method, original size and data size.
+pub fn cmd_inspect<I: Read, O: Write>(input: &mut I, output: &mut O, plain: bool) -> Result<(), Error> {
     let pbo = PBO::read(input).prepend_error("Failed to read PBO:")?;
 
+    if plain {
+        for header in pbo.headers {
+            writeln!(output, "{}\t{}\t{}\t{}", header.filename, header.packing_method, header.original_size, header.data_size)?;
+        }
+
+        return Ok(());
+    }
+
     if !pbo.header_extensions.is_empty() {
-        println!("Header extensions:");
+        writeln!(output, "Header extensions:")?;
         for (key, value) in pbo.header_extensions.iter() {
-            println!("- {}={}", key, value);
+            writeln!(output, "- {}={}", key, value)?;
         }
-        println!();
+        writeln!(output)?;
     }
 
-    println!("# Files: {}\n", pbo.files.len());
+    writeln!(output, "# Files: {}\n", pbo.files.len())?;
 
-    println!("Path                                                  Method  Original    Packed");
-    println!("                                                                  Size      Size");
-    println!("================================================================================");
-    for header in pbo.headers {
-        println!("{:50} {:9} {:9} {:9}", header.filename, header.packing_method, header.original_size, header.data_size);
+    writeln!(output, "Path                                                  Method  Original    Packed  Ratio")?;
+    writeln!(output, "                                                                  Size      Size")?;
+    writeln!(output, "=======================================================================================")?;
+    for header in &pbo.headers {
+        // only compressed entries get a ratio, so they stand out
+        let ratio = if header.data_size < header.original_size { format_ratio(header.data_size.into(), header.original_size.into()) } else { String::new() };
+        writeln!(output, "{:50} {:>9} {:9} {:9} {:>6}", header.filename, header.method(), header.original_size, header.data_size, ratio)?;
     }
 
+    let original_size: u64 = pbo.headers.iter().map(|h| u64::from(h.original_size)).sum();
+    let data_size: u64 = pbo.headers.iter().map(|h| u64::from(h.data_size)).sum();
+    writeln!(output, "=======================================================================================")?;
+    writeln!(output, "{:50} {:>9} {:9} {:9} {:>6}", "Total", "", original_size, data_size, format_ratio(data_size, original_size))?;
+
     Ok(())
 }
 
-pub fn cmd_cat<I: Read, O: Write>(input: &mut I, output: &mut O, name: &str) -> Result<(), Error> {
+/// Same as `cmd_inspect`, but writes the header extensions and all fields of the file headers,
+/// including timestamps and reserved values, as JSON.
+///
+/// Each file also has a `method` with the name of its packing method, e.g. `lzss`. Keys are
+/// sorted, so the output is stable.
+pub fn cmd_inspect_json<I: Read, O: Write>(input: &mut I, output: &mut O) -> Result<(), Error> {
     let pbo = PBO::read(input).prepend_error("Failed to read PBO:")?;
 
-    match pbo.files.get(name) {
-        Some(cursor) => {
-            output.write_all(cursor.get_ref()).prepend_error("Failed to write output:")?;
-        },
-        None => {
-            eprintln!("not found"); // @todo
+    let header_extensions: serde_json::Map<String, serde_json::Value> = pbo.header_extensions.iter()
+        .map(|(k, v)| (k.clone(), serde_json::Value::String(v.clone())))
+        .collect();
+
+    let files = pbo.headers.iter().map(|header| {
+        let mut file = serde_json::to_value(header).map_err(|e| error!("{}", e))?;
+        file["method"] = serde_json::Value::String(header.method().to_string());
+        Ok(file)
+    }).collect::<Result<Vec<serde_json::Value>, Error>>()?;
+
+    let result = serde_json::json!({
+        "header_extensions": header_extensions,
+        "files": files,
+    });
+
+    serde_json::to_writer_pretty(&mut *output, &result).map_err(|e| error!("{}", e))?;
+    output.write_all(b"\n")?;
+
+    Ok(())
+}
+
+/// Reads the named file from the PBO in input and writes it to output.
+///
+/// `name` may contain `*` wildcards, in which case all matching files are written in
+/// alphabetical order, separated by `separator`. Names are matched like in `PBO::get_file`.
+pub fn cmd_cat<I: Read + Seek, O: Write>(input: &mut I, output: &mut O, name: &str, separator: &str) -> Result<(), Error> {
+    if !name.contains('*') {
+        let data = PBO::extract_file(input, name).prepend_error("Failed to read PBO:")?;
+
+        output.write_all(&data).prepend_error("Failed to write output:")?;
+
+        return Ok(());
+    }
+
+    let pbo = PBO::read(input).prepend_error("Failed to read PBO:")?;
+
+    for (i, (_, data)) in matching_files(&pbo, name)?.iter().enumerate() {
+        if i > 0 {
+            output.write_all(separator.as_bytes()).prepend_error("Failed to write output:")?;
         }
+        output.write_all(data).prepend_error("Failed to write output:")?;
     }
 
     Ok(())
 }
 
-pub fn cmd_unpack<I: Read>(input: &mut I, output: PathBuf) -> Result<(), Error> {
+/// Reads all files matching the glob pattern from the PBO in input and writes them to output in
+/// alphabetical order, each preceded by a `==> name <==` line.
+///
+/// Files are separated by an empty line, like `head` and `tail` do for multiple files.
+pub fn cmd_cat_glob<I: Read, O: Write>(input: &mut I, output: &mut O, pattern: &str) -> Result<(), Error> {
+    let pbo = PBO::read(input).prepend_error("Failed to read PBO:")?;
+
+    for (i, (name, data)) in matching_files(&pbo, pattern)?.iter().enumerate() {
+        if i > 0 {
+            output.write_all(b"\n").prepend_error("Failed to write output:")?;
+        }
+        writeln!(output, "==> {} <==", name).prepend_error("Failed to write output:")?;
+        output.write_all(data).prepend_error("Failed to write output:")?;
+        if !data.is_empty() && !data.ends_with(b"\n") {
+            output.write_all(b"\n").prepend_error("Failed to write output:")?;
+        }
+    }
+
+    Ok(())
+}
+
+// Files of the PBO matching the glob pattern, sorted by lowercased name
+fn matching_files<'a>(pbo: &'a PBO, pattern: &str) -> Result<Vec<(&'a str, &'a [u8])>, Error> {
+    let normalized = normalize_path(pattern);
+    let mut matches: Vec<(&str, &[u8])> = pbo.files.iter()
+        .filter(|(n, _)| matches_glob(&normalize_path(n), &normalized))
+        .map(|(n, cursor)| (n.as_str(), &cursor.get_ref()[..]))
+        .collect();
+    matches.sort_by_key(|(n, _)| n.to_lowercase());
+
+    if matches.is_empty() {
+        return Err(error!("No file matching \"{}\" found in PBO.", pattern));
+    }
+
+    Ok(matches)
+}
+
+/// Returns a `mod.cpp` skeleton with the entries that can be derived from the given header
+/// extensions, e.g. `name` from `name` or `product`.
+pub fn mod_cpp(header_extensions: &LinkedHashMap<String, String>) -> Config {
+    let mut class = ConfigClass::new("");
+
+    for (entry, keys) in MOD_CPP_ENTRIES {
+        if let Some(value) = keys.iter().filter_map(|k| header_extensions.get(*k)).next() {
+            class.add_entry(entry, ConfigEntry::StringEntry(value.clone()));
+        }
+    }
+
+    Config::new(class)
+}
+
+/// Reads the header extensions of a PBO and writes a `mod.cpp` skeleton derived from them to
+/// output.
+pub fn cmd_modcpp<I: Read, O: Write>(input: &mut I, output: &mut O) -> Result<(), Error> {
+    let (header_extensions, _) = read_header_table(input).prepend_error("Failed to read PBO:")?;
+
+    mod_cpp(&header_extensions).write(output).prepend_error("Failed to write output:")
+}
+
+/// Reads a PBO and checks it against the given expectations.
+///
+/// If `expected_prefix` is given, the prefix header extension has to match it. Prefixes are
+/// compared case-insensitively, ignoring leading and trailing backslashes.
+pub fn cmd_check<I: Read>(input: &mut I, expected_prefix: Option<&str>) -> Result<(), Error> {
     let pbo = PBO::read(input).prepend_error("Failed to read PBO:")?;
 
+    if let Some(expected) = expected_prefix {
+        let prefix = match pbo.header_extensions.get("prefix") {
+            Some(prefix) => prefix,
+            None => { return Err(error!("PBO has no prefix, expected \"{}\".", expected)); }
+        };
+
+        if normalize_path(prefix).trim_matches('\\') != normalize_path(expected).trim_matches('\\') {
+            return Err(error!("PBO prefix \"{}\" doesn't match the expected prefix \"{}\".", prefix, expected));
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a PBO and writes a copy without the files with any of the given extensions to output.
+pub fn cmd_strip<I: Read, O: Write>(input: &mut I, output: &mut O, extensions: &[String]) -> Result<(), Error> {
+    let mut pbo = PBO::read(input).prepend_error("Failed to read PBO:")?;
+
+    let stripped: Vec<String> = pbo.files.keys().filter(|name| has_extension(name, extensions)).cloned().collect();
+    for name in stripped {
+        pbo.remove_file(&name);
+    }
+
+    pbo.write(output).prepend_error("Failed to write PBO:")
+}
+
+/// Reads a PBO and writes a copy with files replaced, added or removed to output.
+///
+/// `replacements` are given as `name=path` and replace the file `name` with the file at `path`, or
+/// add it if there is no such file. `deletions` are names of files to remove. Names are matched
+/// like in `PBO::get_file`. The header extensions and the data of all other files are copied
+/// as-is, including their compression; only the checksum is calculated anew.
+pub fn cmd_update<I: Read, O: Write>(input: &mut I, output: &mut O, replacements: &[String], deletions: &[String]) -> Result<(), Error> {
+    let (header_extensions, headers) = read_header_table(input).prepend_error("Failed to read PBO:")?;
+
+    let mut entries: Vec<(PBOHeader, Vec<u8>)> = Vec::with_capacity(headers.len());
+    for header in headers {
+        if header.is_garbage() {
+            skip_data(input, &header)?;
+            continue;
+        }
+
+        let mut data: Vec<u8> = Vec::new();
+        input.by_ref().take(u64::from(header.data_size)).read_to_end(&mut data)?;
+        if data.len() < header.data_size as usize {
+            return Err(error!("Unexpected end of PBO while reading {}", header.filename));
+        }
+
+        entries.push((header, data));
+    }
+
+    for name in deletions {
+        let normalized = normalize_path(name);
+        match entries.iter().position(|(h, _)| normalize_path(&h.filename) == normalized) {
+            Some(i) => { entries.remove(i); },
+            None => { return Err(file_not_found(entries.iter().map(|(h, _)| h.filename.as_str()), name)); }
+        }
+    }
+
+    for replacement in replacements {
+        let (name, path) = match replacement.find('=') {
+            Some(i) => (&replacement[..i], Path::new(&replacement[i + 1..])),
+            None => { return Err(error!("Invalid replacement \"{}\", expected \"name=path\".", replacement)); }
+        };
+
+        let mut file = open_file(path)?;
+        let mut data: Vec<u8> = Vec::new();
+        file.read_to_end(&mut data).prepend_error(format!("Failed to read {:?}:", path))?;
+
+        let modified = file.metadata()?.modified()?;
+        let timestamp = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(0);
+
+        let normalized = normalize_path(name);
+        let existing = entries.iter().position(|(h, _)| normalize_path(&h.filename) == normalized);
+
+        let header = PBOHeader {
+            filename: existing.map(|i| entries[i].0.filename.clone()).unwrap_or_else(|| name.replace("/", "\\")),
+            packing_method: 0,
+            original_size: data.len() as u32,
+            reserved: 0,
+            timestamp,
+            data_size: data.len() as u32,
+        };
+
+        match existing {
+            Some(i) => { entries[i] = (header, data); },
+            None => { entries.push((header, data)); }
+        }
+    }
+
+    let (headers, data): (Vec<PBOHeader>, Vec<Vec<u8>>) = entries.into_iter().unzip();
+    let data: Vec<Cow<[u8]>> = data.into_iter().map(Cow::Owned).collect();
+    let block = header_block(&header_extensions, &headers)?;
+
+    output.write_all(&block).prepend_error("Failed to write PBO:")?;
+    for file in &data {
+        output.write_all(file).prepend_error("Failed to write PBO:")?;
+    }
+    output.write_all(&[0]).prepend_error("Failed to write PBO:")?;
+    output.write_all(&checksum(&block, &data)).prepend_error("Failed to write PBO:")?;
+
+    Ok(())
+}
+
+/// Unpacks the PBO read from input into the output folder.
+///
+/// Files are copied straight from input to disk one at a time instead of reading the whole PBO
+/// into memory first.
+///
+/// Files matching any of the `excludes` glob patterns are skipped. The `$PBOPREFIX$` with the
+/// header extensions is always written.
+pub fn cmd_unpack<I: Read>(input: &mut I, output: PathBuf, excludes: &[String]) -> Result<(), Error> {
+    let (header_extensions, headers) = read_header_table(input).prepend_error("Failed to read PBO:")?;
+
     create_dir_all(&output).prepend_error("Failed to create output folder:")?;
 
-    if !pbo.header_extensions.is_empty() {
+    if !header_extensions.is_empty() {
         let prefix_path = output.join(PathBuf::from("$PBOPREFIX$"));
         let mut prefix_file = File::create(prefix_path).prepend_error("Failed to create prefix file:")?;
 
-        for (key, value) in pbo.header_extensions.iter() {
+        for (key, value) in header_extensions.iter() {
             prefix_file.write_all(format!("{}={}\n", key, value).as_bytes()).prepend_error("Failed to write prefix file:")?;
         }
     }
 
-    for (file_name, cursor) in pbo.files.iter() {
+    for header in headers {
+        if header.is_garbage() {
+            skip_data(input, &header)?;
+            continue;
+        }
+
+        if !file_allowed(&header.filename, excludes) {
+            let skipped = copy(&mut input.by_ref().take(u64::from(header.data_size)), &mut sink())?;
+            if skipped < u64::from(header.data_size) {
+                return Err(error!("Unexpected end of PBO while reading {}", header.filename));
+            }
+            continue;
+        }
+
         // @todo: windows
-        let path = output.join(PathBuf::from(file_name.replace("\\", pathsep())));
+        let path = output.join(PathBuf::from(header.filename.replace("\\", pathsep())));
         create_dir_all(path.parent().unwrap()).prepend_error("Failed to create output folder:")?;
-        let mut file = File::create(path).prepend_error("Failed to open output file:")?;
-        file.write_all(cursor.get_ref()).prepend_error("Failed to write output file:")?;
+        let mut file = BufWriter::with_capacity(WRITE_BUFFER_SIZE, File::create(path).prepend_error("Failed to open output file:")?);
+
+        let mut data = input.by_ref().take(u64::from(header.data_size));
+
+        if header.method() == PackingMethod::Compressed {
+            let mut buffer: Vec<u8> = Vec::new();
+            data.read_to_end(&mut buffer)?;
+            if buffer.len() < header.data_size as usize {
+                return Err(error!("Unexpected end of PBO while reading {}", header.filename));
+            }
+
+            let buffer = lzss::decompress(&buffer, header.original_size as usize)
+                .prepend_error(format!("Failed to decompress {}:", header.filename))?;
+            file.write_all(&buffer).prepend_error("Failed to write output file:")?;
+        } else {
+            let copied = copy(&mut data, &mut file).prepend_error("Failed to write output file:")?;
+            if copied < u64::from(header.data_size) {
+                return Err(error!("Unexpected end of PBO while reading {}", header.filename));
+            }
+        }
+
+        file.flush().prepend_error("Failed to write output file:")?;
     }
 
     Ok(())
 }
 
-pub fn cmd_pack<O: Write>(input: PathBuf, output: &mut O, headerext: &[String], excludes: &[String]) -> Result<(), Error> {
-    let mut pbo = PBO::from_directory(input, false, excludes, &Vec::new())?;
+/// Packs the given folder into a PBO without binarization and writes it to output. Returns the
+/// time spent in each phase.
+pub fn cmd_pack<O: Write>(input: PathBuf, output: &mut O, headerext: &[String], excludes: &[String], options: &PackOptions) -> Result<BuildProfile, Error> {
+    let start = Instant::now();
+    let headerext = parse_header_extensions(headerext)?;
+    let mut profile = BuildProfile::default();
+    let mut pbo = PBO::from_directory_timed(input.clone(), false, excludes, &Vec::new(), options, &mut profile)?;
+    if options.require_prefix {
+        check_explicit_prefix(&pbo, &input, &headerext)?;
+    }
 
-    for h in headerext {
-        let (key, value) = (h.split('=').nth(0).unwrap(), h.split('=').nth(1).unwrap());
-        pbo.header_extensions.insert(key.to_string(), value.to_string());
+    add_header_extensions(&mut pbo, headerext, options);
+
+    write_packed(&mut pbo, output, options, start, &mut profile)?;
+    Ok(profile)
+}
+
+/// Builds a PBO from the given folder, binarizing files where possible, and writes it to output.
+/// Returns the time spent in each phase.
+pub fn cmd_build<O: Write>(input: PathBuf, output: &mut O, headerext: &[String], excludes: &[String], includefolders: &[PathBuf], options: &PackOptions) -> Result<BuildProfile, Error> {
+    let start = Instant::now();
+    let headerext = parse_header_extensions(headerext)?;
+    let mut profile = BuildProfile::default();
+    let mut pbo = PBO::from_directory_timed(input.clone(), true, excludes, includefolders, options, &mut profile)?;
+    if options.require_prefix {
+        check_explicit_prefix(&pbo, &input, &headerext)?;
+    }
+
+    add_header_extensions(&mut pbo, headerext, options);
+
+    write_packed(&mut pbo, output, options, start, &mut profile)?;
+    Ok(profile)
+}
+
+/// Builds each folder in `input` into a PBO named after the folder in `output_folder`.
+///
+/// Folders with an existing PBO are skipped if none of their files or subfolders were modified
+/// after `since`, or after the PBO was written if `since` is `None`. Folders with names that
+/// aren't valid UTF-8 are skipped with a warning. Returns the paths of the PBOs that were
+/// built and the time spent in each phase, added up over all builds.
+pub fn cmd_build_all(input: PathBuf, output_folder: PathBuf, headerext: &[String], excludes: &[String], includefolders: &[PathBuf], options: &PackOptions, since: Option<SystemTime>) -> Result<(Vec<PathBuf>, BuildProfile), Error> {
+    let mut addons: Vec<PathBuf> = Vec::new();
+    for entry in read_dir(&input)? {
+        let path = entry?.path();
+        if !path.is_dir() { continue; }
+
+        match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) if name.starts_with('.') => {},
+            Some(_) => { addons.push(path); },
+            None => {
+                warning(format!("Skipping {:?}, folder names have to be valid UTF-8.", path), Some("invalid-folder-name"), (None, None));
+            }
+        }
+    }
+    addons.sort();
+
+    create_dir_all(&output_folder)?;
+
+    let mut built: Vec<PathBuf> = Vec::new();
+    let mut profile = BuildProfile::default();
+    for addon in addons {
+        let target = output_folder.join(format!("{}.pbo", addon.file_name().unwrap().to_str().unwrap()));
+
+        if target.exists() {
+            let threshold = match since {
+                Some(since) => since,
+                None => target.metadata()?.modified()?
+            };
+
+            if !modified_after(&addon, options.symlinks, threshold)? { continue; }
+        }
+
+        write_atomically(&target, false, |output| {
+            profile.add(&cmd_build(addon.clone(), output, headerext, excludes, includefolders, options)?);
+            Ok(())
+        }).prepend_error(format!("Failed to build {:?}:", addon))?;
+        built.push(target);
     }
 
-    pbo.write(output).prepend_error("Failed to write PBO:")?;
+    Ok((built, profile))
+}
+
+// Reads a rapified config back and rapifies it again, which has to give the same bytes
+fn validate_rapified(cursor: &Cursor<Box<[u8]>>) -> Result<(), Error> {
+    let config = Config::read_rapified(&mut Cursor::new(cursor.get_ref())).prepend_error("Failed to read it back:")?;
+    let rewritten = config.to_cursor()?;
+
+    if rewritten.get_ref() != cursor.get_ref() {
+        return Err(error!("Reading it back and rapifying it again gives a different result."));
+    }
 
     Ok(())
 }
 
-pub fn cmd_build<O: Write>(input: PathBuf, output: &mut O, headerext: &[String], excludes: &[String], includefolders: &[PathBuf]) -> Result<(), Error> {
-    let mut pbo = PBO::from_directory(input, true, excludes, includefolders)?;
+// Whether a folder between the file and the root directory contains a $NOBIN$ marker
+fn in_nobin_folder(directory: &Path, path: &Path) -> bool {
+    path.ancestors()
+        .skip(1)
+        .take_while(|folder| *folder != directory)
+        .any(|folder| folder.join("$NOBIN$").exists() || folder.join("$NOBIN-NOTEST$").exists())
+}
+
+// Header extensions in a $PBOPREFIX$ file, lines without a key set the prefix
+fn parse_prefix_file(content: &str) -> Vec<(&str, &str)> {
+    content.lines()
+        .take_while(|l| !l.is_empty())
+        // only the first = separates key and value, values may contain more
+        .map(|l| l.split_once('=').unwrap_or(("prefix", l)))
+        .collect()
+}
+
+// Fails unless the prefix is set in the folder's $PBOPREFIX$ or given as header extension
+fn check_explicit_prefix(pbo: &PBO, directory: &PathBuf, headerext: &LinkedHashMap<String, String>) -> Result<(), Error> {
+    match headerext.get("prefix") {
+        Some(prefix) if !prefix.trim().is_empty() => Ok(()),
+        Some(_) => Err(error!("The prefix is empty.")),
+        None if pbo.prefix_from_folder_name => Err(error!("No prefix set, add a $PBOPREFIX$ file to {:?}.", directory)),
+        None => Ok(())
+    }
+}
+
+// Header extensions given as "key=value", split at the first "="
+fn parse_header_extensions(headerext: &[String]) -> Result<LinkedHashMap<String, String>, Error> {
+    let mut given: LinkedHashMap<String, String> = LinkedHashMap::new();
 
     for h in headerext {
-        let (key, value) = (h.split('=').nth(0).unwrap(), h.split('=').nth(1).unwrap());
-        pbo.header_extensions.insert(key.to_string(), value.to_string());
+        match h.find('=') {
+            Some(i) if i > 0 => insert_header_extension(&mut given, &h[..i], &h[i + 1..], None),
+            _ => { return Err(error!("Invalid header extension \"{}\", expected \"key=value\".", h)); }
+        }
     }
 
-    pbo.write(output).prepend_error("Failed to write PBO:")?;
+    Ok(given)
+}
+
+fn add_header_extensions(pbo: &mut PBO, given: LinkedHashMap<String, String>, options: &PackOptions) {
+    pbo.header_extensions.extend(given);
+
+    if !options.omit_version_extension && !pbo.header_extensions.contains_key(VERSION_EXTENSION) {
+        pbo.header_extensions.insert(VERSION_EXTENSION.to_string(), env!("CARGO_PKG_VERSION").to_string());
+    }
+}
+
+fn insert_header_extension(header_extensions: &mut LinkedHashMap<String, String>, key: &str, value: &str, origin: Option<&PathBuf>) {
+    if let Some(previous) = header_extensions.get(key) {
+        warning(format!("Header extension \"{}\" is specified more than once, \"{}\" overrides \"{}\".", key, value, previous),
+            Some("duplicate-header-extension"),
+            (origin.map(|p| p.to_str().unwrap().to_string()), None));
+    }
+
+    header_extensions.insert(key.to_string(), value.to_string());
+}
+
+fn write_packed<O: Write>(pbo: &mut PBO, output: &mut O, options: &PackOptions, start: Instant, profile: &mut BuildProfile) -> Result<(), Error> {
+    pbo.compress = options.compress;
+    if !options.uncompressed.is_empty() {
+        pbo.uncompressed_extensions = options.uncompressed.clone();
+    }
+    if options.reproducible {
+        pbo.make_reproducible();
+    }
+
+    BuildProfile::time(&mut profile.writing, || pbo.write(output)).prepend_error("Failed to write PBO:")?;
+
+    if let Some(ref report_path) = options.report {
+        BuildReport::new(pbo, options.target.clone(), start.elapsed()).write(report_path)?;
+    }
 
     Ok(())
 }
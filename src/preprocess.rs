@@ -1,14 +1,20 @@
 //! Functions for preprocessing Arma configs and scripts
 
 use std::clone::Clone;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env::current_dir;
 use std::fs::{File, read_dir};
 use std::io::{Read, Write, Error};
 use std::iter::{Sum};
 use std::path::{Path, PathBuf, Component};
 
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde_json::{Value, json};
+
 use crate::error::*;
+use crate::io::{Newline, TextEncoding, encode_text, normalize_newlines, read_text};
+use crate::pbo::{matches_glob};
 
 pub mod preprocess_grammar {
     #![allow(missing_docs)]
@@ -21,7 +27,9 @@ pub struct Definition {
     name: String,
     parameters: Option<Vec<String>>,
     value: Vec<Token>,
-    local: bool
+    local: bool,
+    /// Line and file of the `#define`, if known
+    origin: Option<(u32, Option<PathBuf>)>
 }
 
 /// Preprocessor directive
@@ -31,16 +39,24 @@ pub enum Directive {
     IncludeDirective(String),
     /// `#define` directive containing the definition
     DefineDirective(Definition),
+    /// `#pragma once` directive
+    PragmaOnceDirective,
     /// `#undef` directive containing the name of the macro
     UndefDirective(String),
     /// `#ifdef` directive containing the name of the macro
     IfDefDirective(String),
     /// `#ifndef` directive containing the name of the macro
     IfNDefDirective(String),
+    /// `#if` directive containing the condition
+    IfDirective(String),
+    /// `#elif` directive containing the condition
+    ElIfDirective(String),
     /// `#else` directive
     ElseDirective,
     /// `#endif` directive
     EndIfDirective,
+    /// Unrecognized directive containing the whole line
+    UnknownDirective(String),
 }
 
 /// Potential macro invocation
@@ -61,8 +77,8 @@ pub enum Token {
     NewlineToken(String, u32),
     /// Potential macro token
     MacroToken(Macro),
-    /// Comment token containing a number of newlines
-    CommentToken(u32),
+    /// Comment token with the text of the comment, containing a number of newlines
+    CommentToken(String, u32),
     /// Token for the concatenation operator (`##`)
     ConcatToken
 }
@@ -77,14 +93,63 @@ pub enum Line {
 }
 
 /// Struct for additional information about preprocessor output. Contains import stack used for
-/// loop detection and the origins of all the lines in the output.
+/// loop detection, the files marked with `#pragma once` and the origins of all the lines in the
+/// output.
 #[derive(Debug)]
 pub struct PreprocessInfo {
     /// For every line in the output, `line_origins` contains a line number (starting at 1) and a
     /// `PathBuf` to the file where the line was found. The path may be `None` if the line was in the
     /// original input to `preprocess` and `origin` was not given.
     pub line_origins: Vec<(u32, Option<PathBuf>)>,
-    import_stack: Vec<PathBuf>
+    import_stack: Vec<PathBuf>,
+    once_files: HashSet<PathBuf>,
+    counter: u32
+}
+
+/// Options for the preprocessor
+#[derive(Clone, Debug, Default)]
+pub struct PreprocessOptions {
+    /// If not empty, all included files have to be inside one of these directories. Includes
+    /// resolving to paths outside of them (e.g. by using `..`) are refused.
+    pub include_roots: Vec<PathBuf>,
+    /// Skip unknown directives with a warning instead of passing them through as regular lines.
+    pub allow_unknown_directives: bool,
+    /// Glob patterns of include paths that are allowed to be missing. Missing includes matching
+    /// one of these are skipped with a warning.
+    pub optional_includes: Vec<String>,
+    /// Encoding of the input and included files, and of the output of `cmd_preprocess`.
+    pub encoding: TextEncoding,
+    /// Line ending of the output of `cmd_preprocess`. Line endings are left as they are if
+    /// `None`.
+    pub newline: Option<Newline>,
+    /// Keep comments in the output instead of removing them. Comments inside of macro definitions
+    /// are still removed.
+    pub keep_comments: bool,
+    /// Path `cmd_preprocess` and `cmd_rapify` write the line map of the output to, see
+    /// `PreprocessInfo::write_line_map`.
+    pub line_map: Option<PathBuf>,
+    /// Path `cmd_rapify` writes the file and line of every class definition to as JSON, see
+    /// `Config::class_origins`.
+    pub class_map: Option<PathBuf>,
+}
+
+impl PreprocessInfo {
+    /// Writes `line_origins` as a JSON array with an object for every line of the output,
+    /// containing the `file` (`null` if unknown) and `line` the output line originates from.
+    pub fn write_line_map<O: Write>(&self, output: &mut O) -> Result<(), Error> {
+        let map: Vec<Value> = self.line_origins.iter().map(|(line, path)| json!({
+            "file": path.as_ref().map(|p| p.to_string_lossy().to_string()),
+            "line": line,
+        })).collect();
+
+        serde_json::to_writer_pretty(output, &map).map_err(|e| error!("{}", e))
+    }
+
+    /// Writes the line map to the file at `path`, see `write_line_map`.
+    pub fn write_line_map_file(&self, path: &Path) -> Result<(), Error> {
+        let mut file = File::create(path).prepend_error("Failed to create line map file:")?;
+        self.write_line_map(&mut file).prepend_error("Failed to write line map file:")
+    }
 }
 
 fn parse_macro(input: &str) -> Macro {
@@ -113,16 +178,45 @@ impl Clone for Token {
             Token::RegularToken(s) => Token::RegularToken(s.clone()),
             Token::NewlineToken(s, n) => Token::NewlineToken(s.clone(), *n),
             Token::MacroToken(m) => Token::MacroToken(m.clone()),
-            Token::CommentToken(n) => Token::CommentToken(*n),
+            Token::CommentToken(s, n) => Token::CommentToken(s.clone(), *n),
             Token::ConcatToken => Token::ConcatToken,
         }
     }
 }
 
 impl Definition {
-    fn value(&self, arguments: &Option<Vec<String>>, def_map: &HashMap<String,Definition>, stack: &[Definition]) -> Result<Option<Vec<Token>>, Error> {
-        let params = self.parameters.clone().unwrap_or_default();
-        let args = arguments.clone().unwrap_or_default();
+    fn builtin(name: &str, value: String) -> Definition {
+        Definition {
+            name: name.to_string(),
+            parameters: None,
+            value: vec![Token::RegularToken(value)],
+            local: false,
+            origin: None
+        }
+    }
+
+    // whether both have the same parameters and value, ignoring comments and the amount of
+    // whitespace
+    fn same_as(&self, other: &Definition) -> bool {
+        let normalize = |tokens: &[Token]| Token::concat(tokens).0.split_whitespace().collect::<Vec<&str>>().join(" ");
+        self.parameters == other.parameters && normalize(&self.value) == normalize(&other.value)
+    }
+
+    fn value(&self, arguments: &Option<Vec<String>>, def_map: &HashMap<String,Definition>, stack: &[Definition], counter: &mut u32) -> Result<Option<Vec<Token>>, Error> {
+        let mut params = self.parameters.clone().unwrap_or_default();
+        let mut args = arguments.clone().unwrap_or_default();
+
+        // the arguments for a trailing "..." are joined into __VA_ARGS__, there may be none
+        if params.last().map(|p| p == "...").unwrap_or(false) {
+            if arguments.is_none() || args.len() < params.len() - 1 {
+                return Ok(None);
+            }
+
+            let variadic = args.split_off(params.len() - 1).join(",");
+            params.pop();
+            params.push("__VA_ARGS__".to_string());
+            args.push(variadic);
+        }
 
         if params.len() != args.len() {
             return Ok(None);
@@ -148,19 +242,20 @@ impl Definition {
             for (param, arg) in params.iter().zip(args.iter()) {
                 let mut tokens = preprocess_grammar::tokens(&arg).expect("Failed to parse macro argument");
                 let stack: Vec<Definition> = Vec::new();
-                tokens = Macro::resolve_all(&tokens, &def_map, &stack).expect("Failed to resolve macro arguments");
+                tokens = Macro::resolve_all(&tokens, &def_map, &stack, counter).expect("Failed to resolve macro arguments");
 
                 local_map.insert(param.clone(), Definition {
                     name: param.clone(),
                     parameters: None,
                     value: tokens,
-                    local: true
+                    local: true,
+                    origin: None
                 });
             }
 
-            tokens = Macro::resolve_all(&tokens, &local_map, &stack_new)?;
+            tokens = Macro::resolve_all(&tokens, &local_map, &stack_new, counter)?;
         } else {
-            tokens = Macro::resolve_all(&tokens, &def_map, &stack_new)?;
+            tokens = Macro::resolve_all(&tokens, &def_map, &stack_new, counter)?;
         }
 
         Ok(Some(tokens))
@@ -168,7 +263,7 @@ impl Definition {
 }
 
 impl Macro {
-    fn resolve_pseudoargs(&self, def_map: &HashMap<String, Definition>, stack: &[Definition]) -> Result<Vec<Token>, Error> {
+    fn resolve_pseudoargs(&self, def_map: &HashMap<String, Definition>, stack: &[Definition], counter: &mut u32) -> Result<Vec<Token>, Error> {
         let mut tokens: Vec<Token> = Vec::new();
         tokens.push(Token::RegularToken(self.name.clone()));
 
@@ -179,7 +274,7 @@ impl Macro {
         let (_, without_name) = self.original.split_at(self.name.len());
         let mut arg_tokens = preprocess_grammar::tokens(&without_name).expect("Failed to parse macro arguments.");
 
-        arg_tokens = Macro::resolve_all(&arg_tokens, &def_map, &stack)?;
+        arg_tokens = Macro::resolve_all(&arg_tokens, &def_map, &stack, counter)?;
         for t in arg_tokens {
             tokens.push(t);
         }
@@ -187,10 +282,17 @@ impl Macro {
         Ok(tokens)
     }
 
-    fn resolve(&self, def_map: &HashMap<String, Definition>, stack: &[Definition]) -> Result<Vec<Token>, Error> {
+    fn resolve(&self, def_map: &HashMap<String, Definition>, stack: &[Definition], counter: &mut u32) -> Result<Vec<Token>, Error> {
+        // built-in, counting up for every use
+        if self.name == "__COUNTER__" && self.arguments.is_none() {
+            let value = *counter;
+            *counter += 1;
+            return Ok(vec![Token::RegularToken(value.to_string())]);
+        }
+
         match def_map.get(&self.name) {
             Some(def) => {
-                let value = def.value(&self.arguments, def_map, stack)?;
+                let value = def.value(&self.arguments, def_map, stack, counter)?;
 
                 if !def.local && self.quoted {
                     // @todo: complain
@@ -206,20 +308,20 @@ impl Macro {
                         Ok(tokens)
                     }
                 } else {
-                    self.resolve_pseudoargs(def_map, stack)
+                    self.resolve_pseudoargs(def_map, stack, counter)
                 }
             },
-            None => self.resolve_pseudoargs(def_map, stack)
+            None => self.resolve_pseudoargs(def_map, stack, counter)
         }
     }
 
-    fn resolve_all(tokens: &[Token], def_map: &HashMap<String, Definition>, stack: &[Definition]) -> Result<Vec<Token>, Error> {
+    fn resolve_all(tokens: &[Token], def_map: &HashMap<String, Definition>, stack: &[Definition], counter: &mut u32) -> Result<Vec<Token>, Error> {
         let mut result: Vec<Token> = Vec::new();
 
         for token in tokens {
             match token {
                 Token::MacroToken(ref m) => {
-                    let resolved = m.resolve(def_map, stack)?;
+                    let resolved = m.resolve(def_map, stack, counter)?;
                     for t in resolved {
                         result.push(t);
                     }
@@ -236,6 +338,10 @@ impl Macro {
 
 impl Token {
     fn concat(tokens: &[Token]) -> (String, u32) {
+        Token::concat_comments(tokens, false)
+    }
+
+    fn concat_comments(tokens: &[Token], keep_comments: bool) -> (String, u32) {
         let mut output = String::new();
         let mut newlines = 0;
 
@@ -251,7 +357,10 @@ impl Token {
                 Token::MacroToken(m) => {
                     output += &m.original;
                 },
-                Token::CommentToken(n) => {
+                Token::CommentToken(s, n) => {
+                    if keep_comments {
+                        output += s;
+                    }
                     newlines += n;
                 },
                 _ => {}
@@ -266,7 +375,7 @@ fn read_prefix(prefix_path: &Path) -> String {
     let mut content = String::new();
     File::open(prefix_path).unwrap().read_to_string(&mut content).unwrap();
 
-    content.lines().nth(0).unwrap().to_string()
+    content.lines().nth(0).unwrap_or("").trim().to_string()
 }
 
 /// Returns the path seperator used on the current operating system
@@ -287,6 +396,11 @@ fn matches_include_path(path: &PathBuf, include_path: &str) -> bool {
 
         let mut prefix = read_prefix(&prefixpath);
 
+        if prefix.is_empty() {
+            prefix = parent.file_name().unwrap_or_default().to_str().unwrap().to_string();
+            warning(format!("Prefix file is empty, using folder name \"{}\" as prefix.", prefix), Some("empty-prefix"), (Some(prefixpath.to_str().unwrap().to_string()), None));
+        }
+
         prefix = if !prefix.is_empty() && prefix.chars().nth(0).unwrap() != '\\' {
             format!("\\{}", prefix)
         } else {
@@ -332,6 +446,16 @@ fn search_directory(include_path: &str, directory: PathBuf) -> Option<PathBuf> {
     None
 }
 
+/// Finds the file with the given absolute (prefixed) path, e.g. `x\foo\addons\bar\data\tex.paa`, in
+/// the search paths like absolute includes are. The leading backslash is optional.
+pub(crate) fn find_absolute_path(path: &str, search_paths: &[PathBuf]) -> Option<PathBuf> {
+    let path = if path.starts_with('\\') { path.to_string() } else { format!("\\{}", path) };
+
+    search_paths.iter()
+        .filter_map(|search_path| search_path.canonicalize().ok())
+        .find_map(|search_path| search_directory(&path, search_path))
+}
+
 fn canonicalize(path: PathBuf) -> PathBuf {
     let mut result = PathBuf::new();
     for component in path.components() {
@@ -383,14 +507,226 @@ fn find_include_file(include_path: &str, origin: Option<&PathBuf>, search_paths:
     }
 }
 
-fn preprocess_rec(input: String, origin: Option<PathBuf>, definition_map: &mut HashMap<String, Definition>, info: &mut PreprocessInfo, includefolders: &[PathBuf]) -> Result<String, Error> {
+fn check_include_roots(include_path: &str, file_path: &Path, include_roots: &[PathBuf]) -> Result<(), Error> {
+    if include_roots.is_empty() {
+        return Ok(());
+    }
+
+    let absolute = file_path.canonicalize()?;
+    for root in include_roots {
+        if absolute.starts_with(root.canonicalize()?) {
+            return Ok(());
+        }
+    }
+
+    Err(error!("Included file \"{}\" resolves to \"{}\", which is outside of the include roots.", include_path, absolute.to_str().unwrap()))
+}
+
+/// Removes line continuations (a backslash at the end of a line, optionally followed by
+/// whitespace) from text.
+fn remove_continuations(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(index) = rest.find('\\') {
+        let after = rest[index + 1..].trim_start_matches([' ', '\t']);
+        if let Some(next_line) = after.strip_prefix('\n') {
+            result += &rest[..index];
+            rest = next_line;
+        } else {
+            result += &rest[..=index];
+            rest = &rest[index + 1..];
+        }
+    }
+
+    result += rest;
+    result
+}
+
+// binary operators of `#if` conditions, from lowest to highest precedence
+const CONDITION_OPERATORS: [&[&str]; 10] = [
+    &["||"], &["&&"], &["|"], &["^"], &["&"], &["==", "!="], &["<=", ">=", "<", ">"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]
+];
+
+struct Condition {
+    tokens: Vec<String>,
+    position: usize
+}
+
+impl Condition {
+    fn parse(expression: &str) -> Result<Condition, Error> {
+        let chars: Vec<char> = expression.chars().collect();
+        let mut tokens: Vec<String> = Vec::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            let c = chars[i];
+            if c.is_whitespace() {
+                i += 1;
+            } else if c.is_alphanumeric() || c == '_' {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+
+                if c.is_ascii_digit() {
+                    let digits = word.trim_end_matches(['u', 'U', 'l', 'L']);
+                    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+                        i64::from_str_radix(hex, 16)
+                    } else if digits.len() > 1 && digits.starts_with('0') {
+                        i64::from_str_radix(&digits[1..], 8)
+                    } else {
+                        digits.parse()
+                    };
+                    tokens.push(value.map_err(|_| error!("Invalid number \"{}\"", word))?.to_string());
+                } else {
+                    // identifiers left after macro expansion are undefined
+                    tokens.push("0".to_string());
+                }
+            } else {
+                let pair: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+                if ["||", "&&", "==", "!=", "<=", ">=", "<<", ">>"].contains(&pair.as_str()) {
+                    tokens.push(pair);
+                    i += 2;
+                } else if "|^&<>+-*/%!~()".contains(c) {
+                    tokens.push(c.to_string());
+                    i += 1;
+                } else {
+                    return Err(error!("Unexpected \"{}\"", c));
+                }
+            }
+        }
+
+        Ok(Condition { tokens, position: 0 })
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(|t| t.as_str())
+    }
+
+    fn next(&mut self) -> Result<String, Error> {
+        let token = self.tokens.get(self.position).cloned().ok_or_else(|| error!("Unexpected end of condition"))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn evaluate(&mut self) -> Result<i64, Error> {
+        let value = self.binary(0)?;
+        match self.peek() {
+            Some(token) => Err(error!("Unexpected \"{}\"", token)),
+            None => Ok(value)
+        }
+    }
+
+    fn binary(&mut self, precedence: usize) -> Result<i64, Error> {
+        if precedence == CONDITION_OPERATORS.len() {
+            return self.unary();
+        }
+
+        let mut left = self.binary(precedence + 1)?;
+        while let Some(operator) = self.peek().filter(|t| CONDITION_OPERATORS[precedence].contains(t)).map(|t| t.to_string()) {
+            self.position += 1;
+            let right = self.binary(precedence + 1)?;
+
+            left = match operator.as_str() {
+                "||" => (left != 0 || right != 0) as i64,
+                "&&" => (left != 0 && right != 0) as i64,
+                "|" => left | right,
+                "^" => left ^ right,
+                "&" => left & right,
+                "==" => (left == right) as i64,
+                "!=" => (left != right) as i64,
+                "<=" => (left <= right) as i64,
+                ">=" => (left >= right) as i64,
+                "<" => (left < right) as i64,
+                ">" => (left > right) as i64,
+                "<<" => left.wrapping_shl(right as u32),
+                ">>" => left.wrapping_shr(right as u32),
+                "+" => left.wrapping_add(right),
+                "-" => left.wrapping_sub(right),
+                "*" => left.wrapping_mul(right),
+                _ if right == 0 => { return Err(error!("Division by zero")); },
+                "/" => left.wrapping_div(right),
+                _ => left.wrapping_rem(right)
+            };
+        }
+
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<i64, Error> {
+        let token = self.next()?;
+        match token.as_str() {
+            "!" => Ok((self.unary()? == 0) as i64),
+            "~" => Ok(!self.unary()?),
+            "-" => Ok(self.unary()?.wrapping_neg()),
+            "+" => self.unary(),
+            "(" => {
+                let value = self.binary(0)?;
+                if self.next()? != ")" {
+                    return Err(error!("Missing \")\""));
+                }
+                Ok(value)
+            },
+            _ => token.parse().map_err(|_| error!("Unexpected \"{}\"", token))
+        }
+    }
+}
+
+/// Evaluates the condition of an `#if` or `#elif` directive. `defined(X)` is replaced before the
+/// macros are expanded, identifiers that remain afterwards evaluate to 0.
+fn evaluate_condition(condition: &str, definition_map: &HashMap<String, Definition>, counter: &mut u32) -> Result<bool, Error> {
+    lazy_static! {
+        static ref DEFINED: Regex = Regex::new(r"\bdefined\s*(?:\(\s*(\w+)\s*\)|(\w+))").unwrap();
+    }
+
+    let condition = remove_continuations(condition);
+    let replaced = DEFINED.replace_all(&condition, |captures: &Captures| {
+        let name = captures.get(1).or_else(|| captures.get(2)).unwrap().as_str();
+        if definition_map.contains_key(name) { "1" } else { "0" }
+    });
+
+    let tokens = preprocess_grammar::tokens(&replaced).map_err(|e| error!("{}", e))?;
+    let stack: Vec<Definition> = Vec::new();
+    let resolved = Macro::resolve_all(&tokens, definition_map, &stack, counter)?;
+    let (expanded, _) = Token::concat(&resolved);
+
+    let value = Condition::parse(&expanded).and_then(|mut c| c.evaluate())
+        .prepend_error(format!("Invalid condition \"{}\":", condition.trim()))?;
+    Ok(value != 0)
+}
+
+fn preprocess_rec(input: String, origin: Option<PathBuf>, definition_map: &mut HashMap<String, Definition>, info: &mut PreprocessInfo, includefolders: &[PathBuf], options: &PreprocessOptions) -> Result<String, Error> {
     let lines = preprocess_grammar::file(&input).format_error(&origin, &input)?;
     let mut output = String::from("");
-    let mut original_lineno = 1;
+    let mut original_lineno = 0;
     let mut level = 0;
     let mut level_true = 0;
+    // whether a branch of the conditional at each level was already taken, for #elif and #else
+    let mut branch_taken: Vec<bool> = Vec::new();
+    // directive and line that opened the conditional at each level
+    let mut conditional_starts: Vec<(&str, u32)> = Vec::new();
+
+    let location = |line: u32| match origin {
+        Some(ref path) => format!("line {} of \"{}\"", line, path.to_str().unwrap()),
+        None => format!("line {}", line)
+    };
 
     for line in lines {
+        // counted before the line, since skipped lines are left with `continue`
+        original_lineno += 1;
+
+        // without the option, unknown directives are regular lines like before they were
+        // recognized
+        let line = match line {
+            Line::DirectiveLine(Directive::UnknownDirective(directive), newlines) if !options.allow_unknown_directives => {
+                original_lineno += newlines;
+                Line::TokenLine(preprocess_grammar::tokens(&directive).map_err(|e| error!("{}", e))?)
+            },
+            line => line
+        };
+
         match line {
             Line::DirectiveLine(dir, newlines) => {
                 original_lineno += newlines;
@@ -399,93 +735,191 @@ fn preprocess_rec(input: String, origin: Option<PathBuf>, definition_map: &mut H
                     Directive::IncludeDirective(path) => {
                         if level > level_true { continue; }
 
-                        //let import_tree = &mut info.import_tree;
-                        //let includer = import_tree.get(&path);
-                        //if let Some(path) = includer {
-                        //    // @todo: complain
-                        //}
+                        let file_path = match find_include_file(&path, origin.as_ref(), includefolders) {
+                            Ok(file_path) => Some(file_path),
+                            Err(_) if options.optional_includes.iter().any(|p| matches_glob(&path, p)) => {
+                                warning(format!("Optional include \"{}\" not found, skipping.", path), Some("missing-optional-include"), (origin.as_ref().map(|p| p.to_str().unwrap().to_string()), Some(original_lineno)));
+                                None
+                            },
+                            Err(e) => { return Err(e); }
+                        };
 
-                        let file_path = find_include_file(&path, origin.as_ref(), includefolders)?;
+                        if let Some(file_path) = file_path {
+                            check_include_roots(&path, &file_path, &options.include_roots)?;
 
-                        info.import_stack.push(file_path.clone());
+                            let absolute = file_path.canonicalize()?;
+                            if info.once_files.contains(&absolute) { continue; }
 
-                        let mut content = String::new();
-                        File::open(&file_path)?.read_to_string(&mut content)?;
-                        let result = preprocess_rec(content, Some(file_path), definition_map, info, includefolders).prepend_error(format!("Failed to preprocess include \"{}\":", path))?;
+                            if info.import_stack.contains(&absolute) {
+                                let cycle: Vec<String> = info.import_stack.iter()
+                                    .skip_while(|p| **p != absolute)
+                                    .chain(Some(&absolute))
+                                    .map(|p| p.to_str().unwrap().to_string())
+                                    .collect();
+                                return Err(error!("Include cycle detected: {}", cycle.join(" -> ")));
+                            }
 
-                        info.import_stack.pop();
+                            info.import_stack.push(absolute);
 
-                        output += &result;
-                    },
-                    Directive::DefineDirective(def) => {
-                        original_lineno += u32::sum(def.value.iter().map(|t| match t {
-                            Token::NewlineToken(_s, n) => *n,
-                            Token::CommentToken(n) => *n,
-                            _ => 0
-                        }));
+                            let content = read_text(&mut File::open(&file_path)?, options.encoding)?;
+                            let result = preprocess_rec(content, Some(file_path), definition_map, info, includefolders, options).prepend_error(format!("Failed to preprocess include \"{}\":", path))?;
+
+                            info.import_stack.pop();
 
+                            output += &result;
+                        }
+                    },
+                    Directive::DefineDirective(mut def) => {
                         if level > level_true { continue; }
 
-                        if definition_map.remove(&def.name).is_some() {
-                            // @todo: warn about redefine
+                        // only keep comments where they were written, not where the macro is used
+                        if options.keep_comments {
+                            def.value.retain(|t| !matches!(t, Token::CommentToken(..)));
+                        }
+
+                        def.origin = Some((original_lineno - newlines, origin.clone()));
+
+                        if let Some(previous) = definition_map.remove(&def.name) {
+                            if !previous.same_as(&def) {
+                                let previous_origin = match previous.origin {
+                                    Some((line, Some(ref path))) => format!(", previously defined in {}:{}", path.to_str().unwrap(), line),
+                                    Some((line, None)) => format!(", previously defined in line {}", line),
+                                    None => String::new()
+                                };
+                                warning(format!("Macro \"{}\" redefined{}.", def.name, previous_origin), Some("macro-redefinition"), (origin.as_ref().map(|p| p.to_str().unwrap().to_string()), Some(original_lineno - newlines)));
+                            }
                         }
 
                         definition_map.insert(def.name.clone(), def);
                     }
+                    Directive::PragmaOnceDirective => {
+                        if level > level_true { continue; }
+
+                        // the import stack is empty for input without a path
+                        if let Some(current) = info.import_stack.last() {
+                            info.once_files.insert(current.clone());
+                        }
+                    }
                     Directive::UndefDirective(name) => {
                         if level > level_true { continue; }
 
                         definition_map.remove(&name);
                     }
                     Directive::IfDefDirective(name) => {
-                        level_true += if level_true == level && definition_map.contains_key(&name) { 1 } else { 0 };
+                        let taken = level_true == level && definition_map.contains_key(&name);
+                        level_true += if taken { 1 } else { 0 };
                         level += 1;
+                        branch_taken.push(taken);
+                        conditional_starts.push(("#ifdef", original_lineno - newlines));
                     }
                     Directive::IfNDefDirective(name) => {
-                        level_true += if level_true == level && !definition_map.contains_key(&name) { 1 } else { 0 };
+                        let taken = level_true == level && !definition_map.contains_key(&name);
+                        level_true += if taken { 1 } else { 0 };
+                        level += 1;
+                        branch_taken.push(taken);
+                        conditional_starts.push(("#ifndef", original_lineno - newlines));
+                    }
+                    Directive::IfDirective(condition) => {
+                        // conditions in skipped blocks are not evaluated
+                        let taken = level_true == level && evaluate_condition(&condition, definition_map, &mut info.counter)
+                            .prepend_error(format!("Failed to evaluate #if in line {}:", original_lineno))?;
+                        level_true += if taken { 1 } else { 0 };
                         level += 1;
+                        branch_taken.push(taken);
+                        conditional_starts.push(("#if", original_lineno - newlines));
+                    }
+                    Directive::ElIfDirective(condition) => {
+                        if level == 0 {
+                            return Err(error!("#elif without #if in {}.", location(original_lineno - newlines)));
+                        }
+
+                        if level_true == level {
+                            level_true -= 1;
+                        } else if level_true + 1 == level && !branch_taken[level - 1] && evaluate_condition(&condition, definition_map, &mut info.counter)
+                                .prepend_error(format!("Failed to evaluate #elif in line {}:", original_lineno))? {
+                            level_true = level;
+                            branch_taken[level - 1] = true;
+                        }
                     }
                     Directive::ElseDirective => {
-                        if level_true + 1 == level {
+                        if level == 0 {
+                            return Err(error!("#else without #if in {}.", location(original_lineno - newlines)));
+                        }
+
+                        if level_true + 1 == level && !branch_taken[level - 1] {
                             level_true = level;
                         } else if level_true == level {
                             level_true -= 1;
                         }
                     }
                     Directive::EndIfDirective => {
-                        assert!(level > 0);
+                        if level == 0 {
+                            return Err(error!("#endif without #if in {}.", location(original_lineno - newlines)));
+                        }
+
+                        branch_taken.pop();
+                        conditional_starts.pop();
                         level -= 1;
                         if level_true > level {
                             level_true -= 1;
                         }
                     }
+                    Directive::UnknownDirective(directive) => {
+                        if level > level_true { continue; }
+
+                        warning(format!("Skipping unknown preprocessor directive: {}", directive), Some("unknown-directive"), (origin.as_ref().map(|p| p.to_str().unwrap().to_string()), Some(original_lineno)));
+                    }
                 }
             },
             Line::TokenLine(tokens) => {
+                // line continuations in this line, not counting ones from expanded macros
+                let continuations: u32 = u32::sum(tokens.iter().map(|t| match t {
+                    Token::RegularToken(s) => s.matches('\n').count() as u32,
+                    Token::MacroToken(m) => m.original.matches('\n').count() as u32,
+                    _ => 0
+                }));
+
+                // built-in macros, always for the file containing the line, even if they are used
+                // in a macro defined elsewhere
+                let file = origin.as_ref().map(|p| p.to_string_lossy().to_string()).unwrap_or_default();
+                definition_map.insert("__LINE__".to_string(), Definition::builtin("__LINE__", original_lineno.to_string()));
+                definition_map.insert("__FILE__".to_string(), Definition::builtin("__FILE__", format!("\"{}\"", file)));
+
+                // newlines in comments and strings of this line, again ignoring expanded macros
+                let start_lineno = original_lineno;
+                original_lineno += Token::concat(&tokens).1;
+
+                // lines in skipped blocks are not resolved, so macros in them can't fail the build
+                // and __COUNTER__ only counts lines that are used
+                if level > level_true {
+                    original_lineno += continuations;
+                    continue;
+                }
+
                 let stack: Vec<Definition> = Vec::new();
-                let resolved = Macro::resolve_all(&tokens, &definition_map, &stack).prepend_error("Failed to resolve macros:")?;
+                let resolved = Macro::resolve_all(&tokens, &definition_map, &stack, &mut info.counter).prepend_error("Failed to resolve macros:")?;
 
-                let (mut result, newlines) = Token::concat(&resolved);
+                let (mut result, _) = Token::concat_comments(&resolved, options.keep_comments);
                 result = result.replace("\r\n", "\n");
-                original_lineno += newlines;
-
-                let before = result.len();
-                result = result.replace("\\\n", "");
-
-                if level > level_true { continue; }
+                result = remove_continuations(&result);
 
                 output += &result;
                 output += "\n";
 
-                info.line_origins.push((original_lineno, origin.clone()));
-                original_lineno += (before - result.len()) as u32 / 2;
+                // kept comments may span several lines of the output, counted back from the last
+                // line but not past the first one
+                let comment_lines = result.matches('\n').count() as u32;
+                for i in 0..=comment_lines {
+                    let lineno = original_lineno.saturating_sub(comment_lines - i).max(start_lineno);
+                    info.line_origins.push((lineno, origin.clone()));
+                }
+                original_lineno += continuations;
             }
         }
-        original_lineno += 1;
+    }
 
-        if level > 0 {
-            // @todo: complain
-        }
+    if let Some((directive, line)) = conditional_starts.last() {
+        return Err(error!("{} in {} is missing an #endif.", directive, location(*line)));
     }
 
     Ok(output)
@@ -513,23 +947,31 @@ fn preprocess_rec(input: String, origin: Option<PathBuf>, definition_map: &mut H
 ///
 /// assert_eq!("foo = \"abc_xyz\";", output.trim());
 /// ```
-pub fn preprocess(mut input: String, origin: Option<PathBuf>, includefolders: &[PathBuf]) -> Result<(String, PreprocessInfo), Error> {
-    if input[..3].as_bytes() == [0xef,0xbb,0xbf] {
-        input = input[3..].to_string();
+pub fn preprocess(input: String, origin: Option<PathBuf>, includefolders: &[PathBuf]) -> Result<(String, PreprocessInfo), Error> {
+    preprocess_with_options(input, origin, includefolders, &PreprocessOptions::default())
+}
+
+/// Same as `preprocess`, but with additional options.
+pub fn preprocess_with_options(mut input: String, origin: Option<PathBuf>, includefolders: &[PathBuf], options: &PreprocessOptions) -> Result<(String, PreprocessInfo), Error> {
+    if let Some(rest) = input.strip_prefix('\u{feff}') {
+        input = rest.to_string();
     }
 
     let mut info = PreprocessInfo {
         line_origins: Vec::new(),
-        import_stack: Vec::new()
+        import_stack: Vec::new(),
+        once_files: HashSet::new(),
+        counter: 0
     };
 
+    // the input might not exist as a file, e.g. when it is read from stdin
     if let Some(ref path) = origin {
-        info.import_stack.push(path.clone());
+        info.import_stack.push(path.canonicalize().unwrap_or_else(|_| path.clone()));
     }
 
     let mut def_map: HashMap<String, Definition> = HashMap::new();
 
-    match preprocess_rec(input, origin, &mut def_map, &mut info, includefolders) {
+    match preprocess_rec(input, origin, &mut def_map, &mut info, includefolders, options) {
         Ok(result) => Ok((result, info)),
         Err(e) => Err(e)
     }
@@ -540,13 +982,20 @@ pub fn preprocess(mut input: String, origin: Option<PathBuf>, includefolders: &[
 /// `path` is the `path` to the input if it is known and is used for relative includes and error
 /// messages. `includefolders` are the folders searched for absolute includes and should usually at
 /// least include the current working directory.
-pub fn cmd_preprocess<I: Read, O: Write>(input: &mut I, output: &mut O, path: Option<PathBuf>, includefolders: &[PathBuf]) -> Result<(), Error> {
-    let mut buffer = String::new();
-    input.read_to_string(&mut buffer).prepend_error("Failed to read input file")?;
+pub fn cmd_preprocess<I: Read, O: Write>(input: &mut I, output: &mut O, path: Option<PathBuf>, includefolders: &[PathBuf], options: &PreprocessOptions) -> Result<(), Error> {
+    let buffer = read_text(input, options.encoding).prepend_error("Failed to read input file")?;
 
-    let (result, _) = preprocess(buffer, path, includefolders)?;
+    let (mut result, info) = preprocess_with_options(buffer, path, includefolders, options)?;
+
+    if let Some(ref line_map) = options.line_map {
+        info.write_line_map_file(line_map)?;
+    }
+
+    if let Some(newline) = options.newline {
+        result = normalize_newlines(&result, newline);
+    }
 
-    output.write_all(result.as_bytes()).prepend_error("Failed to write output")?;
+    output.write_all(&encode_text(&result, options.encoding)).prepend_error("Failed to write output")?;
 
     Ok(())
 }
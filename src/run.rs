@@ -1,14 +1,16 @@
 use std::collections::{HashSet};
 use std::fs::{File};
-use std::io::{Error, Read, Cursor, stdin, stdout};
+use std::io::{Error, Read, Cursor, stderr, stdin, stdout};
 use std::iter::{FromIterator};
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
 
 use crate::*;
 use crate::binarize;
 use crate::config;
 use crate::error::*;
-use crate::io::{Input, Output};
+use crate::io::{Input, Output, TextEncoding, write_atomically};
+use crate::p3d;
 use crate::pbo;
 use crate::preprocess;
 use crate::sign;
@@ -21,18 +23,28 @@ pub const USAGE: &str = "
 armake2
 
 Usage:
-    armake2 rapify [-v] [-f] [-w <wname>]... [-i <includefolder>]... [<source> [<target>]]
-    armake2 preprocess [-v] [-f] [-w <wname>]... [-i <includefolder>]... [<source> [<target>]]
-    armake2 derapify [-v] [-f] [-d <indentation>] [<source> [<target>]]
-    armake2 binarize [-v] [-f] [-w <wname>]... <source> <target>
-    armake2 build [-v] [-f] [-w <wname>]... [-i <includefolder>]... [-x <excludepattern>]... [-e <headerext>]... [-k <privatekey>] [-s <signature>] <sourcefolder> [<target>]
-    armake2 pack [-v] [-f] [-x <excludepattern>]... [-e <headerext>]... [-k <privatekey>] [-s <signature>] <sourcefolder> [<target>]
-    armake2 inspect [-v] [<source>]
-    armake2 unpack [-v] [-f] <source> <targetfolder>
-    armake2 cat [-v] <source> <filename> [<target>]
-    armake2 keygen [-v] [-f] <keyname>
-    armake2 sign [-v] [-f] [--v2] <privatekey> <pbo> [<signature>]
-    armake2 verify [-v] <publickey> <pbo> [<signature>]
+    armake2 rapify [-v] [-f] [-w <wname>]... [-i <includefolder>]... [--include-root <includeroot>]... [--allow-unknown-directives] [--optional-include <includepattern>]... [--dump-preprocessed <dumpfile>] [--emit-line-map <mapfile>] [--emit-class-map <classmapfile>] [--target-version <version>] [--encoding <encoding>] [--output-on-error] [<source> [<target>]]
+    armake2 preprocess [-v] [-f] [-w <wname>]... [-i <includefolder>]... [--include-root <includeroot>]... [--allow-unknown-directives] [--optional-include <includepattern>]... [--encoding <encoding>] [--newline <newline>] [--emit-line-map <mapfile>] [--output-on-error] [<source> [<target>]]
+    armake2 derapify [-v] [-f] [-d <indentation>] [--encoding <encoding>] [--output-on-error] [<source> [<target>]]
+    armake2 tree [-v] [-w <wname>]... [-i <includefolder>]... [--parents] [<source>]
+    armake2 config2json [-v] [-f] [-w <wname>]... [-i <includefolder>]... [--output-on-error] [<source> [<target>]]
+    armake2 binarize [-v] [-f] [-w <wname>]... [-i <includefolder>]... [--binarize-exe <binarizeexe>] <source> <target>
+    armake2 build [-v] [-f] [-w <wname>]... [-i <includefolder>]... [-x <excludepattern>]... [-e <headerext>]... [-z] [-u <extension>]... [--report <reportfile>] [--fail-on-empty-prefix] [--validate-configs] [--build-rules <rulesfile>] [--symlinks <mode>] [--profile] [--no-version-extension] [--reproducible] [--binarize-exe <binarizeexe>] [-k <privatekey>] [-s <signature>] [--output-on-error] <sourcefolder> [<target>]
+    armake2 buildall [-v] [-w <wname>]... [-i <includefolder>]... [-x <excludepattern>]... [-e <headerext>]... [-z] [-u <extension>]... [--since <timestamp>] [--fail-on-empty-prefix] [--validate-configs] [--build-rules <rulesfile>] [--symlinks <mode>] [--profile] [--no-version-extension] [--reproducible] [--binarize-exe <binarizeexe>] <sourcefolder> <targetfolder>
+    armake2 pack [-v] [-f] [-x <excludepattern>]... [-e <headerext>]... [-z] [-u <extension>]... [--report <reportfile>] [--fail-on-empty-prefix] [--symlinks <mode>] [--profile] [--no-version-extension] [--reproducible] [-k <privatekey>] [-s <signature>] [--output-on-error] <sourcefolder> [<target>]
+    armake2 inspect [-v] [--plain | --json] [<source>]
+    armake2 update [-v] [--set <replacement>]... [--delete <deletename>]... [--output-on-error] <source> [<target>]
+    armake2 unpack [-v] [-f] [-x <excludepattern>]... <source> <targetfolder>
+    armake2 strip [-v] [-f] [-r <extension>]... [--output-on-error] [<source> [<target>]]
+    armake2 check [-v] [--expect-prefix <prefix>] [<source>]
+    armake2 modcpp [-v] [-f] [--output-on-error] [<source> [<target>]]
+    armake2 cat [-v] [--separator <separator>] <source> <filename> [<target>]
+    armake2 cat [-v] --glob <pattern> <source> [<target>]
+    armake2 keygen [-v] [-f] [--bits <bits>] [--exponent <exponent>] <keyname>
+    armake2 sign [-v] [-f] [--v2 | --sign-all-versions] [--sha256] <privatekey> <pbo> [<signature>]
+    armake2 verify [-v] [--json] <publickey> <pbo> [<signature>]
+    armake2 verify [-v] --keydir <keydir> <pbo> [<signature>]
+    armake2 p3d-info [-v] [<source> [<target>]]
     armake2 paa2img [-v] [-f] [<source> [<target>]]
     armake2 img2paa [-v] [-f] [-z] [-t <paatype>] [<source> [<target>]]
     armake2 (-h | --help)
@@ -42,31 +54,92 @@ Commands:
     rapify      Preprocess and rapify a config file.
     preprocess  Preprocess a file.
     derapify    Derapify a config.
-    binarize    Binarize a file using BI's binarize.exe (Windows only).
+    tree        Show the class hierarchy of a config.
+    config2json Convert a config to JSON.
+    binarize    Binarize a file using BI's binarize.exe (Windows, or Wine elsewhere).
     build       Build a PBO from a folder.
+    buildall    Build each folder in a folder into a PBO in the target folder, skipping
+                folders without changes since their PBO was built.
     pack        Pack a folder into a PBO without any binarization or rapification.
     inspect     Inspect a PBO and list contained files.
+    update      Replace, add or remove files in a PBO, in place unless a target is given.
     unpack      Unpack a PBO into a folder.
+    strip       Copy a PBO without the files of the given extensions.
+    check       Check a PBO, e.g. for the expected prefix.
+    modcpp      Generate a mod.cpp from a PBO's header extensions.
     cat         Read the named file from the target PBO to stdout.
+                The name may contain * to read all matching files.
     keygen      Generate a keypair with the specified path (extensions are added).
-    sign        Sign a PBO with the given private key.
+    sign        Sign a PBO, or all PBOs in a directory, with the given private key.
     verify      Verify a PBO's signature with the given public key.
+    p3d-info    List the textures and materials used by each LOD of a P3D.
     paa2img     Convert PAA to image (PNG only). (not implemented)
     img2paa     Convert image to PAA. (not implemented)
 
 Options:
     -v --verbose                Enable verbose output.
     -f --force                  Overwrite the target file/folder if it already exists.
+       --output-on-error        Keep partial output if the command fails.
     -w --warning <wname>        Warning to disable
     -i --include <includefolder>    Folder to search for includes, defaults to CWD.
     -x --exclude <excludepattern>   Glob pattern to exclude from PBO.
                                       For unpack: pattern to exclude from output folder.
+       --include-root <includeroot>  Refuse includes resolving to files outside of this folder.
+       --allow-unknown-directives  Skip unknown preprocessor directives with a warning.
+       --optional-include <includepattern>  Glob pattern of includes that may be missing.
+       --encoding <encoding>    Encoding of text input and output: auto, utf8, utf16 or cp1252.
+                                Auto detects the input encoding and writes UTF-8.
+       --newline <newline>      Line endings of the output: lf or crlf.
+       --dump-preprocessed <dumpfile>  Also write the preprocessed config to the given file.
+       --emit-line-map <mapfile>  Write the source file and line of every line of the
+                                preprocessed output to the given file as JSON.
+       --emit-class-map <classmapfile>  Write the source file and line of every class to the
+                                given file as JSON.
+       --target-version <version>  Engine to rapify for: arma1, arma2 or arma3 (default).
+                                Configs using unsupported features are rejected.
     -d --indent <indentation>   String to use for indentation. 4 spaces by default.
+                                \"\\t\" is replaced with a tab.
     -e --headerext <headerext>  Extension to add to PBO header as \"key=value\".
+       --report <reportfile>    Write a JSON summary of the build to the given file.
+       --fail-on-empty-prefix   Fail if no prefix is set instead of using the folder name.
+       --since <timestamp>      For buildall: build folders with files changed after the given
+                                Unix timestamp instead of after their existing PBO.
+       --validate-configs       Read rapified configs back after building to check them.
+       --build-rules <rulesfile>  Config file with arrays of extensions to rapify, binarize
+                                or copy, e.g. rapify[] = {\"myext\"};
+       --symlinks <mode>        Handling of symbolic links when packing: follow (default)
+                                packs their targets, skip leaves them out, error fails.
+       --profile                Print the time spent in each build phase at the end.
+       --no-version-extension   Don't add the \"armake\" header extension with the version.
+       --reproducible           Build byte-identical PBOs from the same files: zero timestamps,
+                                sort header extensions and use backslashes in paths.
+       --binarize-exe <binarizeexe>  binarize.exe to run through Wine when not on Windows.
+                                Defaults to the BINARIZE_EXE environment variable.
+       --set <replacement>      For update: replace or add a file as \"name=path\".
+       --delete <deletename>    For update: remove the file with the given name.
+       --expect-prefix <prefix>  Fail if the PBO's prefix doesn't match the given one.
+       --separator <separator>  String written between files matched by cat. Empty by default.
+                                \"\\t\" and \"\\n\" are replaced with a tab and a newline.
+       --glob <pattern>         Write all files matching the pattern, each preceded by a
+                                \"==> name <==\" line.
+       --parents                Show the full inheritance chain of each class.
+       --plain                  List files as tab-separated lines without any header.
     -k --key <privatekey>       Sign the PBO with the given private key.
     -s --signature <signature>  Signature path to use when signing the PBO.
        --v2                     Generate an older v2 signature.
+       --json                   Print the result as JSON (inspect, verify).
+       --bits <bits>            Key length in bits, a multiple of 16. [default: 1024]
+       --exponent <exponent>    Public exponent of the key. [default: 65537]
+       --sign-all-versions      Generate both a v2 and a v3 signature.
+       --sha256                 Use SHA256 for the hashes. The game doesn't accept these
+                                signatures, they are only for external verification.
+       --keydir <keydir>        Verify against every .bikey in the directory and print the
+                                name of the matching key.
     -z --compress               Compress final PAA where possible.
+                                  For build/pack: compress PBO entries.
+    -u --uncompressed <extension>   Extension of files to store uncompressed when compressing.
+                                      Replaces the default list of already compressed formats.
+    -r --remove <extension>     Extension of files to remove from the PBO.
     -t --type <paatype>         PAA type. DXT1 or DXT5
     -h --help                   Show usage information and exit.
        --version                Print the version number and exit.
@@ -78,19 +151,28 @@ pub struct Args {
     cmd_rapify: bool,
     cmd_preprocess: bool,
     cmd_derapify: bool,
+    cmd_tree: bool,
+    cmd_config2json: bool,
     cmd_binarize: bool,
     cmd_build: bool,
+    cmd_buildall: bool,
     cmd_pack: bool,
     cmd_inspect: bool,
+    cmd_update: bool,
     cmd_unpack: bool,
+    cmd_strip: bool,
+    cmd_check: bool,
+    cmd_modcpp: bool,
     cmd_cat: bool,
     cmd_keygen: bool,
     cmd_sign: bool,
     cmd_verify: bool,
+    cmd_p3d_info: bool,
     cmd_paa2img: bool,
     cmd_img2paa: bool,
     flag_verbose: bool,
     flag_force: bool,
+    flag_output_on_error: bool,
     flag_warning: Vec<String>,
     flag_include: Vec<String>,
     flag_exclude: Vec<String>,
@@ -98,8 +180,42 @@ pub struct Args {
     flag_key: Option<String>,
     flag_signature: Option<String>,
     flag_indent: Option<String>,
+    flag_dump_preprocessed: Option<String>,
+    flag_emit_line_map: Option<String>,
+    flag_emit_class_map: Option<String>,
+    flag_target_version: Option<String>,
+    flag_include_root: Vec<String>,
+    flag_allow_unknown_directives: bool,
+    flag_optional_include: Vec<String>,
+    flag_encoding: Option<String>,
+    flag_newline: Option<String>,
     flag_v2: bool,
+    flag_sign_all_versions: bool,
+    flag_sha256: bool,
+    flag_json: bool,
+    flag_keydir: Option<String>,
     flag_compress: bool,
+    flag_uncompressed: Vec<String>,
+    flag_remove: Vec<String>,
+    flag_report: Option<String>,
+    flag_fail_on_empty_prefix: bool,
+    flag_validate_configs: bool,
+    flag_build_rules: Option<String>,
+    flag_symlinks: Option<String>,
+    flag_profile: bool,
+    flag_no_version_extension: bool,
+    flag_reproducible: bool,
+    flag_binarize_exe: Option<String>,
+    flag_since: Option<String>,
+    flag_bits: String,
+    flag_exponent: String,
+    flag_plain: bool,
+    flag_parents: bool,
+    flag_separator: Option<String>,
+    flag_glob: Option<String>,
+    flag_set: Vec<String>,
+    flag_delete: Vec<String>,
+    flag_expect_prefix: Option<String>,
     flag_type: Option<String>,
     flag_version: bool,
     arg_wname: Vec<String>,
@@ -125,11 +241,11 @@ fn get_input(args: &Args) -> Result<Input, Error> {
     }
 }
 
-fn get_output(args: &Args) -> Result<Output, Error> {
+fn with_output<F: FnOnce(&mut Output) -> Result<(), Error>>(args: &Args, f: F) -> Result<(), Error> {
     if let Some(ref target) = args.arg_target {
-        Ok(Output::File(File::create(target).prepend_error("Failed to open output file:")?))
+        write_atomically(&PathBuf::from(target), args.flag_output_on_error, f)
     } else {
-        Ok(Output::Standard(stdout()))
+        f(&mut Output::Standard(stdout()))
     }
 }
 
@@ -140,15 +256,44 @@ fn run_command(args: &Args) -> Result<(), Error> {
     let mut includefolders: Vec<PathBuf> = args.flag_include.iter().map(PathBuf::from).collect();
     includefolders.push(PathBuf::from("."));
 
-    if args.cmd_binarize {
-        binarize::cmd_binarize(PathBuf::from(args.arg_source.as_ref().unwrap()), PathBuf::from(args.arg_target.as_ref().unwrap()))
+    let encoding: TextEncoding = match args.flag_encoding {
+        Some(ref encoding) => encoding.parse()?,
+        None => TextEncoding::Auto
+    };
+
+    let preprocess_options = preprocess::PreprocessOptions {
+        include_roots: args.flag_include_root.iter().map(PathBuf::from).collect(),
+        allow_unknown_directives: args.flag_allow_unknown_directives,
+        optional_includes: args.flag_optional_include.clone(),
+        encoding,
+        newline: match args.flag_newline {
+            Some(ref newline) => Some(newline.parse()?),
+            None => None
+        },
+        line_map: args.flag_emit_line_map.as_ref().map(PathBuf::from),
+        class_map: args.flag_emit_class_map.as_ref().map(PathBuf::from),
+        ..Default::default()
+    };
+
+    if args.cmd_tree {
+        with_output(&args, |output| config::cmd_tree(&mut get_input(&args)?, output, path, &includefolders, args.flag_parents))
+    } else if args.cmd_config2json {
+        with_output(&args, |output| config::cmd_config2json(&mut get_input(&args)?, output, path, &includefolders))
+    } else if args.cmd_binarize {
+        binarize::cmd_binarize(PathBuf::from(args.arg_source.as_ref().unwrap()), PathBuf::from(args.arg_target.as_ref().unwrap()), args.flag_binarize_exe.as_ref().map(PathBuf::from), &includefolders)
     } else if args.cmd_rapify {
-        config::cmd_rapify(&mut get_input(&args)?, &mut get_output(&args)?, path, &includefolders)
+        let dump_path = args.flag_dump_preprocessed.as_ref().map(PathBuf::from);
+        let target: config::TargetVersion = match args.flag_target_version {
+            Some(ref version) => version.parse()?,
+            None => config::TargetVersion::default()
+        };
+        with_output(&args, |output| config::cmd_rapify(&mut get_input(&args)?, output, path, &includefolders, dump_path, &preprocess_options, target))
     } else if args.cmd_derapify {
-        config::cmd_derapify(&mut get_input(&args)?, &mut get_output(&args)?)
+        let indent = args.flag_indent.as_ref().unwrap().replace("\\t", "\t");
+        with_output(&args, |output| config::cmd_derapify(&mut get_input(&args)?, output, &indent, encoding))
     } else if args.cmd_preprocess {
-        preprocess::cmd_preprocess(&mut get_input(&args)?, &mut get_output(&args)?, path, &includefolders)
-    } else if args.cmd_build || args.cmd_pack {
+        with_output(&args, |output| preprocess::cmd_preprocess(&mut get_input(&args)?, output, path, &includefolders, &preprocess_options))
+    } else if args.cmd_build || args.cmd_buildall || args.cmd_pack {
         let flag_privatekey = args.flag_key.as_ref().map(PathBuf::from);
         let flag_signature = args.flag_signature.as_ref().map(PathBuf::from);
 
@@ -156,28 +301,100 @@ fn run_command(args: &Args) -> Result<(), Error> {
             return Err(error!("Cannot sign a pbo that is piped to stdout."));
         }
 
-        if args.cmd_build {
-            pbo::cmd_build(PathBuf::from(&args.arg_sourcefolder), &mut get_output(&args)?, &args.flag_headerext, &args.flag_exclude, &includefolders)?;
+        let options = pbo::PackOptions {
+            compress: args.flag_compress,
+            uncompressed: args.flag_uncompressed.clone(),
+            report: args.flag_report.as_ref().map(PathBuf::from),
+            target: args.arg_target.as_ref().map(PathBuf::from),
+            require_prefix: args.flag_fail_on_empty_prefix,
+            validate_configs: args.flag_validate_configs,
+            classifier: match args.flag_build_rules {
+                Some(ref path) => pbo::ExtensionClassifier::read(Path::new(path)).prepend_error("Failed to read build rules:")?,
+                None => pbo::ExtensionClassifier::default()
+            },
+            symlinks: match args.flag_symlinks {
+                Some(ref mode) => mode.parse()?,
+                None => pbo::SymlinkMode::default()
+            },
+            omit_version_extension: args.flag_no_version_extension,
+            binarize_exe: args.flag_binarize_exe.as_ref().map(PathBuf::from),
+            reproducible: args.flag_reproducible,
+        };
+
+        let mut profile = pbo::BuildProfile::default();
+        if args.cmd_buildall {
+            let since = match args.flag_since {
+                Some(ref since) => Some(UNIX_EPOCH + Duration::from_secs(since.parse().map_err(|_| error!("Invalid timestamp \"{}\".", since))?)),
+                None => None
+            };
+            let (_, built_profile) = pbo::cmd_build_all(PathBuf::from(&args.arg_sourcefolder), PathBuf::from(&args.arg_targetfolder), &args.flag_headerext, &args.flag_exclude, &includefolders, &options, since)?;
+            profile = built_profile;
+        } else if args.cmd_build {
+            with_output(&args, |output| {
+                profile = pbo::cmd_build(PathBuf::from(&args.arg_sourcefolder), output, &args.flag_headerext, &args.flag_exclude, &includefolders, &options)?;
+                Ok(())
+            })?;
         } else {
-            pbo::cmd_pack(PathBuf::from(&args.arg_sourcefolder), &mut get_output(&args)?, &args.flag_headerext, &args.flag_exclude)?;
+            with_output(&args, |output| {
+                profile = pbo::cmd_pack(PathBuf::from(&args.arg_sourcefolder), output, &args.flag_headerext, &args.flag_exclude, &options)?;
+                Ok(())
+            })?;
         }
 
         if let Some(pkey) = flag_privatekey {
-            sign::cmd_sign(pkey, PathBuf::from(args.arg_target.as_ref().unwrap()), flag_signature, sign::BISignVersion::V3)?;
+            sign::cmd_sign(pkey, PathBuf::from(args.arg_target.as_ref().unwrap()), flag_signature, sign::BISignVersion::V3, sign::BISignDigest::Sha1)?;
+        }
+
+        if args.flag_profile {
+            profile.write(&mut stderr())?;
         }
 
         Ok(())
+    } else if args.cmd_inspect && args.flag_json {
+        with_output(&args, |output| pbo::cmd_inspect_json(&mut get_input(&args)?, output))
     } else if args.cmd_inspect {
-        pbo::cmd_inspect(&mut get_input(&args)?)
+        with_output(&args, |output| pbo::cmd_inspect(&mut get_input(&args)?, output, args.flag_plain))
+    } else if args.cmd_check {
+        pbo::cmd_check(&mut get_input(&args)?, args.flag_expect_prefix.as_deref())
+    } else if args.cmd_modcpp {
+        with_output(&args, |output| pbo::cmd_modcpp(&mut get_input(&args)?, output))
+    } else if let (true, Some(pattern)) = (args.cmd_cat, &args.flag_glob) {
+        with_output(&args, |output| pbo::cmd_cat_glob(&mut get_input(&args)?, output, pattern))
     } else if args.cmd_cat {
-        pbo::cmd_cat(&mut get_input(&args)?, &mut get_output(&args)?, &args.arg_filename)
+        let separator = args.flag_separator.as_ref().map(|s| s.replace("\\t", "\t").replace("\\n", "\n")).unwrap_or_default();
+        with_output(&args, |output| pbo::cmd_cat(&mut get_input(&args)?, output, &args.arg_filename, &separator))
+    } else if args.cmd_p3d_info {
+        with_output(&args, |output| p3d::cmd_p3d_info(&mut get_input(&args)?, output))
+    } else if args.cmd_update {
+        let target = args.arg_target.as_ref().or(args.arg_source.as_ref()).map(PathBuf::from).unwrap();
+        write_atomically(&target, args.flag_output_on_error, |output| pbo::cmd_update(&mut get_input(&args)?, output, &args.flag_set, &args.flag_delete))
     } else if args.cmd_unpack {
-        pbo::cmd_unpack(&mut get_input(&args)?, PathBuf::from(&args.arg_targetfolder))
+        pbo::cmd_unpack(&mut get_input(&args)?, PathBuf::from(&args.arg_targetfolder), &args.flag_exclude)
+    } else if args.cmd_strip {
+        with_output(&args, |output| pbo::cmd_strip(&mut get_input(&args)?, output, &args.flag_remove))
     } else if args.cmd_keygen {
-        sign::cmd_keygen(PathBuf::from(&args.arg_keyname))
+        let bits = args.flag_bits.parse().map_err(|_| error!("Invalid key length \"{}\".", args.flag_bits))?;
+        let exponent = args.flag_exponent.parse().map_err(|_| error!("Invalid exponent \"{}\".", args.flag_exponent))?;
+        sign::cmd_keygen(PathBuf::from(&args.arg_keyname), bits, exponent)
+    } else if args.cmd_sign && Path::new(&args.arg_pbo).is_dir() {
+        if signature.is_some() || args.flag_sign_all_versions {
+            return Err(error!("<signature> and --sign-all-versions can't be used when signing a directory."));
+        }
+        let version = if args.flag_v2 { sign::BISignVersion::V2 } else { sign::BISignVersion::V3 };
+        let digest = if args.flag_sha256 { sign::BISignDigest::Sha256 } else { sign::BISignDigest::Sha1 };
+        sign::cmd_sign_dir(&mut stdout(), PathBuf::from(&args.arg_privatekey), PathBuf::from(&args.arg_pbo), version, digest)
+    } else if args.cmd_sign && args.flag_sign_all_versions && args.flag_sha256 {
+        Err(error!("--sha256 can't be used with --sign-all-versions."))
+    } else if args.cmd_sign && args.flag_sign_all_versions {
+        sign::cmd_sign_all_versions(PathBuf::from(&args.arg_privatekey), PathBuf::from(&args.arg_pbo), signature)
     } else if args.cmd_sign {
         let version = if args.flag_v2 { sign::BISignVersion::V2 } else { sign::BISignVersion::V3 };
-        sign::cmd_sign(PathBuf::from(&args.arg_privatekey), PathBuf::from(&args.arg_pbo), signature, version)
+        let digest = if args.flag_sha256 { sign::BISignDigest::Sha256 } else { sign::BISignDigest::Sha1 };
+        sign::cmd_sign(PathBuf::from(&args.arg_privatekey), PathBuf::from(&args.arg_pbo), signature, version, digest)
+    } else if let (true, Some(keydir)) = (args.cmd_verify, &args.flag_keydir) {
+        sign::cmd_verify_dir(&mut stdout(), PathBuf::from(keydir), PathBuf::from(&args.arg_pbo), signature)
+    } else if args.cmd_verify && args.flag_json {
+        sign::cmd_verify_json(&mut stdout(), PathBuf::from(&args.arg_publickey), PathBuf::from(&args.arg_pbo), signature)
     } else if args.cmd_verify {
         sign::cmd_verify(PathBuf::from(&args.arg_publickey), PathBuf::from(&args.arg_pbo), signature)
     } else {
@@ -201,11 +418,9 @@ pub fn args(args: &mut Args) {
         std::process::exit(0);
     }
 
-    unsafe {
-        WARNINGS_MUTED = Some(HashSet::from_iter(args.flag_warning.clone()));
-        if args.flag_verbose {
-            WARNINGS_MAXIMUM = std::u32::MAX;
-        }
+    set_muted_warnings(Some(HashSet::from_iter(args.flag_warning.clone())));
+    if args.flag_verbose {
+        set_warnings_maximum(u32::MAX);
     }
 
     run_command(&args).print_error(true);
@@ -1,14 +1,17 @@
 //! Functions for creating and working with BI keys and signatures
 
-use std::fs::{File};
+use std::fs::{read_dir};
 use std::io::{Read, Write, Error, Cursor};
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use openssl::bn::{BigNum, BigNumContext};
+use openssl::bn::{BigNum, BigNumContext, BigNumRef};
 use openssl::hash::{Hasher, MessageDigest, DigestBytes};
+use openssl::pkey::{Private, Public};
 use openssl::rsa::{Rsa};
+use serde::{Serialize};
 
+use crate::error::*;
 use crate::io::*;
 use crate::pbo::*;
 
@@ -43,9 +46,69 @@ pub enum BISignVersion {
     V3
 }
 
+/// Digest used for the hashes of a signature
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BISignDigest {
+    /// SHA1, used by all BI signature versions
+    #[default]
+    Sha1,
+    /// SHA256, for an experimental signature variant that is not accepted by the game. The first
+    /// hash covers the PBO's SHA1 checksum, since that is the only hash of the whole file stored
+    /// in it.
+    Sha256,
+}
+
+/// Added to the version number in signatures using SHA256
+const SHA256_VERSION_FLAG: u32 = 0x100;
+
+impl BISignDigest {
+    fn message_digest(self) -> MessageDigest {
+        match self {
+            BISignDigest::Sha1 => MessageDigest::sha1(),
+            BISignDigest::Sha256 => MessageDigest::sha256(),
+        }
+    }
+
+    // DER encoded DigestInfo that precedes the hash in the PKCS #1 v1.5 padding
+    fn digest_info(self) -> &'static [u8] {
+        match self {
+            BISignDigest::Sha1 => b"\x30\x21\x30\x09\x06\x05\x2b\x0e\x03\x02\x1a\x05\x00\x04\x14",
+            BISignDigest::Sha256 => b"\x30\x31\x30\x0d\x06\x09\x60\x86\x48\x01\x65\x03\x04\x02\x01\x05\x00\x04\x20",
+        }
+    }
+}
+
+/// Result of comparing one of the three hashes of a signature, see `VerifyReport`
+#[derive(Debug, Serialize)]
+pub struct HashCheck {
+    /// Whether the signed hash matches the one computed from the PBO
+    pub valid: bool,
+    /// Hash contained in the signature, as hex
+    pub signed: String,
+    /// Hash computed from the PBO, as hex
+    pub real: String,
+}
+
+/// Detailed result of verifying a signature, as returned by `BIPublicKey::check`
+#[derive(Debug, Serialize)]
+pub struct VerifyReport {
+    /// Name of the public key
+    pub key: String,
+    /// Signature version (2 or 3)
+    pub version: u32,
+    /// Digest used for the hashes
+    pub digest: BISignDigest,
+    /// Results for the three hashes in the signature
+    pub hashes: Vec<HashCheck>,
+    /// Whether all hashes match
+    pub valid: bool,
+}
+
 /// BI signature (.bisign)
 pub struct BISign {
     version: BISignVersion,
+    digest: BISignDigest,
     name: String,
     length: u32,
     exponent: u32,
@@ -63,11 +126,27 @@ fn write_bignum<O: Write>(output: &mut O, bn: &BigNum, size: usize) -> Result<()
     Ok(output.write_all(&vec)?)
 }
 
-fn namehash(pbo: &PBO) -> DigestBytes {
+// Key length for an RSA modulus, which has to fit the BI key layout
+fn rsa_key_length(n: &BigNumRef) -> Result<u32, Error> {
+    let length = n.num_bits() as u32;
+    if length == 0 || !length.is_multiple_of(16) {
+        return Err(error!("Unsupported key length of {} bits, has to be a multiple of 16.", length));
+    }
+    Ok(length)
+}
+
+fn rsa_exponent(e: &BigNumRef) -> Result<u32, Error> {
+    if e.num_bits() > 32 {
+        return Err(error!("Unsupported public exponent {}, has to fit into 32 bits.", e));
+    }
+    Ok(e.to_vec().iter().fold(0, |acc, byte| (acc << 8) | u32::from(*byte)))
+}
+
+fn namehash(pbo: &PBO, digest: BISignDigest) -> DigestBytes {
     let mut files_sorted: Vec<(String,&Cursor<Box<[u8]>>)> = pbo.files.iter().map(|(a,b)| (a.to_lowercase(),b)).collect();
     files_sorted.sort_by(|a, b| a.0.cmp(&b.0));
 
-    let mut h = Hasher::new(MessageDigest::sha1()).unwrap();
+    let mut h = Hasher::new(digest.message_digest()).unwrap();
 
     for (name, data) in &files_sorted {
         if data.get_ref().len() == 0 {
@@ -80,8 +159,8 @@ fn namehash(pbo: &PBO) -> DigestBytes {
     h.finish().unwrap()
 }
 
-fn filehash(pbo: &PBO, version: BISignVersion) -> DigestBytes {
-    let mut h = Hasher::new(MessageDigest::sha1()).unwrap();
+fn filehash(pbo: &PBO, version: BISignVersion, digest: BISignDigest) -> DigestBytes {
+    let mut h = Hasher::new(digest.message_digest()).unwrap();
     let mut nothing = true;
 
     for (name, cursor) in pbo.files.iter() {
@@ -115,13 +194,17 @@ fn filehash(pbo: &PBO, version: BISignVersion) -> DigestBytes {
     h.finish().unwrap()
 }
 
-fn generate_hashes(pbo: &PBO, version: BISignVersion, length: u32) -> (BigNum, BigNum, BigNum) {
+fn generate_hashes(pbo: &PBO, version: BISignVersion, digest: BISignDigest, length: u32) -> (BigNum, BigNum, BigNum) {
     let checksum = pbo.checksum.clone().unwrap();
-    let hash1 = checksum.as_slice();
+    let hash1 = match digest {
+        BISignDigest::Sha1 => checksum,
+        _ => openssl::hash::hash(digest.message_digest(), &checksum).unwrap().to_vec()
+    };
+    let hash1 = hash1.as_slice();
 
-    let mut h = Hasher::new(MessageDigest::sha1()).unwrap();
+    let mut h = Hasher::new(digest.message_digest()).unwrap();
     h.update(hash1).unwrap();
-    h.update(&*namehash(pbo)).unwrap();
+    h.update(&*namehash(pbo, digest)).unwrap();
     if let Some(prefix) = pbo.header_extensions.get("prefix") {
         h.update(prefix.as_bytes()).unwrap();
         if !prefix.ends_with('\\') {
@@ -130,9 +213,9 @@ fn generate_hashes(pbo: &PBO, version: BISignVersion, length: u32) -> (BigNum, B
     }
     let hash2 = &*h.finish().unwrap();
 
-    h = Hasher::new(MessageDigest::sha1()).unwrap();
-    h.update(&*filehash(pbo, version)).unwrap();
-    h.update(&*namehash(pbo)).unwrap();
+    h = Hasher::new(digest.message_digest()).unwrap();
+    h.update(&*filehash(pbo, version, digest)).unwrap();
+    h.update(&*namehash(pbo, digest)).unwrap();
     if let Some(prefix) = pbo.header_extensions.get("prefix") {
         h.update(prefix.as_bytes()).unwrap();
         if !prefix.ends_with('\\') {
@@ -141,34 +224,35 @@ fn generate_hashes(pbo: &PBO, version: BISignVersion, length: u32) -> (BigNum, B
     }
     let hash3 = &*h.finish().unwrap();
 
-    (pad_hash(hash1, (length / 8) as usize),
-        pad_hash(hash2, (length / 8) as usize),
-        pad_hash(hash3, (length / 8) as usize))
+    (pad_hash(hash1, (length / 8) as usize, digest),
+        pad_hash(hash2, (length / 8) as usize, digest),
+        pad_hash(hash3, (length / 8) as usize, digest))
 }
 
-fn pad_hash(hash: &[u8], size: usize) -> BigNum {
+fn pad_hash(hash: &[u8], size: usize, digest: BISignDigest) -> BigNum {
     let mut vec: Vec<u8> = Vec::new();
 
     vec.push(0);
     vec.push(1);
-    vec.resize(size - 36, 255);
-    vec.extend(b"\x00\x30\x21\x30\x09\x06\x05\x2b");
-    vec.extend(b"\x0e\x03\x02\x1a\x05\x00\x04\x14");
+    vec.resize(size - 1 - digest.digest_info().len() - hash.len(), 255);
+    vec.push(0);
+    vec.extend(digest.digest_info());
     vec.extend(hash);
 
     BigNum::from_slice(&vec).unwrap()
 }
 
-fn display_hashes(a: BigNum, b: BigNum) -> (String, String) {
+fn display_hashes(a: BigNum, b: BigNum, digest: BISignDigest) -> (String, String) {
     let hexa = a.to_hex_str().unwrap().to_lowercase();
     let hexb = b.to_hex_str().unwrap().to_lowercase();
+    let hash_length = digest.message_digest().size() * 2;
 
-    if hexa.len() != hexb.len() || hexa.len() <= 40 {
+    if hexa.len() != hexb.len() || hexa.len() <= hash_length {
         return (hexa, hexb);
     }
 
-    let (paddinga, hasha) = hexa.split_at(hexa.len() - 40);
-    let (paddingb, hashb) = hexb.split_at(hexb.len() - 40);
+    let (paddinga, hasha) = hexa.split_at(hexa.len() - hash_length);
+    let (paddingb, hashb) = hexb.split_at(hexb.len() - hash_length);
 
     if paddinga != paddingb {
         (hexa, hexb)
@@ -188,7 +272,9 @@ impl BIPrivateKey {
         let length = input.read_u32::<LittleEndian>()?;
         let exponent = input.read_u32::<LittleEndian>()?;
 
-        assert_eq!(temp, length / 16 * 9 + 20);
+        if temp != length / 16 * 9 + 20 {
+            return Err(error!("Invalid private key, key length {} doesn't match block length {}.", length, temp));
+        }
 
         let mut buffer = vec![0; (length / 8) as usize];
         input.read_exact(&mut buffer)?;
@@ -243,20 +329,84 @@ impl BIPrivateKey {
     ///
     /// Arma 3 uses 1024 bit keys.
     pub fn generate(length: u32, name: String) -> BIPrivateKey {
-        let rsa = Rsa::generate(length).expect("Failed to generate keypair");
+        BIPrivateKey::generate_with_exponent(length, 65537, name).expect("Failed to generate keypair")
+    }
+
+    /// Same as `generate`, but with the given public exponent instead of 65537.
+    ///
+    /// The length has to be a multiple of 16 and at least 512 bits, the exponent odd and at
+    /// least 3.
+    pub fn generate_with_exponent(length: u32, exponent: u32, name: String) -> Result<BIPrivateKey, Error> {
+        if length < 512 || !length.is_multiple_of(16) {
+            return Err(error!("Invalid key length {}, has to be a multiple of 16 and at least 512.", length));
+        }
+        if exponent < 3 || exponent.is_multiple_of(2) {
+            return Err(error!("Invalid public exponent {}, has to be odd and at least 3.", exponent));
+        }
+
+        let e = BigNum::from_u32(exponent).unwrap();
+        let rsa = Rsa::generate_with_e(length, &e).map_err(|e| error!("Failed to generate keypair: {}", e))?;
+
+        BIPrivateKey::from_rsa(&rsa, name)
+    }
+
+    /// Converts an RSA private key in PEM format (PKCS#1 or PKCS#8) to a private key with the
+    /// given name.
+    pub fn from_rsa_pem(pem: &[u8], name: String) -> Result<BIPrivateKey, Error> {
+        let rsa = Rsa::private_key_from_pem(pem).map_err(|e| error!("Failed to read PEM private key: {}", e))?;
+        BIPrivateKey::from_rsa(&rsa, name)
+    }
 
-        BIPrivateKey {
+    /// Converts an RSA private key in PKCS#1 DER format to a private key with the given name.
+    pub fn from_rsa_der(der: &[u8], name: String) -> Result<BIPrivateKey, Error> {
+        let rsa = Rsa::private_key_from_der(der).map_err(|e| error!("Failed to read DER private key: {}", e))?;
+        BIPrivateKey::from_rsa(&rsa, name)
+    }
+
+    fn from_rsa(rsa: &Rsa<Private>, name: String) -> Result<BIPrivateKey, Error> {
+        let length = rsa_key_length(rsa.n())?;
+        let exponent = rsa_exponent(rsa.e())?;
+
+        let components = [rsa.p(), rsa.q(), rsa.dmp1(), rsa.dmq1(), rsa.iqmp()];
+        if components.iter().any(|c| c.map(|c| c.num_bytes() as u32 > length / 16).unwrap_or(true)) {
+            return Err(error!("Private key doesn't have CRT components of {} bits.", length / 2));
+        }
+
+        Ok(BIPrivateKey {
             name,
             length,
-            exponent: 65537,
-            n: BigNum::from_slice(&rsa.n().to_vec()).unwrap(),
-            p: BigNum::from_slice(&rsa.p().unwrap().to_vec()).unwrap(),
-            q: BigNum::from_slice(&rsa.q().unwrap().to_vec()).unwrap(),
-            dmp1: BigNum::from_slice(&rsa.dmp1().unwrap().to_vec()).unwrap(),
-            dmq1: BigNum::from_slice(&rsa.dmq1().unwrap().to_vec()).unwrap(),
-            iqmp: BigNum::from_slice(&rsa.iqmp().unwrap().to_vec()).unwrap(),
-            d: BigNum::from_slice(&rsa.d().to_vec()).unwrap(),
-        }
+            exponent,
+            n: rsa.n().to_owned().unwrap(),
+            p: rsa.p().unwrap().to_owned().unwrap(),
+            q: rsa.q().unwrap().to_owned().unwrap(),
+            dmp1: rsa.dmp1().unwrap().to_owned().unwrap(),
+            dmq1: rsa.dmq1().unwrap().to_owned().unwrap(),
+            iqmp: rsa.iqmp().unwrap().to_owned().unwrap(),
+            d: rsa.d().to_owned().unwrap(),
+        })
+    }
+
+    /// Returns this private key in PKCS#1 PEM format. The name isn't part of it.
+    pub fn to_rsa_pem(&self) -> Result<Vec<u8>, Error> {
+        self.to_rsa()?.private_key_to_pem().map_err(|e| error!("Failed to write PEM private key: {}", e))
+    }
+
+    /// Returns this private key in PKCS#1 DER format. The name isn't part of it.
+    pub fn to_rsa_der(&self) -> Result<Vec<u8>, Error> {
+        self.to_rsa()?.private_key_to_der().map_err(|e| error!("Failed to write DER private key: {}", e))
+    }
+
+    fn to_rsa(&self) -> Result<Rsa<Private>, Error> {
+        Rsa::from_private_components(
+            self.n.to_owned().unwrap(),
+            BigNum::from_u32(self.exponent).unwrap(),
+            self.d.to_owned().unwrap(),
+            self.p.to_owned().unwrap(),
+            self.q.to_owned().unwrap(),
+            self.dmp1.to_owned().unwrap(),
+            self.dmq1.to_owned().unwrap(),
+            self.iqmp.to_owned().unwrap(),
+        ).map_err(|e| error!("Invalid private key: {}", e))
     }
 
     /// Returns the public key for this private key.
@@ -271,7 +421,12 @@ impl BIPrivateKey {
 
     /// Signs the given PBO with this private key.
     pub fn sign(&self, pbo: &PBO, version: BISignVersion) -> BISign {
-        let (hash1, hash2, hash3) = generate_hashes(pbo, version, self.length);
+        self.sign_with_digest(pbo, version, BISignDigest::Sha1)
+    }
+
+    /// Same as `sign`, but with the given digest for the hashes.
+    pub fn sign_with_digest(&self, pbo: &PBO, version: BISignVersion, digest: BISignDigest) -> BISign {
+        let (hash1, hash2, hash3) = generate_hashes(pbo, version, digest, self.length);
 
         let mut ctx = BigNumContext::new().unwrap();
 
@@ -284,6 +439,7 @@ impl BIPrivateKey {
 
         BISign {
             version,
+            digest,
             name: self.name.clone(),
             length: self.length,
             exponent: self.exponent,
@@ -324,7 +480,9 @@ impl BIPublicKey {
         let length = input.read_u32::<LittleEndian>()?;
         let exponent = input.read_u32::<LittleEndian>()?;
 
-        assert_eq!(temp, length / 8 + 20);
+        if temp != length / 8 + 20 {
+            return Err(error!("Invalid public key, key length {} doesn't match block length {}.", length, temp));
+        }
 
         let mut buffer = vec![0; (length / 8) as usize];
         input.read_exact(&mut buffer)?;
@@ -339,35 +497,80 @@ impl BIPublicKey {
         })
     }
 
-    // @todo: example
-    /// Verifies a signature against this public key.
-    pub fn verify(&self, pbo: &PBO, signature: &BISign) -> Result<(), Error> {
-        let (real_hash1, real_hash2, real_hash3) = generate_hashes(pbo, signature.version, self.length);
+    /// Converts an RSA public key in PEM format (SubjectPublicKeyInfo or PKCS#1) to a public key
+    /// with the given name.
+    pub fn from_rsa_pem(pem: &[u8], name: String) -> Result<BIPublicKey, Error> {
+        let rsa = Rsa::public_key_from_pem(pem).or_else(|_| Rsa::public_key_from_pem_pkcs1(pem))
+            .map_err(|e| error!("Failed to read PEM public key: {}", e))?;
+        BIPublicKey::from_rsa(&rsa, name)
+    }
+
+    /// Converts an RSA public key in SubjectPublicKeyInfo DER format to a public key with the
+    /// given name.
+    pub fn from_rsa_der(der: &[u8], name: String) -> Result<BIPublicKey, Error> {
+        let rsa = Rsa::public_key_from_der(der).map_err(|e| error!("Failed to read DER public key: {}", e))?;
+        BIPublicKey::from_rsa(&rsa, name)
+    }
+
+    fn from_rsa(rsa: &Rsa<Public>, name: String) -> Result<BIPublicKey, Error> {
+        Ok(BIPublicKey {
+            name,
+            length: rsa_key_length(rsa.n())?,
+            exponent: rsa_exponent(rsa.e())?,
+            n: rsa.n().to_owned().unwrap(),
+        })
+    }
+
+    /// Returns this public key in SubjectPublicKeyInfo PEM format. The name isn't part of it.
+    pub fn to_rsa_pem(&self) -> Result<Vec<u8>, Error> {
+        self.to_rsa()?.public_key_to_pem().map_err(|e| error!("Failed to write PEM public key: {}", e))
+    }
+
+    /// Returns this public key in SubjectPublicKeyInfo DER format. The name isn't part of it.
+    pub fn to_rsa_der(&self) -> Result<Vec<u8>, Error> {
+        self.to_rsa()?.public_key_to_der().map_err(|e| error!("Failed to write DER public key: {}", e))
+    }
+
+    fn to_rsa(&self) -> Result<Rsa<Public>, Error> {
+        Rsa::from_public_components(self.n.to_owned().unwrap(), BigNum::from_u32(self.exponent).unwrap())
+            .map_err(|e| error!("Invalid public key: {}", e))
+    }
+
+    /// Compares the hashes of a signature with the ones computed from the PBO, using the digest the
+    /// signature claims to use.
+    pub fn check(&self, pbo: &PBO, signature: &BISign) -> VerifyReport {
+        let (real_hash1, real_hash2, real_hash3) = generate_hashes(pbo, signature.version, signature.digest, self.length);
 
         let mut ctx = BigNumContext::new().unwrap();
 
         let exponent = BigNum::from_u32(self.exponent).unwrap();
 
-        let mut signed_hash1: BigNum = BigNum::new().unwrap();
-        signed_hash1.mod_exp(&signature.sig1, &exponent, &self.n, &mut ctx).unwrap();
-        let mut signed_hash2: BigNum = BigNum::new().unwrap();
-        signed_hash2.mod_exp(&signature.sig2, &exponent, &self.n, &mut ctx).unwrap();
-        let mut signed_hash3: BigNum = BigNum::new().unwrap();
-        signed_hash3.mod_exp(&signature.sig3, &exponent, &self.n, &mut ctx).unwrap();
+        let mut hashes = Vec::with_capacity(3);
+        for (sig, real_hash) in [(&signature.sig1, real_hash1), (&signature.sig2, real_hash2), (&signature.sig3, real_hash3)] {
+            let mut signed_hash: BigNum = BigNum::new().unwrap();
+            signed_hash.mod_exp(sig, &exponent, &self.n, &mut ctx).unwrap();
 
-        if real_hash1 != signed_hash1 {
-            let (s, r) = display_hashes(signed_hash1, real_hash1);
-            return Err(error!("Hash 1 doesn't match\nSigned hash: {}\nReal hash:   {}", s, r));
+            let valid = real_hash == signed_hash;
+            let (signed, real) = display_hashes(signed_hash, real_hash, signature.digest);
+            hashes.push(HashCheck { valid, signed, real });
         }
 
-        if real_hash2 != signed_hash2 {
-            let (s, r) = display_hashes(signed_hash2, real_hash2);
-            return Err(error!("Hash 2 doesn't match\nSigned hash: {}\nReal hash:   {}", s, r));
+        VerifyReport {
+            key: self.name.clone(),
+            version: signature.version.into(),
+            digest: signature.digest,
+            valid: hashes.iter().all(|h| h.valid),
+            hashes,
         }
+    }
 
-        if real_hash3 != signed_hash3 {
-            let (s, r) = display_hashes(signed_hash3, real_hash3);
-            return Err(error!("Hash 3 doesn't match\nSigned hash: {}\nReal hash:   {}", s, r));
+    // @todo: example
+    /// Verifies a signature against this public key.
+    pub fn verify(&self, pbo: &PBO, signature: &BISign) -> Result<(), Error> {
+        let report = self.check(pbo, signature);
+
+        if let Some((i, hash)) = report.hashes.iter().enumerate().find(|(_, h)| !h.valid) {
+            return Err(error!("Hash {} doesn't match\nSigned hash: {}\nReal hash:   {}", i + 1, hash.signed, hash.real));
         }
 
         Ok(())
@@ -407,7 +610,9 @@ impl BISign {
         let length = input.read_u32::<LittleEndian>()?;
         let exponent = input.read_u32::<LittleEndian>()?;
 
-        assert_eq!(temp, length / 8 + 20);
+        if temp != length / 8 + 20 {
+            return Err(error!("Invalid signature, key length {} doesn't match block length {}.", length, temp));
+        }
 
         let mut buffer = vec![0; (length / 8) as usize];
         input.read_exact(&mut buffer)?;
@@ -421,7 +626,9 @@ impl BISign {
         buffer = buffer.iter().rev().cloned().collect();
         let sig1 = BigNum::from_slice(&buffer).unwrap();
 
-        let version = match input.read_u32::<LittleEndian>()? {
+        let version_number = input.read_u32::<LittleEndian>()?;
+        let digest = if version_number & SHA256_VERSION_FLAG != 0 { BISignDigest::Sha256 } else { BISignDigest::Sha1 };
+        let version = match version_number & !SHA256_VERSION_FLAG {
             2 => BISignVersion::V2,
             3 => BISignVersion::V3,
             _ => {
@@ -445,6 +652,7 @@ impl BISign {
 
         Ok(BISign {
             version,
+            digest,
             name,
             length,
             exponent,
@@ -466,7 +674,11 @@ impl BISign {
         write_bignum(output, &self.n, (self.length / 8) as usize)?;
         output.write_u32::<LittleEndian>(self.length / 8)?;
         write_bignum(output, &self.sig1, (self.length / 8) as usize)?;
-        output.write_u32::<LittleEndian>(self.version.into())?;
+        let version: u32 = self.version.into();
+        output.write_u32::<LittleEndian>(match self.digest {
+            BISignDigest::Sha1 => version,
+            BISignDigest::Sha256 => version | SHA256_VERSION_FLAG
+        })?;
         output.write_u32::<LittleEndian>(self.length / 8)?;
         write_bignum(output, &self.sig2, (self.length / 8) as usize)?;
         output.write_u32::<LittleEndian>(self.length / 8)?;
@@ -475,43 +687,107 @@ impl BISign {
     }
 }
 
-/// Generates a key pair with the given name.
+/// Generates a key pair with the given name, key length and public exponent.
 ///
 /// The output paths are created by appending extensions to the keyname.
-pub fn cmd_keygen(keyname: PathBuf) -> Result<(), Error> {
-    let private_key = BIPrivateKey::generate(1024, keyname.file_name().unwrap().to_str().unwrap().to_string());
+pub fn cmd_keygen(keyname: PathBuf, length: u32, exponent: u32) -> Result<(), Error> {
+    let name = keyname.file_name().and_then(|n| n.to_str()).ok_or_else(|| error!("Invalid key name {:?}.", keyname))?;
+    let private_key = BIPrivateKey::generate_with_exponent(length, exponent, name.to_string())?;
     let public_key = private_key.to_public_key();
-    let name = keyname.file_name().unwrap().to_str().unwrap();
 
     let mut private_key_path = keyname.clone();
     private_key_path.set_file_name(format!("{}.biprivatekey", name));
-    private_key.write(&mut File::create(private_key_path).unwrap()).expect("Failed to write private key");
+    private_key.write(&mut create_file(&private_key_path).prepend_error("Failed to create private key:")?).prepend_error("Failed to write private key:")?;
 
     let mut public_key_path = keyname.clone();
     public_key_path.set_file_name(format!("{}.bikey", name));
-    public_key.write(&mut File::create(public_key_path).unwrap()).expect("Failed to write public key");
+    public_key.write(&mut create_file(&public_key_path).prepend_error("Failed to create public key:")?).prepend_error("Failed to write public key:")?;
 
     Ok(())
 }
 
 /// Signs a PBO with the given private key.
 ///
-/// If the signature path is not given it is inferred from the PBO path.
-pub fn cmd_sign(privatekey_path: PathBuf, pbo_path: PathBuf, signature_path: Option<PathBuf>, version: BISignVersion) -> Result<(), Error> {
-    let privatekey = BIPrivateKey::read(&mut File::open(&privatekey_path).expect("Failed to open private key")).expect("Failed to read private key");
-    let pbo = PBO::read(&mut File::open(&pbo_path).expect("Failed to open PBO")).expect("Failed to read PBO");
-
-    let sig_path = match signature_path {
-        Some(path) => path,
-        None => {
-            let mut path = pbo_path.clone();
-            path.set_extension(format!("pbo.{}.bisign", privatekey.name));
-            path
+/// If the signature path is not given it is inferred from the PBO path. `digest` is used for the
+/// hashes, the game only accepts SHA1.
+pub fn cmd_sign(privatekey_path: PathBuf, pbo_path: PathBuf, signature_path: Option<PathBuf>, version: BISignVersion, digest: BISignDigest) -> Result<(), Error> {
+    let privatekey = BIPrivateKey::read(&mut open_file(&privatekey_path).prepend_error("Failed to open private key:")?).prepend_error("Failed to read private key:")?;
+    let pbo = PBO::read(&mut open_file(&pbo_path).prepend_error("Failed to open PBO:")?).prepend_error("Failed to read PBO:")?;
+
+    let sig_path = signature_path.unwrap_or_else(|| default_signature_path(&pbo_path, &privatekey.name));
+
+    let sig = privatekey.sign_with_digest(&pbo, version, digest);
+    sig.write(&mut create_file(&sig_path).prepend_error("Failed to create signature file:")?).prepend_error("Failed to write signature:")?;
+
+    Ok(())
+}
+
+/// Signs every PBO in a directory and its subdirectories with the given private key.
+///
+/// The signatures are written next to the PBOs with inferred paths, like `cmd_sign` does. A line
+/// is written to the output for every PBO; failing PBOs don't stop the others from being signed,
+/// but the command fails at the end if any did.
+pub fn cmd_sign_dir<O: Write>(output: &mut O, privatekey_path: PathBuf, directory: PathBuf, version: BISignVersion, digest: BISignDigest) -> Result<(), Error> {
+    let privatekey = BIPrivateKey::read(&mut open_file(&privatekey_path).prepend_error("Failed to open private key:")?).prepend_error("Failed to read private key:")?;
+
+    let mut pbo_paths = Vec::new();
+    find_pbos(&directory, &mut pbo_paths).prepend_error(format!("Failed to read directory {:?}:", directory))?;
+    pbo_paths.sort();
+
+    let mut failed = 0;
+    for pbo_path in &pbo_paths {
+        let result = open_file(pbo_path).and_then(|mut f| PBO::read(&mut f)).and_then(|pbo| {
+            let sig = privatekey.sign_with_digest(&pbo, version, digest);
+            sig.write(&mut create_file(&default_signature_path(pbo_path, &privatekey.name))?)
+        });
+
+        match result {
+            Ok(()) => writeln!(output, "Signed {:?}.", pbo_path)?,
+            Err(e) => {
+                failed += 1;
+                writeln!(output, "Failed to sign {:?}: {}", pbo_path, e)?;
+            }
         }
-    };
+    }
+
+    if failed > 0 {
+        return Err(error!("Failed to sign {} of {} PBOs.", failed, pbo_paths.len()));
+    }
+
+    Ok(())
+}
+
+fn find_pbos(directory: &Path, result: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_pbos(&path, result)?;
+        } else if path.extension().map(|ext| ext.eq_ignore_ascii_case("pbo")).unwrap_or(false) {
+            result.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Signs a PBO with the given private key, writing both a V2 and a V3 signature.
+///
+/// The signature paths are inferred from the PBO path if not given, and have the version
+/// inserted before the extension (`.bi2.bisign` and `.bi3.bisign`).
+pub fn cmd_sign_all_versions(privatekey_path: PathBuf, pbo_path: PathBuf, signature_path: Option<PathBuf>) -> Result<(), Error> {
+    let privatekey = BIPrivateKey::read(&mut open_file(&privatekey_path).prepend_error("Failed to open private key:")?).prepend_error("Failed to read private key:")?;
+    let pbo = PBO::read(&mut open_file(&pbo_path).prepend_error("Failed to open PBO:")?).prepend_error("Failed to read PBO:")?;
+
+    let sig_path = signature_path.unwrap_or_else(|| default_signature_path(&pbo_path, &privatekey.name));
+
+    for version in &[BISignVersion::V2, BISignVersion::V3] {
+        let number: u32 = (*version).into();
+        let mut path = sig_path.clone();
+        path.set_extension(format!("bi{}.bisign", number));
 
-    let sig = privatekey.sign(&pbo, version);
-    sig.write(&mut File::create(&sig_path).expect("Failed to open signature file")).expect("Failed to write signature");
+        let sig = privatekey.sign(&pbo, *version);
+        sig.write(&mut create_file(&path).prepend_error("Failed to create signature file:")?).prepend_error("Failed to write signature:")?;
+    }
 
     Ok(())
 }
@@ -520,19 +796,95 @@ pub fn cmd_sign(privatekey_path: PathBuf, pbo_path: PathBuf, signature_path: Opt
 ///
 /// If the signature path is not given it is inferred from the PBO path.
 pub fn cmd_verify(publickey_path: PathBuf, pbo_path: PathBuf, signature_path: Option<PathBuf>) -> Result<(), Error> {
-    let publickey = BIPublicKey::read(&mut File::open(&publickey_path).expect("Failed to open public key")).expect("Failed to read public key");
-    let pbo = PBO::read(&mut File::open(&pbo_path).expect("Failed to open PBO")).expect("Failed to read PBO");
-
-    let sig_path = match signature_path {
-        Some(path) => path,
-        None => {
-            let mut path = pbo_path.clone();
-            path.set_extension(format!("pbo.{}.bisign", publickey.name));
-            path
+    let (publickey, pbo, sig) = read_verify_inputs(publickey_path, pbo_path, signature_path)?;
+
+    publickey.verify(&pbo, &sig)
+}
+
+/// Same as `cmd_verify`, but writes the result for every hash to the output as JSON. Still
+/// fails if the signature is invalid, after writing the result.
+pub fn cmd_verify_json<O: Write>(output: &mut O, publickey_path: PathBuf, pbo_path: PathBuf, signature_path: Option<PathBuf>) -> Result<(), Error> {
+    let (publickey, pbo, sig) = read_verify_inputs(publickey_path, pbo_path, signature_path)?;
+
+    let report = publickey.check(&pbo, &sig);
+    serde_json::to_writer_pretty(&mut *output, &report).map_err(|e| error!("{}", e))?;
+    output.write_all(b"\n")?;
+
+    if !report.valid {
+        return Err(error!("Signature is invalid."));
+    }
+
+    Ok(())
+}
+
+/// Verifies a signature for a PBO against every public key (`*.bikey`) in a directory and writes
+/// the name of the first matching key to the output.
+///
+/// If the signature path is not given it is inferred from the PBO path and the name of each key.
+/// Keys that can't be read are reported as warnings and skipped. Fails if no key matches.
+pub fn cmd_verify_dir<O: Write>(output: &mut O, keys_dir: PathBuf, pbo_path: PathBuf, signature_path: Option<PathBuf>) -> Result<(), Error> {
+    let pbo = PBO::read(&mut open_file(&pbo_path).prepend_error("Failed to open PBO:")?).prepend_error("Failed to read PBO:")?;
+
+    let mut key_paths: Vec<PathBuf> = read_dir(&keys_dir).prepend_error(format!("Failed to read key directory {:?}:", keys_dir))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file() && path.extension().map(|ext| ext.eq_ignore_ascii_case("bikey")).unwrap_or(false))
+        .collect();
+    key_paths.sort();
+
+    let mut unreadable = Vec::new();
+    let mut matching = None;
+    for key_path in &key_paths {
+        let publickey = match open_file(key_path).and_then(|mut f| BIPublicKey::read(&mut f)) {
+            Ok(key) => key,
+            Err(e) => {
+                unreadable.push((key_path, e));
+                continue;
+            }
+        };
+
+        let sig_path = match &signature_path {
+            Some(path) => path.clone(),
+            None => default_signature_path(&pbo_path, &publickey.name)
+        };
+
+        // without a given signature, keys without an inferred signature next to the PBO don't match
+        if signature_path.is_none() && !sig_path.exists() {
+            continue;
         }
-    };
 
-    let sig = BISign::read(&mut File::open(&sig_path).expect("Failed to open signature")).expect("Failed to read signature");
+        let sig = BISign::read(&mut open_file(&sig_path).prepend_error("Failed to open signature:")?).prepend_error("Failed to read signature:")?;
+        if publickey.check(&pbo, &sig).valid {
+            matching = Some(publickey.name);
+            break;
+        }
+    }
 
-    publickey.verify(&pbo, &sig)
+    for (path, e) in &unreadable {
+        warning(format!("Failed to read public key: {}", e), Some("unreadable-key"), (Some(path.to_str().unwrap().to_string()), None));
+    }
+
+    if let Some(name) = matching {
+        writeln!(output, "{}", name)?;
+        return Ok(());
+    }
+
+    Err(error!("None of the {} keys in {:?} match the signature ({} could not be read).", key_paths.len(), keys_dir, unreadable.len()))
+}
+
+// Signature path next to the PBO, used when none is given
+fn default_signature_path(pbo_path: &Path, keyname: &str) -> PathBuf {
+    let mut path = pbo_path.to_path_buf();
+    path.set_extension(format!("pbo.{}.bisign", keyname));
+    path
+}
+
+fn read_verify_inputs(publickey_path: PathBuf, pbo_path: PathBuf, signature_path: Option<PathBuf>) -> Result<(BIPublicKey, PBO, BISign), Error> {
+    let publickey = BIPublicKey::read(&mut open_file(&publickey_path).prepend_error("Failed to open public key:")?).prepend_error("Failed to read public key:")?;
+    let pbo = PBO::read(&mut open_file(&pbo_path).prepend_error("Failed to open PBO:")?).prepend_error("Failed to read PBO:")?;
+
+    let sig_path = signature_path.unwrap_or_else(|| default_signature_path(&pbo_path, &publickey.name));
+
+    let sig = BISign::read(&mut open_file(&sig_path).prepend_error("Failed to open signature:")?).prepend_error("Failed to read signature:")?;
+
+    Ok((publickey, pbo, sig))
 }
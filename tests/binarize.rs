@@ -0,0 +1,128 @@
+use std::fs::{File};
+use std::io::{Write};
+
+use tempfile::{tempdir};
+
+use armake2::binarize::*;
+
+#[test]
+fn test_material_dependencies() {
+    let input = String::from("\
+ambient[] = {1, 1, 1, 1};
+class Stage0 {
+    texture = \"#(argb,8,8,3)color(1,1,1,1,CO)\";
+};
+class Stage1 {
+    texture = \"x\\foo\\addons\\bar\\data\\bar_nohq.paa\";
+    uvSource = \"tex\";
+};
+class Stage2 {
+    texture = \"x\\foo\\addons\\bar\\data\\bar_smdi.paa\";
+};
+");
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("bar.rvmat");
+    File::create(&path).unwrap().write_all(input.as_bytes()).unwrap();
+
+    let textures = material_dependencies(&path).unwrap();
+
+    assert_eq!(vec!["x\\foo\\addons\\bar\\data\\bar_nohq.paa", "x\\foo\\addons\\bar\\data\\bar_smdi.paa"], textures);
+}
+
+#[test]
+fn test_extract_dependencies() {
+    use std::fs::{create_dir_all, read_to_string};
+
+    use armake2::p3d::*;
+
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("bar");
+    create_dir_all(addon.join("data")).unwrap();
+    File::create(addon.join("$PBOPREFIX$")).unwrap().write_all(b"x\\foo\\addons\\bar").unwrap();
+    File::create(addon.join("data").join("bar_co.paa")).unwrap().write_all(b"co").unwrap();
+    File::create(addon.join("data").join("bar_nohq.paa")).unwrap().write_all(b"nohq").unwrap();
+    File::create(addon.join("data").join("bar.rvmat")).unwrap()
+        .write_all(b"class Stage1 { texture = \"x\\foo\\addons\\bar\\data\\bar_nohq.paa\"; };").unwrap();
+
+    let mut lod = LOD {
+        version_major: 28,
+        version_minor: 256,
+        resolution: 1.0,
+        points: vec![Point::new()],
+        face_normals: Vec::new(),
+        faces: Vec::new(),
+        taggs: Default::default(),
+    };
+    for (texture, material) in &[
+        ("x\\foo\\addons\\bar\\data\\bar_co.paa", "x\\foo\\addons\\bar\\data\\bar.rvmat"),
+        ("#(argb,8,8,3)color(1,0,0,1,CO)", ""),
+        ("x\\foo\\addons\\bar\\data\\missing_co.paa", ""),
+    ] {
+        let mut face = Face::new();
+        face.vertices = vec![Vertex::new(), Vertex::new(), Vertex::new()];
+        face.texture = texture.to_string();
+        face.material = material.to_string();
+        lod.faces.push(face);
+    }
+    let p3d = P3D { version: 257, lods: vec![lod] };
+    p3d.write(&mut File::create(addon.join("bar.p3d")).unwrap()).unwrap();
+
+    let target = dir.path().join("textures");
+    let unresolved = extract_dependencies(&addon.join("bar.p3d"), &[dir.path().to_path_buf()], &target).unwrap();
+
+    assert_eq!(vec!["x\\foo\\addons\\bar\\data\\missing_co.paa"], unresolved);
+    let data = target.join("x").join("foo").join("addons").join("bar").join("data");
+    assert_eq!("co", read_to_string(data.join("bar_co.paa")).unwrap());
+    assert_eq!("nohq", read_to_string(data.join("bar_nohq.paa")).unwrap());
+    assert!(data.join("bar.rvmat").exists());
+}
+
+#[test]
+#[cfg(all(unix, feature = "wine"))]
+fn test_binarize_wine() {
+    use std::os::unix::fs::PermissionsExt;
+
+    use armake2::pbo::*;
+
+    let dir = tempdir().unwrap();
+    let bin = dir.path().join("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+
+    // winepath maps to a fake Z: drive, wine "binarizes" by prefixing the file given in the last
+    // three arguments (source folder, target folder and file name)
+    let stubs = [
+        ("winepath", "#!/bin/sh\necho \"Z:$2\"\n"),
+        ("wine", "#!/bin/sh\nwhile [ $# -gt 3 ]; do shift; done\n[ \"$3\" = broken.p3d ] && exit 2\nsrc=\"${1#Z:}\"\ndst=\"${2#Z:}\"\n{ printf 'binarized:'; cat \"$src/$3\"; } > \"$dst/$3\"\n"),
+    ];
+    for (name, script) in &stubs {
+        let path = bin.join(name);
+        File::create(&path).unwrap().write_all(script.as_bytes()).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    let path = std::env::var("PATH").unwrap_or_default();
+    std::env::set_var("PATH", format!("{}:{}", bin.to_str().unwrap(), path));
+
+    let exe = dir.path().join("binarize_x64.exe");
+    File::create(&exe).unwrap();
+
+    let addon = dir.path().join("addon");
+    std::fs::create_dir_all(&addon).unwrap();
+    File::create(addon.join("model.p3d")).unwrap().write_all(b"MLOD").unwrap();
+
+    assert!(is_available(Some(&exe)));
+    assert!(!is_available(Some(&dir.path().join("missing.exe"))));
+
+    let cursor = binarize_with_options(&addon.join("model.p3d"), Some(&exe), &[]).unwrap();
+    assert_eq!(b"binarized:MLOD", &cursor.get_ref()[..]);
+
+    // the temp directories are removed when binarize.exe fails too
+    File::create(addon.join("broken.p3d")).unwrap().write_all(b"MLOD").unwrap();
+    assert!(binarize_with_options(&addon.join("broken.p3d"), Some(&exe), &[]).is_err());
+    std::fs::remove_file(addon.join("broken.p3d")).unwrap();
+    assert!(!std::fs::read_dir(std::env::temp_dir()).unwrap().any(|e| e.unwrap().file_name().to_str().unwrap().starts_with("armake_broken.p3d")));
+
+    let options = PackOptions { binarize_exe: Some(exe), ..Default::default() };
+    let pbo = PBO::from_directory_with_options(addon, true, &Vec::new(), &Vec::new(), &options).unwrap();
+    assert_eq!(b"binarized:MLOD", &pbo.files["model.p3d"].get_ref()[..]);
+}
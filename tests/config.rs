@@ -1,5 +1,8 @@
+use std::fs::{read_to_string};
 use std::io::{Cursor, Seek, SeekFrom};
 
+use tempfile::{tempdir};
+
 use armake2::config::*;
 
 #[test]
@@ -46,3 +49,552 @@ class CfgPatches {
     };
 };", output.trim());
 }
+
+#[test]
+fn config_read_rapified_offset() {
+    let input = String::from("\
+class CfgPatches {
+    class foo {
+        units[] = {};
+        requiredVersion = 1.56;
+    };
+};");
+    let config = Config::read(&mut Cursor::new(input), None, &Vec::new()).unwrap();
+
+    let mut buffer: Vec<u8> = b"some junk in front".to_vec();
+    buffer.extend(config.to_cursor().unwrap().into_inner().iter());
+
+    let mut cursor = Cursor::new(buffer);
+    cursor.seek(SeekFrom::Start(18)).unwrap();
+
+    let reread = Config::read_rapified(&mut cursor).unwrap();
+
+    assert_eq!(config.to_string().unwrap(), reread.to_string().unwrap());
+}
+
+#[test]
+fn config_read_rapified_deletions() {
+    let input = String::from("class CfgVehicles {\n    class Car;\n    delete Truck;\n};");
+    let config = Config::read(&mut Cursor::new(input), None, &Vec::new()).unwrap();
+
+    let reread = Config::read_rapified(&mut Cursor::new(config.to_cursor().unwrap().into_inner())).unwrap();
+
+    assert_eq!("class CfgVehicles {\n    class Car;\n    delete Truck;\n};", reread.to_string().unwrap().trim());
+}
+
+#[test]
+fn config_rapify_dump_preprocessed() {
+    let input = String::from("\
+#define QUOTE(x) #x
+class CfgPatches {
+    version = QUOTE(1.2.3);
+};");
+
+    let dir = tempdir().unwrap();
+    let dump_path = dir.path().join("config.cpp.preprocessed");
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_rapify(&mut Cursor::new(input), &mut output, None, &Vec::new(), Some(dump_path.clone()), &armake2::preprocess::PreprocessOptions::default(), TargetVersion::default()).unwrap();
+
+    let dumped = read_to_string(dump_path).unwrap();
+    assert!(dumped.contains("version = \"1.2.3\";"));
+    assert!(!dumped.contains("QUOTE"));
+    assert_eq!(b"\0raP", &output[..4]);
+}
+
+#[test]
+fn config_unexpanded_macro() {
+    let input = String::from("\
+class CfgPatches {
+    class foo {
+        name = \"foo\";
+        version = VERSION_STR;
+    };
+};");
+
+    let error = Config::from_string(input, None, &Vec::new()).unwrap_err().to_string();
+
+    assert!(error.contains("In line 4:"));
+    assert!(error.contains("version = VERSION_STR;"));
+    assert!(error.contains("Unexpected identifier \"VERSION_STR\", did a macro fail to expand?"));
+
+    let error = Config::from_string(String::from("value = FORMAT(1, 2);"), None, &Vec::new()).unwrap_err().to_string();
+    assert!(error.contains("Unexpected identifier \"FORMAT(1, 2)\""));
+
+    // quoted, lowercase and numeric values are fine
+    let input = String::from("version = \"VERSION_STR\"; side = west; value = 1E3;");
+    let config = Config::from_string(input, None, &Vec::new()).unwrap();
+    let json = config.to_json().to_string();
+    assert!(json.contains("\"VERSION_STR\""));
+    assert!(json.contains("\"west\""));
+}
+
+#[test]
+fn config_derapify_indent() {
+    let input = String::from("class CfgPatches { class foo { units[] = {}; }; };");
+    let config = Config::read(&mut Cursor::new(input), None, &Vec::new()).unwrap();
+    let mut rapified = config.to_cursor().unwrap();
+
+    let mut output: Vec<u8> = Vec::new();
+    rapified.seek(SeekFrom::Start(0)).unwrap();
+    cmd_derapify(&mut rapified, &mut output, "  ", armake2::io::TextEncoding::Utf8).unwrap();
+    assert_eq!("class CfgPatches {\n  class foo {\n    units[] = {};\n  };\n};\n", String::from_utf8(output).unwrap());
+
+    let mut output: Vec<u8> = Vec::new();
+    rapified.seek(SeekFrom::Start(0)).unwrap();
+    cmd_derapify(&mut rapified, &mut output, "\t", armake2::io::TextEncoding::Utf8).unwrap();
+    assert_eq!("class CfgPatches {\n\tclass foo {\n\t\tunits[] = {};\n\t};\n};\n", String::from_utf8(output).unwrap());
+}
+
+#[test]
+fn config_array_expansion() {
+    let mut class = ConfigClass::new("");
+    class.add_entry("foo", ConfigEntry::ArrayEntry(ConfigArray::new(vec![ConfigArrayElement::IntElement(1)], true)));
+    let config = Config::new(class);
+
+    assert_eq!("foo[] += {1};\n", config.to_string().unwrap());
+
+    let rapified = config.to_cursor().unwrap().into_inner();
+    assert_eq!(&[5, 1, 0, 0, 0], &rapified[18..23]);
+
+    let mut cursor = Cursor::new(rapified);
+    let config = Config::read_rapified(&mut cursor).unwrap();
+    assert_eq!("foo[] += {1};\n", config.to_string().unwrap());
+
+    let mut array = ConfigArray::new(Vec::new(), true);
+    array.set_expansion(false);
+    assert!(!array.is_expansion());
+}
+
+#[test]
+fn config_entry_type() {
+    let input = String::from("\
+class CfgPatches {
+    class ace_frag {
+        units[] = {};
+        requiredVersion = 1.56;
+        versionStr = \"3.5.0.0\";
+    };
+    class ace_common;
+};");
+
+    let config = Config::read(&mut Cursor::new(input), None, &Vec::new()).unwrap();
+
+    assert_eq!(Some(ConfigEntryKind::String), config.entry_type(&["CfgPatches", "ace_frag", "versionStr"]));
+    assert_eq!(Some(ConfigEntryKind::Array), config.entry_type(&["CfgPatches", "ace_frag", "units"]));
+    assert_eq!(Some(ConfigEntryKind::Float), config.entry_type(&["cfgpatches", "ace_frag", "requiredVersion"]));
+    assert_eq!(Some(ConfigEntryKind::Class), config.entry_type(&["CfgPatches", "ace_frag"]));
+    assert_eq!(Some(ConfigEntryKind::External), config.entry_type(&["CfgPatches", "ace_common"]));
+    assert_eq!(None, config.entry_type(&["CfgPatches", "ace_frag", "author"]));
+}
+
+#[test]
+fn config_read_enums() {
+    let input = String::from("class CfgPatches { foo = 1; };");
+    let config = Config::read(&mut Cursor::new(input), None, &Vec::new()).unwrap();
+    assert!(config.enums().is_empty());
+
+    // replace the empty enum table with two constants
+    let mut rapified = config.to_cursor().unwrap().into_inner().to_vec();
+    let length = rapified.len();
+    rapified.truncate(length - 4);
+    rapified.extend_from_slice(b"\x02\0\0\0");
+    rapified.extend_from_slice(b"destructengine\0\x02\0\0\0");
+    rapified.extend_from_slice(b"stabilizedinaxisx\0\xff\xff\xff\xff");
+
+    let config = Config::read_rapified(&mut Cursor::new(rapified)).unwrap();
+    assert_eq!(&[("destructengine".to_string(), 2), ("stabilizedinaxisx".to_string(), -1)], config.enums());
+    assert_eq!("class CfgPatches {\n    foo = 1;\n};\n", config.to_string().unwrap());
+
+    // an enum table past the end of the file only results in a warning
+    let mut rapified = config.to_cursor().unwrap().into_inner().to_vec();
+    rapified[12..16].copy_from_slice(&0x10000u32.to_le_bytes());
+
+    let config = Config::read_rapified(&mut Cursor::new(rapified)).unwrap();
+    assert!(config.enums().is_empty());
+    assert_eq!("class CfgPatches {\n    foo = 1;\n};\n", config.to_string().unwrap());
+}
+
+#[test]
+fn config_tree_parents() {
+    let input = String::from("\
+class CfgVehicles {
+    class Car;
+    class A {};
+    class B: A {};
+    class C: B {
+        class Inner: A {};
+    };
+    class D: Car {};
+    class E: Missing {};
+};");
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_tree(&mut Cursor::new(input.clone()), &mut output, None, &Vec::new(), false).unwrap();
+    assert_eq!("\
+CfgVehicles
+    Car (external)
+    A
+    B : A
+    C : B
+        Inner : A
+    D : Car
+    E : Missing
+", String::from_utf8(output).unwrap());
+
+    let rapified = Config::from_string(input, None, &Vec::new()).unwrap().to_cursor().unwrap().into_inner();
+    let mut output: Vec<u8> = Vec::new();
+    cmd_tree(&mut Cursor::new(rapified), &mut output, None, &Vec::new(), true).unwrap();
+    assert_eq!("\
+CfgVehicles
+    Car (external)
+    A
+    B -> A
+    C -> B -> A
+        Inner -> A
+    D -> Car (external)
+    E -> Missing (unresolved)
+", String::from_utf8(output).unwrap());
+}
+
+#[test]
+fn config_target_version() {
+    let input = String::from("class CfgFoo { class Bar { items[] += {1}; }; };");
+
+    let mut output: Vec<u8> = Vec::new();
+    let options = armake2::preprocess::PreprocessOptions::default();
+    let error = cmd_rapify(&mut Cursor::new(input.clone()), &mut output, None, &Vec::new(), None, &options, TargetVersion::Arma1).unwrap_err();
+    assert!(error.to_string().contains("\"CfgFoo >> Bar >> items[] += ...\" is not supported by Arma1"));
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_rapify(&mut Cursor::new(input), &mut output, None, &Vec::new(), None, &options, TargetVersion::Arma2).unwrap();
+    assert_eq!(b"\0raP", &output[..4]);
+
+    assert_eq!(TargetVersion::Arma1, "ArmA1".parse().unwrap());
+    assert!("ofp".parse::<TargetVersion>().is_err());
+}
+
+#[test]
+fn config_keep_comments() {
+    let input = String::from("\
+// header comment
+class CfgPatches {
+    /* block comment
+       over two lines */
+    class foo {
+        units[] = {}; // inline comment
+        requiredVersion = 1.56; /* inline block */
+        // trailing comment
+    };
+};
+class Empty {
+    // only a comment
+};
+");
+
+    let options = armake2::preprocess::PreprocessOptions {
+        keep_comments: true,
+        ..Default::default()
+    };
+    let config = Config::read_with_options(&mut Cursor::new(input.clone()), None, &Vec::new(), &options).unwrap();
+
+    assert_eq!("\
+// header comment
+class CfgPatches {
+    /* block comment
+       over two lines */
+    class foo {
+        units[] = {}; // inline comment
+        requiredVersion = 1.56; /* inline block */
+        // trailing comment
+    };
+};
+class Empty {
+    // only a comment
+};
+", config.to_string().unwrap());
+
+    // comments are dropped by default and when rapifying
+    let config = Config::read(&mut Cursor::new(input), None, &Vec::new()).unwrap();
+    assert!(!config.to_string().unwrap().contains("//"));
+}
+
+#[test]
+fn config_get() {
+    let input = String::from("\
+class CfgPatches {
+    class foo {
+        units[] = {\"a\", {1, 2.5}};
+        requiredVersion = 1.56;
+        version = 3;
+        author = \"Nou\";
+    };
+};
+class Bar: foo {};");
+    let config = Config::read(&mut Cursor::new(input), None, &Vec::new()).unwrap();
+
+    assert_eq!(Some(3), config.get(&["CfgPatches", "foo", "version"]).and_then(|e| e.as_int()));
+    assert_eq!(Some(1.56), config.get(&["CfgPatches", "foo", "requiredVersion"]).and_then(|e| e.as_float()));
+    assert_eq!(Some("Nou"), config.get(&["cfgpatches", "FOO", "author"]).and_then(|e| e.as_string()));
+    assert_eq!(None, config.get(&["CfgPatches", "foo", "author"]).unwrap().as_int());
+    assert!(config.get(&["CfgPatches", "foo", "missing"]).is_none());
+    assert!(config.get(&["CfgPatches", "foo", "version", "nested"]).is_none());
+
+    let units = config.get(&["CfgPatches", "foo", "units"]).unwrap().as_array().unwrap();
+    match units.elements() {
+        [ConfigArrayElement::StringElement(s), ConfigArrayElement::ArrayElement(a)] => {
+            assert_eq!("a", s);
+            assert_eq!(2, a.elements().len());
+        },
+        _ => panic!("unexpected elements {:?}", units)
+    }
+
+    let class = config.get(&["CfgPatches", "foo"]).unwrap().as_class().unwrap();
+    let names: Vec<&str> = class.entries().iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(vec!["units", "requiredVersion", "version", "author"], names);
+    assert_eq!("foo", config.get(&["Bar"]).unwrap().as_class().unwrap().parent());
+}
+
+#[test]
+fn config_to_sqf() {
+    let input = String::from("\
+class CfgFoo {
+    class Bar {
+        name = \"Say \"\"hi\"\"\";
+        count = 3;
+        weight = 1.5;
+        items[] = {\"a\", {1, 2}};
+        class Inner {};
+        class External;
+        delete Removed;
+    };
+};");
+    let config = Config::read(&mut Cursor::new(input), None, &Vec::new()).unwrap();
+
+    assert_eq!(
+        "[[\"name\", \"Say \"\"hi\"\"\"], [\"count\", 3], [\"weight\", 1.5], [\"items\", [\"a\", [1, 2]]], [\"Inner\", []], [\"External\", []]]",
+        config.to_sqf(&["CfgFoo", "Bar"]).unwrap());
+    assert_eq!("[\"a\", [1, 2]]", config.to_sqf(&["cfgfoo", "bar", "items"]).unwrap());
+    assert!(config.to_sqf(&[]).unwrap().starts_with("[[\"CfgFoo\", [[\"Bar\", [[\"name\""));
+    assert!(config.to_sqf(&["CfgFoo", "Missing"]).is_none());
+}
+
+#[test]
+fn config_eval() {
+    let input = String::from("\
+class CfgFoo {
+    count = __EVAL(2 * (3 + 4) - 1);
+    half = __EVAL(5 / 2);
+    scale = __EVAL(max(1, 0.5 * 3, -2));
+    items[] = {__EVAL(min(4, 3)), __EVAL(1.5 + 1)};
+};");
+    let config = Config::read(&mut Cursor::new(input), None, &Vec::new()).unwrap();
+
+    assert_eq!(Some(13), config.get(&["CfgFoo", "count"]).and_then(|e| e.as_int()));
+    assert_eq!(Some(2.5), config.get(&["CfgFoo", "half"]).and_then(|e| e.as_float()));
+    assert_eq!(Some(1.5), config.get(&["CfgFoo", "scale"]).and_then(|e| e.as_float()));
+    assert!(config.to_string().unwrap().contains("items[] = {3, 2.5};"));
+
+    let input = String::from("class CfgFoo {\n    count = __EVAL(2 + (3 *));\n};");
+    let error = Config::read(&mut Cursor::new(input), None, &Vec::new()).unwrap_err().to_string();
+    assert!(error.contains("In line 2:"));
+    assert!(error.contains("Invalid __EVAL expression \"2 + (3 *)\"."));
+
+    for input in &["x = __EVAL(3000000000);", "x[] = {__EVAL(-2147483648 - 1)};", "x = __EVAL(100000000000000000000.0 * 10000000000000000000.0);"] {
+        let error = Config::read(&mut Cursor::new(input.to_string()), None, &Vec::new()).unwrap_err().to_string();
+        assert!(error.contains("is out of range."), "{}", error);
+    }
+    assert!(Config::read(&mut Cursor::new(String::from("x = __EVAL(-2147483647 - 1);")), None, &Vec::new()).is_ok());
+}
+
+#[test]
+fn config_from_preprocessed_without_origins() {
+    let (preprocessed, mut info) = armake2::preprocess::preprocess(String::from("class A {};\nx = __EVAL(3000000000);\n"), None, &Vec::new()).unwrap();
+    info.line_origins.clear();
+
+    // falls back to the line in the preprocessed input
+    let error = Config::from_preprocessed(&preprocessed, &info).unwrap_err().to_string();
+    assert!(error.contains("In line 2:"), "{}", error);
+}
+
+#[test]
+fn config_to_json() {
+    let input = String::from("\
+class CfgPatches {
+    class ace_frag {
+        units[] = {};
+        requiredVersion = 1.56;
+        requiredAddons[] = {\"ace_common\"};
+        versionAr[] = {3, 5, {\"a\", 0.5}};
+        items[] += {1};
+    };
+    class ace_common;
+    delete ace_old;
+    class ace_child: ace_frag {};
+};");
+    let config = Config::read(&mut Cursor::new(input), None, &Vec::new()).unwrap();
+
+    let expected = serde_json::json!({
+        "CfgPatches": {
+            "ace_frag": {
+                "units": [],
+                "requiredVersion": 1.56,
+                "requiredAddons": ["ace_common"],
+                "versionAr": [3, 5, ["a", 0.5]],
+                "items": {"$append": [1]}
+            },
+            "ace_common": {"$external": true},
+            "ace_old": {"$deletion": true},
+            "ace_child": {"$parent": "ace_frag"}
+        }
+    });
+    assert_eq!(expected, config.to_json());
+
+    // the rapified config gives the same structure
+    let rapified = config.to_cursor().unwrap().into_inner();
+    let mut output: Vec<u8> = Vec::new();
+    cmd_config2json(&mut Cursor::new(rapified), &mut output, None, &Vec::new()).unwrap();
+    assert_eq!(expected, serde_json::from_slice::<serde_json::Value>(&output).unwrap());
+
+    let input = String::from("class CfgFoo { count = 1; Count = 2; class Bar; class Bar { a = 1; }; class bar { b = 2; }; };");
+    let config = Config::read(&mut Cursor::new(input), None, &Vec::new()).unwrap();
+    assert_eq!(serde_json::json!({"CfgFoo": {"Count": 2, "bar": {"b": 2}}}), config.to_json());
+}
+
+#[test]
+fn config_empty_statement() {
+    armake2::error::set_muted_warnings(Some(std::collections::HashSet::new()));
+
+    let input = String::from("\
+class CfgFoo {
+    x = 1;;
+    ;
+    class Bar {};;
+};
+;");
+    let config = Config::read(&mut Cursor::new(input), None, &Vec::new()).unwrap();
+
+    assert_eq!("class CfgFoo {\n    x = 1;\n    class Bar {};\n};\n", config.to_string().unwrap());
+    assert!(armake2::error::warnings_raised().get("empty-statement").unwrap_or(&0) >= &4);
+}
+
+#[test]
+fn config_duplicate_class() {
+    armake2::error::set_muted_warnings(Some(std::collections::HashSet::new()));
+
+    let input = String::from("\
+class CfgVehicles {
+    class Car;
+    class Car {
+        items[] = {1};
+    };
+    class Car {
+        items[] += {2};
+    };
+    class Truck {};
+    delete Truck;
+    class Other {
+        class Car {};
+    };
+};");
+    let config = Config::read(&mut Cursor::new(input), None, &Vec::new()).unwrap();
+
+    assert_eq!(Some(&1), armake2::error::warnings_raised().get("duplicate-class"));
+    assert!(config.get(&["CfgVehicles", "Car", "items"]).unwrap().as_array().unwrap().is_expansion());
+}
+
+#[test]
+fn config_hex_integers() {
+    let hex = String::from("class CfgFoo { color = 0xFF00FF; flags = 0xFFFFFFFF; mask = 0X10; items[] = {0x7fffffff, -0x1, {0x80000000}}; };");
+    let decimal = String::from("class CfgFoo { color = 16711935; flags = -1; mask = 16; items[] = {2147483647, -1, {-2147483648}}; };");
+
+    let hex = Config::read(&mut Cursor::new(hex), None, &Vec::new()).unwrap();
+    let decimal = Config::read(&mut Cursor::new(decimal), None, &Vec::new()).unwrap();
+
+    assert_eq!(decimal.to_cursor().unwrap().into_inner(), hex.to_cursor().unwrap().into_inner());
+    assert_eq!(Some(-1), hex.get(&["CfgFoo", "flags"]).and_then(|e| e.as_int()));
+
+    let error = Config::read(&mut Cursor::new(String::from("x = 0x100000000;")), None, &Vec::new()).unwrap_err();
+    assert!(error.to_string().contains("Integer \"0x100000000\" is out of range."));
+    assert!(Config::read(&mut Cursor::new(String::from("x = 2147483648;")), None, &Vec::new()).is_err());
+
+    // no errors are left behind when the value is parsed as an unquoted string instead
+    let config = Config::read(&mut Cursor::new(String::from("x = 2147483648 m; y[] = {0x100000000 m};")), None, &Vec::new()).unwrap();
+    assert!(config.to_string().unwrap().contains("x = \"2147483648 m\";"));
+}
+
+#[test]
+fn config_class_map() {
+    let input = String::from("\
+#define BASE(name) class name { scope = 2; }
+
+class CfgVehicles {
+    BASE(Car);
+    class Truck: Car {
+        class Turrets;
+    };
+};");
+
+    let dir = tempdir().unwrap();
+    let map_path = dir.path().join("config.json");
+    let options = armake2::preprocess::PreprocessOptions {
+        class_map: Some(map_path.clone()),
+        ..Default::default()
+    };
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_rapify(&mut Cursor::new(input), &mut output, Some("config.cpp".into()), &Vec::new(), None, &options, TargetVersion::default()).unwrap();
+
+    let map: serde_json::Value = serde_json::from_str(&read_to_string(map_path).unwrap()).unwrap();
+    assert_eq!(serde_json::json!([
+        {"path": ["CfgVehicles"], "file": "config.cpp", "line": 3},
+        {"path": ["CfgVehicles", "Car"], "file": "config.cpp", "line": 4},
+        {"path": ["CfgVehicles", "Truck"], "file": "config.cpp", "line": 5}
+    ]), map);
+}
+
+#[test]
+fn config_remove() {
+    let input = String::from("\
+class CfgPatches {
+    class foo {
+        units[] = {};
+        class Debug { enabled = 1; };
+        version = 3;
+    };
+};
+class CfgDebug {};");
+    let mut config = Config::read(&mut Cursor::new(input), None, &Vec::new()).unwrap();
+
+    assert!(config.remove(&["cfgpatches", "FOO", "debug"]));
+    assert!(config.remove(&["CfgDebug"]));
+    assert!(!config.remove(&["CfgDebug"]));
+    assert!(!config.remove(&["CfgPatches", "foo", "version", "nested"]));
+    assert!(!config.remove(&[]));
+
+    let rapified = config.to_cursor().unwrap();
+    let derapified = Config::read_rapified(&mut Cursor::new(rapified.get_ref())).unwrap().to_string().unwrap();
+    assert!(!derapified.contains("Debug"));
+    assert!(derapified.contains("version = 3;"));
+
+    // comments of removed entries are removed as well
+    let input = String::from("\
+class CfgFoo {
+    // first
+    a = 1;
+    // second
+    b = 2;
+    c = 3; // third
+};
+");
+    let options = armake2::preprocess::PreprocessOptions { keep_comments: true, ..Default::default() };
+    let mut config = Config::read_with_options(&mut Cursor::new(input), None, &Vec::new(), &options).unwrap();
+    assert!(config.remove(&["CfgFoo", "b"]));
+    assert_eq!("\
+class CfgFoo {
+    // first
+    a = 1;
+    c = 3; // third
+};
+", config.to_string().unwrap());
+}
@@ -0,0 +1,23 @@
+use std::collections::{HashSet};
+use std::thread;
+
+use armake2::error::*;
+
+#[test]
+fn warnings_threaded() {
+    set_muted_warnings(Some(HashSet::new()));
+
+    let handles: Vec<_> = (0..8).map(|_| thread::spawn(|| {
+        for _ in 0..100 {
+            warning("threaded warning", Some("threaded-warning"), (None, None));
+        }
+    })).collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(Some(&800), warnings_raised().get("threaded-warning"));
+    assert!(warning_suppressed(Some("threaded-warning")));
+    assert!(!warning_suppressed(Some("other-warning")));
+}
@@ -0,0 +1,72 @@
+use std::fs::{read_dir, read_to_string};
+use std::io::{Cursor, ErrorKind, Write};
+
+use tempfile::{tempdir};
+
+use armake2::error;
+use armake2::io::*;
+
+#[test]
+fn test_write_atomically() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("output.bin");
+
+    let result = write_atomically(&path, false, |output| {
+        output.write_all(b"partial")?;
+        Err(error!("Simulated failure"))
+    });
+
+    assert!(result.is_err());
+    assert!(!path.exists());
+    assert_eq!(0, read_dir(dir.path()).unwrap().count());
+
+    write_atomically(&path, false, |output| output.write_all(b"complete")).unwrap();
+    assert_eq!("complete", read_to_string(&path).unwrap());
+    assert_eq!(1, read_dir(dir.path()).unwrap().count());
+}
+
+#[test]
+fn test_write_atomically_panic() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("output.bin");
+
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        write_atomically(&path, false, |output| {
+            output.write_all(b"partial")?;
+            panic!("Simulated panic");
+        })
+    }));
+
+    assert!(result.is_err());
+    assert_eq!(0, read_dir(dir.path()).unwrap().count());
+}
+
+#[test]
+#[cfg(unix)]
+fn test_write_atomically_non_utf8() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join(OsStr::from_bytes(b"output\xff.bin"));
+
+    write_atomically(&path, false, |output| output.write_all(b"complete")).unwrap();
+    assert_eq!("complete", read_to_string(&path).unwrap());
+    assert_eq!(1, read_dir(dir.path()).unwrap().count());
+}
+
+#[test]
+fn test_read_cstring() {
+    let mut input = Cursor::new(b"data\\script.sqf\0\xc3\xa4\0rest".to_vec());
+    assert_eq!("data\\script.sqf", input.read_cstring().unwrap());
+    assert_eq!("\u{e4}", input.read_cstring().unwrap());
+    assert_eq!("rest", input.read_cstring().unwrap());
+
+    let mut input = Cursor::new(b"caf\xe9.sqf\0next\0".to_vec());
+    let error = input.read_cstring().unwrap_err();
+    assert_eq!(ErrorKind::InvalidData, error.kind());
+    assert!(error.to_string().contains("caf\u{fffd}.sqf"));
+    assert_eq!("next", input.read_cstring().unwrap());
+}
@@ -0,0 +1,38 @@
+use armake2::lzo::*;
+
+#[test]
+fn test_lzo_decompress() {
+    // 3 literals, a match of 8 bytes at distance 3 followed by 1 literal, end of stream
+    let input = b"\x14abc\xe9\x00c\x11\x00\x00";
+    assert_eq!(b"abcabcabcabc".to_vec(), decompress(input, 12).unwrap());
+}
+
+#[test]
+fn test_lzo_decompress_mip() {
+    // 4x4 DXT1 mip with the first block repeated: 16 literals, a 48 byte match at distance 16
+    // with an extended length, end of stream
+    let block: &[u8] = b"\x00\xf8\x1f\x00\xaa\x55\xaa\x55\xe0\x07\x00\x1f\x00\xff\x00\xff";
+    let mut input: Vec<u8> = vec![13];
+    input.extend_from_slice(block);
+    input.extend_from_slice(b"\x20\x0f\x3c\x00\x11\x00\x00");
+
+    let expected: Vec<u8> = block.repeat(4);
+    assert_eq!(expected, decompress(&input, 64).unwrap());
+
+    // long literal run
+    let mut input: Vec<u8> = vec![0, 2];
+    input.extend_from_slice(&expected[..20]);
+    input.extend_from_slice(b"\x11\x00\x00");
+    assert_eq!(&expected[..20], &decompress(&input, 20).unwrap()[..]);
+}
+
+#[test]
+fn test_lzo_decompress_invalid() {
+    let input = b"\x14abc\xe9\x00c\x11\x00\x00";
+    assert!(decompress(input, 16).unwrap_err().to_string().contains("instead of the expected 16 bytes"));
+    assert!(decompress(input, 8).is_err());
+    assert!(decompress(&input[..6], 12).unwrap_err().to_string().contains("ended unexpectedly"));
+
+    // match before the start of the output
+    assert!(decompress(b"\x14abc\xe9\x01c\x11\x00\x00", 12).is_err());
+}
@@ -0,0 +1,215 @@
+use std::io::{Cursor};
+
+use armake2::p3d::*;
+
+fn odol_lod(textures: &[&str], materials: &[&str]) -> Vec<u8> {
+    let mut lod: Vec<u8> = Vec::new();
+
+    // one proxy, no skeleton
+    lod.extend_from_slice(&1u32.to_le_bytes());
+    lod.extend_from_slice(b"\\a3\\data_f\\proxies\\dummy\0");
+    lod.extend_from_slice(&[0; 12 * 4 + 4 * 4]);
+    lod.extend_from_slice(&[0; 2 * 4]);
+
+    // vertex count, face area, hints, bounding box and sphere
+    lod.extend_from_slice(&[0; 4 + 4 + 2 * 4 + 10 * 4]);
+
+    lod.extend_from_slice(&(textures.len() as u32).to_le_bytes());
+    for texture in textures {
+        lod.extend_from_slice(texture.as_bytes());
+        lod.push(0);
+    }
+
+    lod.extend_from_slice(&(materials.len() as u32).to_le_bytes());
+    for material in materials {
+        lod.extend_from_slice(material.as_bytes());
+        lod.push(0);
+        lod.extend_from_slice(&11u32.to_le_bytes());
+        lod.extend_from_slice(&[0; 6 * 4 * 4 + 4 + 4 * 4]);
+        lod.extend_from_slice(b"a3\\data_f\\default.bisurf\0");
+        lod.extend_from_slice(&[0; 2 * 4]);
+        // one stage, one texgen
+        lod.extend_from_slice(&1u32.to_le_bytes());
+        lod.extend_from_slice(&1u32.to_le_bytes());
+        lod.extend_from_slice(&[0; 4]);
+        lod.extend_from_slice(b"x\\foo\\addons\\bar\\data\\bar_nohq.paa\0");
+        lod.extend_from_slice(&[0; 4 + 1]);
+        lod.extend_from_slice(&[0; 4 + 12 * 4]);
+        // TI stage
+        lod.extend_from_slice(&[0; 4]);
+        lod.extend_from_slice(b"\0");
+        lod.extend_from_slice(&[0; 4 + 1]);
+    }
+
+    // faces etc. aren't read
+    lod.extend_from_slice(&[0xff; 16]);
+
+    lod
+}
+
+fn odol(lods: &[(f32, Vec<u8>)]) -> Vec<u8> {
+    let mut header: Vec<u8> = Vec::new();
+    header.extend_from_slice(b"ODOL");
+    header.extend_from_slice(&73u32.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(b"x\\foo\\addons\\bar\0");
+    header.extend_from_slice(&(lods.len() as u32).to_le_bytes());
+    for (resolution, _) in lods {
+        header.extend_from_slice(&resolution.to_le_bytes());
+    }
+
+    // model info
+    header.extend_from_slice(&[0; 6 * 4 + 3 * 4 + 3 * 4 + 2 * 3 * 4 + 2 * 4 + 2 * 3 * 4]);
+    header.extend_from_slice(&[0; 3 * 3 * 4 + 9 * 4 + 4 + 1]);
+    header.extend_from_slice(&[0; 4 * 4 + 2 * 4 + 1 + 4 + 1 + 4 + 1]);
+    // skeleton with one bone
+    header.extend_from_slice(b"OFP2_ManSkeleton\0\x01");
+    header.extend_from_slice(&1u32.to_le_bytes());
+    header.extend_from_slice(b"pelvis\0\0\0");
+    // map type, LZO compressed mass array of 4 points, masses and armor
+    header.push(0);
+    header.extend_from_slice(&4u32.to_le_bytes());
+    header.push(1);
+    header.push(17 + 16);
+    header.extend_from_slice(&[0x40; 16]);
+    header.extend_from_slice(b"\x11\0\0");
+    header.extend_from_slice(&[0; 5 * 4]);
+    // special LODs, minimum shadow, blend flag and properties
+    header.extend_from_slice(&[0xff; 14]);
+    header.extend_from_slice(&[0; 4 + 1]);
+    header.extend_from_slice(b"house\0\0");
+    header.extend_from_slice(&[0; 1 + 4]);
+    header.extend_from_slice(&vec![0; lods.len() * 3 * 4]);
+
+    // animations: a rotation and a hide animation, both on the only bone
+    header.push(1);
+    header.extend_from_slice(&2u32.to_le_bytes());
+    header.extend_from_slice(&1u32.to_le_bytes());
+    header.extend_from_slice(b"door\0door\0");
+    header.extend_from_slice(&[0; 7 * 4 + 2 * 4]);
+    header.extend_from_slice(&9u32.to_le_bytes());
+    header.extend_from_slice(b"hide\0damage\0");
+    header.extend_from_slice(&[0; 7 * 4 + 2 * 4]);
+    header.extend_from_slice(&(lods.len() as u32).to_le_bytes());
+    for _lod in lods {
+        header.extend_from_slice(&1u32.to_le_bytes());
+        header.extend_from_slice(&2u32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&1u32.to_le_bytes());
+    }
+    for _lod in lods {
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&[0; 2 * 3 * 4]);
+        header.extend_from_slice(&0u32.to_le_bytes());
+    }
+
+    // address table and default face data flags, then the LODs in reverse order
+    let mut address = header.len() + lods.len() * 9;
+    let mut addresses: Vec<(usize, usize)> = vec![(0, 0); lods.len()];
+    for (i, (_, lod)) in lods.iter().enumerate().rev() {
+        addresses[i] = (address, address + lod.len());
+        address += lod.len();
+    }
+
+    let mut data = header;
+    for (start, _) in &addresses {
+        data.extend_from_slice(&(*start as u32).to_le_bytes());
+    }
+    for (_, end) in &addresses {
+        data.extend_from_slice(&(*end as u32).to_le_bytes());
+    }
+    data.extend(vec![1; lods.len()]);
+    for (_, lod) in lods.iter().rev() {
+        data.extend_from_slice(lod);
+    }
+
+    data
+}
+
+#[test]
+fn test_p3d_read_odol() {
+    let lods = vec![
+        (1.0f32, odol_lod(&["x\\foo\\addons\\bar\\data\\bar_co.paa", "#(argb,8,8,3)color(1,0,0,1,CO)"], &["x\\foo\\addons\\bar\\data\\bar.rvmat"])),
+        (1e15f32, odol_lod(&[], &[])),
+    ];
+
+    let p3d = P3D::read(&mut Cursor::new(odol(&lods))).unwrap();
+
+    assert_eq!(73, p3d.version);
+    assert_eq!(2, p3d.lods.len());
+    assert_eq!(1.0, p3d.lods[0].resolution);
+    assert_eq!(1e15, p3d.lods[1].resolution);
+
+    let textures: Vec<&str> = p3d.lods[0].faces.iter().map(|f| f.texture.as_str()).filter(|t| !t.is_empty()).collect();
+    let materials: Vec<&str> = p3d.lods[0].faces.iter().map(|f| f.material.as_str()).filter(|m| !m.is_empty()).collect();
+    assert_eq!(vec!["x\\foo\\addons\\bar\\data\\bar_co.paa", "#(argb,8,8,3)color(1,0,0,1,CO)"], textures);
+    assert_eq!(vec!["x\\foo\\addons\\bar\\data\\bar.rvmat"], materials);
+    assert!(p3d.lods[1].faces.is_empty());
+}
+
+#[test]
+fn test_p3d_read_odol_invalid() {
+    let lods = vec![(1.0f32, odol_lod(&["x\\foo\\addons\\bar\\data\\bar_co.paa"], &[]))];
+    let data = odol(&lods);
+
+    // the LOD extends past the end of the file
+    let error = P3D::read(&mut Cursor::new(data[..data.len() - 8].to_vec())).unwrap_err();
+    assert!(error.to_string().contains("invalid address range"), "{}", error);
+
+    // a count that overflows 32 bits when multiplied with the element size
+    let mut lods = vec![(1.0f32, odol_lod(&[], &[]))];
+    lods[0].1[4 + 25 + 12 * 4 + 4 * 4..][..4].copy_from_slice(&0xffff_ffffu32.to_le_bytes());
+    let error = P3D::read(&mut Cursor::new(odol(&lods))).unwrap_err();
+    assert!(error.to_string().contains("Failed to read LOD 0"), "{}", error);
+
+    // unknown version
+    let mut data = odol(&lods);
+    data[4..8].copy_from_slice(&74u32.to_le_bytes());
+    assert!(P3D::read(&mut Cursor::new(data)).is_err());
+}
+
+#[test]
+fn test_p3d_read_unknown() {
+    assert!(P3D::read(&mut Cursor::new(b"ABCD\0\0\0\0".to_vec())).is_err());
+}
+
+#[test]
+fn test_p3d_info() {
+    let mut lods: Vec<LOD> = Vec::new();
+    for (resolution, dependencies) in &[
+        (1.0, vec![("x\\foo\\data\\b_co.paa", "x\\foo\\data\\b.rvmat"), ("x\\foo\\data\\a_co.paa", ""), ("#(argb,8,8,3)color(1,0,0,1,CO)", ""), ("x\\foo\\data\\a_co.paa", "")]),
+        (1e15, vec![("", "")]),
+    ] {
+        let mut lod = LOD {
+            version_major: 28,
+            version_minor: 256,
+            resolution: *resolution,
+            points: vec![Point::new()],
+            face_normals: Vec::new(),
+            faces: Vec::new(),
+            taggs: Default::default(),
+        };
+        for (texture, material) in dependencies {
+            let mut face = Face::new();
+            face.vertices = vec![Vertex::new(), Vertex::new(), Vertex::new()];
+            face.texture = texture.to_string();
+            face.material = material.to_string();
+            lod.faces.push(face);
+        }
+        lods.push(lod);
+    }
+
+    let mut input: Vec<u8> = Vec::new();
+    P3D { version: 257, lods }.write(&mut input).unwrap();
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_p3d_info(&mut Cursor::new(input), &mut output).unwrap();
+
+    assert_eq!("\
+LOD 1
+    texture x\\foo\\data\\a_co.paa
+    texture x\\foo\\data\\b_co.paa
+    material x\\foo\\data\\b.rvmat
+LOD 1000000000000000
+", String::from_utf8(output).unwrap());
+}
@@ -0,0 +1,1232 @@
+use std::collections::{HashSet};
+use std::fs::{File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use tempfile::{tempdir};
+
+use armake2::pbo::*;
+
+// Creates `dir/addon` containing a single script and returns its path
+fn addon_with_script(dir: &Path) -> PathBuf {
+    let addon = dir.join("addon");
+    std::fs::create_dir(&addon).unwrap();
+    File::create(addon.join("script.sqf")).unwrap().write_all(b"hint \"foo\";").unwrap();
+
+    addon
+}
+
+#[test]
+fn test_pbo_compress_by_extension() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir(&addon).unwrap();
+
+    File::create(addon.join("script.sqf")).unwrap().write_all("private _foo = 1;\n".repeat(100).as_bytes()).unwrap();
+    File::create(addon.join("texture.paa")).unwrap().write_all(&vec![0x42; 2048]).unwrap();
+
+    let mut pbo = PBO::from_directory(addon, false, &Vec::new(), &Vec::new()).unwrap();
+    pbo.compress = true;
+
+    let reread = PBO::read(&mut pbo.to_cursor().unwrap()).unwrap();
+
+    let script = reread.headers.iter().find(|h| h.filename == "script.sqf").unwrap();
+    assert_eq!(0x4370_7273, script.packing_method);
+    assert!(script.data_size < script.original_size);
+
+    let texture = reread.headers.iter().find(|h| h.filename == "texture.paa").unwrap();
+    assert_eq!(0, texture.packing_method);
+    assert_eq!(2048, texture.data_size);
+}
+
+#[test]
+fn test_pbo_build_report() {
+    armake2::error::set_muted_warnings(Some(HashSet::new()));
+
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir(&addon).unwrap();
+
+    File::create(addon.join("config.cpp")).unwrap().write_all(b"class CfgPatches { foo = bar; };").unwrap();
+    File::create(addon.join("script.sqf")).unwrap().write_all(b"hint \"foo\";").unwrap();
+    File::create(addon.join("other.sqf")).unwrap().write_all(b"hint \"bar\";").unwrap();
+
+    let report_path = dir.path().join("report.json");
+    let mut output: Vec<u8> = Vec::new();
+    let options = PackOptions { report: Some(report_path.clone()), ..Default::default() };
+    cmd_build(addon, &mut output, &Vec::new(), &Vec::new(), &Vec::new(), &options).unwrap();
+
+    let report: serde_json::Value = serde_json::from_reader(File::open(report_path).unwrap()).unwrap();
+    assert_eq!(3, report["files"]);
+    assert_eq!(2, report["extensions"]["sqf"]);
+    assert_eq!(1, report["extensions"]["bin"]);
+    assert!(report["warnings"]["unquoted-string"].as_u64().unwrap() >= 1);
+}
+
+#[test]
+fn test_pbo_inspect_plain() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir(&addon).unwrap();
+
+    File::create(addon.join("script.sqf")).unwrap().write_all(b"hint \"foo\";").unwrap();
+    File::create(addon.join("data.txt")).unwrap().write_all(b"foobar").unwrap();
+
+    let pbo = PBO::from_directory(addon, false, &Vec::new(), &Vec::new()).unwrap();
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_inspect(&mut pbo.to_cursor().unwrap(), &mut output, true).unwrap();
+
+    assert_eq!("data.txt\t0\t6\t6\nscript.sqf\t0\t11\t11\n", String::from_utf8(output).unwrap());
+}
+
+#[test]
+fn test_pbo_empty_prefix() {
+    armake2::error::set_muted_warnings(Some(HashSet::new()));
+
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("my_addon");
+    std::fs::create_dir(&addon).unwrap();
+
+    File::create(addon.join("$PBOPREFIX$")).unwrap();
+    File::create(addon.join("script.sqf")).unwrap().write_all(b"hint \"foo\";").unwrap();
+
+    let pbo = PBO::from_directory(addon, false, &Vec::new(), &Vec::new()).unwrap();
+
+    assert_eq!(Some(&"my_addon".to_string()), pbo.header_extensions.get("prefix"));
+}
+
+#[test]
+fn test_pbo_duplicate_header_extension() {
+    armake2::error::set_muted_warnings(Some(HashSet::new()));
+
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir(&addon).unwrap();
+
+    File::create(addon.join("script.sqf")).unwrap().write_all(b"hint \"foo\";").unwrap();
+
+    let headerext = vec!["a=1".to_string(), "a=2".to_string()];
+    let mut output: Vec<u8> = Vec::new();
+    cmd_pack(addon, &mut output, &headerext, &Vec::new(), &PackOptions::default()).unwrap();
+
+    assert!(armake2::error::warnings_raised().get("duplicate-header-extension").unwrap_or(&0) >= &1);
+
+    let pbo = PBO::read(&mut std::io::Cursor::new(output)).unwrap();
+    assert_eq!(Some(&"2".to_string()), pbo.header_extensions.get("a"));
+}
+
+#[test]
+fn test_pbo_missing_checksum() {
+    armake2::error::set_muted_warnings(Some(HashSet::new()));
+
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir(&addon).unwrap();
+
+    File::create(addon.join("script.sqf")).unwrap().write_all(b"hint \"foo\";").unwrap();
+
+    let pbo = PBO::from_directory(addon, false, &Vec::new(), &Vec::new()).unwrap();
+    let mut data = pbo.to_cursor().unwrap().into_inner().to_vec();
+    let len = data.len();
+    data.truncate(len - 20);
+
+    let pbo = PBO::read(&mut std::io::Cursor::new(data)).unwrap();
+
+    assert!(pbo.checksum.is_none());
+    assert_eq!(b"hint \"foo\";", &**pbo.files.get("script.sqf").unwrap().get_ref());
+}
+
+#[test]
+fn test_pbo_entry_offsets() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir(&addon).unwrap();
+
+    File::create(addon.join("a.sqf")).unwrap().write_all(b"hint \"a\";").unwrap();
+    File::create(addon.join("b.sqf")).unwrap().write_all(b"hint \"bbbb\";").unwrap();
+
+    let pbo = PBO::from_directory(addon, false, &Vec::new(), &Vec::new()).unwrap();
+    let mut cursor = pbo.to_cursor().unwrap();
+    cursor.set_position(0);
+
+    let offsets = PBO::entry_offsets(&mut cursor).unwrap();
+    assert_eq!(2, offsets.len());
+
+    let (name, offset, size) = &offsets[1];
+    assert_eq!("b.sqf", name);
+
+    let mut buffer = vec![0; *size as usize];
+    cursor.seek(SeekFrom::Start(*offset)).unwrap();
+    cursor.read_exact(&mut buffer).unwrap();
+    assert_eq!(b"hint \"bbbb\";", &buffer[..]);
+}
+
+#[test]
+fn test_pbo_read_compressed() {
+    let mut data: Vec<u8> = Vec::new();
+
+    // header extensions
+    data.extend_from_slice(b"\0sreV\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0prefix\0test\0\0");
+    // compressed entry: "abc" as literals followed by a pointer back 3 bytes with length 9
+    data.extend_from_slice(b"abc.txt\0srpC");
+    data.extend_from_slice(&[12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 10, 0, 0, 0]);
+    data.extend_from_slice(b"plain.txt\0");
+    data.extend_from_slice(&[0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0]);
+    data.extend_from_slice(&[0; 21]);
+    data.extend_from_slice(&[0x07, b'a', b'b', b'c', 0x03, 0x06, 0x98, 0x04, 0x00, 0x00]);
+    data.extend_from_slice(b"foo");
+    data.extend_from_slice(&[0; 21]);
+
+    let pbo = PBO::read(&mut std::io::Cursor::new(data)).unwrap();
+
+    assert_eq!(b"abcabcabcabc", &**pbo.files.get("abc.txt").unwrap().get_ref());
+    assert_eq!(b"foo", &**pbo.files.get("plain.txt").unwrap().get_ref());
+    assert_eq!(PackingMethod::Compressed, pbo.headers[0].method());
+}
+
+#[test]
+fn test_pbo_read_compressed_oversized() {
+    let mut data: Vec<u8> = Vec::new();
+
+    // the original size claims 4 GiB for 4 bytes of compressed data
+    data.extend_from_slice(b"\0sreV\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0prefix\0test\0\0");
+    data.extend_from_slice(b"abc.txt\0srpC");
+    data.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0]);
+    data.extend_from_slice(&[0; 21]);
+    data.extend_from_slice(&[0xff, b'a', b'b', b'c']);
+    data.extend_from_slice(&[0; 21]);
+
+    let error = PBO::read(&mut std::io::Cursor::new(data)).err().unwrap();
+    assert!(error.to_string().contains("ended unexpectedly"));
+
+    // a back reference has to point before the current position
+    let error = armake2::lzss::decompress(&[0x00, 0x00, 0x00], 10).unwrap_err();
+    assert!(error.to_string().contains("Invalid back reference"));
+}
+
+#[test]
+fn test_pbo_compress_round_trip() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir(&addon).unwrap();
+
+    let script = "params [\"_unit\"];\nif (alive _unit) then { hint \"alive\"; };\n".repeat(50);
+    let mut state: u32 = 0x1234_5678;
+    let random: Vec<u8> = (0..512).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as u8
+    }).collect();
+    File::create(addon.join("script.sqf")).unwrap().write_all(script.as_bytes()).unwrap();
+    File::create(addon.join("random.bin")).unwrap().write_all(&random).unwrap();
+
+    let mut pbo = PBO::from_directory(addon, false, &Vec::new(), &Vec::new()).unwrap();
+    pbo.compress = true;
+
+    let reread = PBO::read(&mut pbo.to_cursor().unwrap()).unwrap();
+
+    assert_eq!(script.as_bytes(), &**reread.files.get("script.sqf").unwrap().get_ref());
+    assert_eq!(&random[..], &**reread.files.get("random.bin").unwrap().get_ref());
+
+    let header = reread.headers.iter().find(|h| h.filename == "script.sqf").unwrap();
+    assert_eq!(PackingMethod::Compressed, header.method());
+    assert!(header.data_size < header.original_size);
+
+    let header = reread.headers.iter().find(|h| h.filename == "random.bin").unwrap();
+    assert_eq!(PackingMethod::Uncompressed, header.method());
+    assert_eq!(512, header.data_size);
+}
+
+#[test]
+fn test_pbo_timestamps() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir(&addon).unwrap();
+
+    File::create(addon.join("a.sqf")).unwrap().write_all(b"hint \"a\";").unwrap();
+    File::create(addon.join("b.sqf")).unwrap().write_all(b"hint \"b\";").unwrap();
+
+    let pbo = PBO::from_directory(addon.clone(), false, &Vec::new(), &Vec::new()).unwrap();
+    let mut cursor = pbo.to_cursor().unwrap();
+    cursor.set_position(0);
+
+    let reread = PBO::read(&mut cursor).unwrap();
+    assert_eq!(2, reread.headers.len());
+
+    for header in &reread.headers {
+        let modified = std::fs::metadata(addon.join(&header.filename)).unwrap().modified().unwrap();
+        let expected = modified.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64;
+
+        assert_ne!(0, header.timestamp);
+        assert!((i64::from(header.timestamp) - expected).abs() <= 2);
+    }
+}
+
+#[test]
+fn test_pbo_strip() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir_all(addon.join("data")).unwrap();
+
+    File::create(addon.join("script.sqf")).unwrap().write_all(b"hint \"foo\";").unwrap();
+    File::create(addon.join("data").join("texture_co.paa")).unwrap().write_all(&[0x42; 64]).unwrap();
+    File::create(addon.join("data").join("icon.PAA")).unwrap().write_all(&[0x42; 64]).unwrap();
+
+    let pbo = PBO::from_directory(addon, false, &Vec::new(), &Vec::new()).unwrap();
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_strip(&mut pbo.to_cursor().unwrap(), &mut output, &[".paa".to_string()]).unwrap();
+
+    let stripped = PBO::read(&mut std::io::Cursor::new(output)).unwrap();
+    assert_eq!(vec!["script.sqf"], stripped.files.keys().collect::<Vec<_>>());
+    assert_eq!(1, stripped.headers.len());
+    assert!(stripped.checksum.is_some());
+}
+
+#[test]
+fn test_pbo_read_checked() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir(&addon).unwrap();
+
+    File::create(addon.join("script.sqf")).unwrap().write_all(b"hint \"foo\";").unwrap();
+
+    let pbo = PBO::from_directory(addon, false, &Vec::new(), &Vec::new()).unwrap();
+    let mut buffer = pbo.to_cursor().unwrap().into_inner();
+
+    let reread = PBO::read_checked(&mut std::io::Cursor::new(buffer.clone())).unwrap();
+    assert_eq!(b"hint \"foo\";", &reread.files.get("script.sqf").unwrap().get_ref()[..]);
+
+    // flip a byte in the file data
+    let position = buffer.len() - 21 - 3;
+    buffer[position] ^= 0xff;
+
+    assert!(PBO::read(&mut std::io::Cursor::new(buffer.clone())).is_ok());
+
+    let error = PBO::read_checked(&mut std::io::Cursor::new(buffer)).err().unwrap();
+    assert!(error.to_string().contains("checksum mismatch"));
+}
+
+#[test]
+fn test_pbo_extract_file() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir(&addon).unwrap();
+
+    File::create(addon.join("a.sqf")).unwrap().write_all(b"hint \"a\";").unwrap();
+    File::create(addon.join("b.sqf")).unwrap().write_all("private _foo = 1;\n".repeat(100).as_bytes()).unwrap();
+    File::create(addon.join("c.sqf")).unwrap().write_all(b"hint \"c\";").unwrap();
+
+    let mut pbo = PBO::from_directory(addon, false, &Vec::new(), &Vec::new()).unwrap();
+    pbo.compress = true;
+    let mut cursor = pbo.to_cursor().unwrap();
+
+    let full = PBO::read(&mut cursor).unwrap();
+    cursor.set_position(0);
+    let extracted = PBO::extract_file(&mut cursor, "b.sqf").unwrap();
+    assert_eq!(&full.files.get("b.sqf").unwrap().get_ref()[..], &extracted[..]);
+
+    // everything after the requested file is never read
+    cursor.set_position(0);
+    let (_, offset, size) = PBO::entry_offsets(&mut cursor).unwrap().into_iter().find(|e| e.0 == "b.sqf").unwrap();
+    let mut truncated = cursor.into_inner();
+    truncated.truncate((offset + u64::from(size)) as usize);
+
+    let extracted = PBO::extract_file(&mut std::io::Cursor::new(truncated.clone()), "b.sqf").unwrap();
+    assert_eq!("private _foo = 1;\n".repeat(100).as_bytes(), &extracted[..]);
+
+    let error = PBO::extract_file(&mut std::io::Cursor::new(truncated), "d.sqf").unwrap_err();
+    assert!(error.to_string().contains("\"d.sqf\" not found"));
+}
+
+#[test]
+fn test_pbo_get_file() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir_all(addon.join("functions")).unwrap();
+
+    File::create(addon.join("config.bin")).unwrap().write_all(b"config").unwrap();
+    File::create(addon.join("functions").join("fn_Init.sqf")).unwrap().write_all(b"hint \"init\";").unwrap();
+
+    let pbo = PBO::from_directory(addon, false, &Vec::new(), &Vec::new()).unwrap();
+
+    assert_eq!(b"config", &pbo.get_file("Config.bin").unwrap().get_ref()[..]);
+    assert_eq!(b"hint \"init\";", &pbo.get_file("functions/fn_init.sqf").unwrap().get_ref()[..]);
+    assert_eq!(b"hint \"init\";", &pbo.get_file("FUNCTIONS\\FN_INIT.SQF").unwrap().get_ref()[..]);
+    assert!(pbo.get_file("functions\\fn_other.sqf").is_none());
+    assert!(pbo.files.get("Config.bin").is_none());
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_cat(&mut pbo.to_cursor().unwrap(), &mut output, "Functions/Fn_Init.sqf", "").unwrap();
+    assert_eq!(b"hint \"init\";", &output[..]);
+}
+
+#[test]
+fn test_pbo_get_config_value() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir(&addon).unwrap();
+
+    File::create(addon.join("config.cpp")).unwrap().write_all(b"\
+class CfgPatches {
+    class foo {
+        units[] = {};
+        version = \"1.2.3\";
+    };
+};").unwrap();
+
+    let pbo = PBO::from_directory(addon, true, &Vec::new(), &Vec::new()).unwrap();
+    let reread = PBO::read(&mut pbo.to_cursor().unwrap()).unwrap();
+
+    match reread.get_config_value(&["CfgPatches", "foo", "version"]) {
+        Some(armake2::config::ConfigEntry::StringEntry(version)) => assert_eq!("1.2.3", version),
+        entry => panic!("unexpected entry {:?}", entry)
+    }
+
+    assert!(reread.get_config_value(&["CfgPatches", "foo", "author"]).is_none());
+}
+
+#[test]
+fn test_pbo_unpack() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir_all(addon.join("data")).unwrap();
+
+    File::create(addon.join("$PBOPREFIX$")).unwrap().write_all(b"x\\foo\\addons\\bar").unwrap();
+    for i in 0..40 {
+        let content: Vec<u8> = (0..256 * 1024).map(|j| ((i * 7 + j) % 251) as u8).collect();
+        File::create(addon.join("data").join(format!("file{}.bin", i))).unwrap().write_all(&content).unwrap();
+    }
+    File::create(addon.join("script.sqf")).unwrap().write_all("private _foo = 1;\n".repeat(100).as_bytes()).unwrap();
+
+    let mut pbo = PBO::from_directory(addon, false, &Vec::new(), &Vec::new()).unwrap();
+    pbo.compress = true;
+    pbo.uncompressed_extensions = vec!["bin".to_string()];
+
+    let target = dir.path().join("unpacked");
+    cmd_unpack(&mut pbo.to_cursor().unwrap(), target.clone(), &[]).unwrap();
+
+    assert_eq!(40, std::fs::read_dir(target.join("data")).unwrap().count());
+    for i in 0..40 {
+        let content: Vec<u8> = (0..256 * 1024).map(|j| ((i * 7 + j) % 251) as u8).collect();
+        assert_eq!(content, std::fs::read(target.join("data").join(format!("file{}.bin", i))).unwrap());
+    }
+
+    assert_eq!("private _foo = 1;\n".repeat(100), std::fs::read_to_string(target.join("script.sqf")).unwrap());
+    assert_eq!("prefix=x\\foo\\addons\\bar\n", std::fs::read_to_string(target.join("$PBOPREFIX$")).unwrap());
+}
+
+#[test]
+fn test_pbo_p3do_names() {
+    let dir = tempdir().unwrap();
+    let addon = addon_with_script(dir.path());
+    File::create(addon.join("model.p3do")).unwrap().write_all(b"ODOL").unwrap();
+    File::create(addon.join("modelxp3do")).unwrap().write_all(b"ODOL").unwrap();
+
+    let pbo = PBO::from_directory(addon, false, &Vec::new(), &Vec::new()).unwrap();
+    assert!(pbo.files.contains_key("model.p3d"));
+    assert!(pbo.files.contains_key("modelxp3do"));
+}
+
+#[test]
+fn test_pbo_unpack_truncated() {
+    let dir = tempdir().unwrap();
+
+    for compress in &[false, true] {
+        let mut pbo = PBO::new();
+        pbo.compress = *compress;
+        pbo.add_file("script.sqf".to_string(), "private _foo = 1;\n".repeat(100).into_bytes());
+
+        // cut off the checksum and the end of the file data
+        let data = pbo.to_cursor().unwrap().into_inner();
+        let truncated = data[..data.len() - 21 - 3].to_vec();
+
+        let error = cmd_unpack(&mut std::io::Cursor::new(truncated), dir.path().join("unpacked"), &[]).unwrap_err();
+        assert!(error.to_string().contains("Unexpected end of PBO while reading script.sqf"));
+    }
+}
+
+#[test]
+fn test_pbo_case_insensitive_names() {
+    let mut pbo = PBO::new();
+    pbo.add_file("Config.bin".to_string(), b"config".to_vec());
+    pbo.add_file("Data\\Script.sqf".to_string(), b"hint \"foo\";".to_vec());
+
+    let reread = PBO::read(&mut pbo.to_cursor().unwrap()).unwrap();
+    assert_eq!(Some("Config.bin"), reread.stored_name("config.bin"));
+    assert_eq!(Some("Config.bin"), reread.stored_name("Config.bin"));
+    assert_eq!(b"config", &reread.get_file("config.bin").unwrap().get_ref()[..]);
+    assert_eq!(b"config", &PBO::extract_file(&mut pbo.to_cursor().unwrap(), "CONFIG.BIN").unwrap()[..]);
+
+    pbo.add_file("data/script.sqf".to_string(), b"hint \"bar\";".to_vec());
+    assert_eq!(2, pbo.files.len());
+    assert_eq!(b"hint \"bar\";", &pbo.get_file("Data\\Script.sqf").unwrap().get_ref()[..]);
+
+    assert!(pbo.remove_file("config.BIN"));
+    assert!(pbo.get_file("Config.bin").is_none());
+}
+
+#[test]
+fn test_pbo_from_scratch() {
+    let mut pbo = PBO::new();
+    pbo.set_header_extension("prefix", "x\\foo\\addons\\bar");
+    pbo.add_file("script.sqf".to_string(), b"hint \"foo\";".to_vec());
+    pbo.add_file("data\\other.sqf".to_string(), b"hint \"other\";".to_vec());
+    pbo.add_file("removed.sqf".to_string(), Vec::new());
+    pbo.add_file("script.sqf".to_string(), b"hint \"bar\";".to_vec());
+
+    assert!(pbo.remove_file("removed.sqf"));
+    assert!(!pbo.remove_file("removed.sqf"));
+
+    let reread = PBO::read_checked(&mut pbo.to_cursor().unwrap()).unwrap();
+
+    assert_eq!(Some(&"x\\foo\\addons\\bar".to_string()), reread.header_extensions.get("prefix"));
+    assert_eq!(2, reread.files.len());
+    assert_eq!(b"hint \"bar\";", &reread.files.get("script.sqf").unwrap().get_ref()[..]);
+    assert_eq!(b"hint \"other\";", &reread.files.get("data\\other.sqf").unwrap().get_ref()[..]);
+}
+
+#[test]
+fn test_pbo_check_prefix() {
+    let mut pbo = PBO::new();
+    pbo.set_header_extension("prefix", "x\\foo\\addons\\bar");
+    pbo.add_file("script.sqf".to_string(), b"hint \"foo\";".to_vec());
+    let buffer = pbo.to_cursor().unwrap().into_inner();
+
+    assert!(cmd_check(&mut std::io::Cursor::new(&buffer), None).is_ok());
+    assert!(cmd_check(&mut std::io::Cursor::new(&buffer), Some("x\\foo\\addons\\bar")).is_ok());
+    assert!(cmd_check(&mut std::io::Cursor::new(&buffer), Some("\\X\\Foo\\addons\\bar")).is_ok());
+
+    let error = cmd_check(&mut std::io::Cursor::new(&buffer), Some("x\\foo\\addons\\baz")).unwrap_err();
+    assert!(error.to_string().contains("doesn't match the expected prefix \"x\\foo\\addons\\baz\""));
+
+    let buffer = PBO::new().to_cursor().unwrap().into_inner();
+    assert!(cmd_check(&mut std::io::Cursor::new(&buffer), Some("x\\foo\\addons\\bar")).is_err());
+}
+
+#[test]
+fn test_pbo_garbage_header() {
+    armake2::error::set_muted_warnings(Some(HashSet::new()));
+
+    let mut pbo = PBO::new();
+    pbo.set_header_extension("prefix", "foo");
+    pbo.add_file("script.sqf".to_string(), b"hint \"foo\";".to_vec());
+    let mut buffer = pbo.to_cursor().unwrap().into_inner();
+
+    // insert a junk header in front of the first file header
+    let position = buffer.windows(11).position(|w| w == b"script.sqf\0").unwrap();
+    let mut junk: Vec<u8> = b"\x01\x7f\xff\x10junk\0".to_vec();
+    junk.extend_from_slice(&[0; 20]);
+    buffer.splice(position..position, junk);
+
+    let reread = PBO::read(&mut std::io::Cursor::new(&buffer)).unwrap();
+    assert_eq!(vec!["script.sqf"], reread.files.keys().collect::<Vec<_>>());
+    assert_eq!(b"hint \"foo\";", &reread.files.get("script.sqf").unwrap().get_ref()[..]);
+    assert!(armake2::error::warnings_raised().get("garbage-header").unwrap_or(&0) >= &1);
+
+    // truncated data is reported instead of failing with a generic error
+    let position = buffer.windows(11).position(|w| w == b"hint \"foo\";").unwrap();
+    let error = PBO::read(&mut std::io::Cursor::new(&buffer[..position + 4])).err().unwrap();
+    assert!(error.to_string().contains("Unexpected end of PBO while reading script.sqf"));
+}
+
+#[test]
+fn test_pbo_garbage_header_data() {
+    armake2::error::set_muted_warnings(Some(HashSet::new()));
+
+    let mut pbo = PBO::new();
+    pbo.set_header_extension("prefix", "foo");
+    pbo.add_file("a.sqf".to_string(), b"hint \"a\";".to_vec());
+    pbo.add_file("b.sqf".to_string(), b"hint \"b\";".to_vec());
+    let mut buffer = pbo.to_cursor().unwrap().into_inner();
+
+    // junk data in front of the data of a.sqf, belonging to the junk headers inserted below
+    let position = buffer.windows(9).position(|w| w == b"hint \"a\";").unwrap();
+    buffer.splice(position..position, b"JUNKJUNKJUNK".iter().cloned());
+
+    // a junk header with 5 bytes of data and a stray header extension header with 7, in front of
+    // the header of a.sqf
+    let position = buffer.windows(6).position(|w| w == b"a.sqf\0").unwrap();
+    let mut junk: Vec<u8> = b"\x01junk\0".to_vec();
+    junk.extend_from_slice(&[0; 16]);
+    junk.extend_from_slice(&5u32.to_le_bytes());
+    junk.extend_from_slice(b"\0");
+    junk.extend_from_slice(&0x5665_7273u32.to_le_bytes());
+    junk.extend_from_slice(&[0; 12]);
+    junk.extend_from_slice(&7u32.to_le_bytes());
+    junk.extend_from_slice(b"key\0value\0\0");
+    buffer.splice(position..position, junk);
+
+    let reread = PBO::read(&mut std::io::Cursor::new(&buffer)).unwrap();
+    assert_eq!(vec!["a.sqf", "b.sqf"], reread.files.keys().collect::<Vec<_>>());
+    assert_eq!(b"hint \"a\";", &reread.files["a.sqf"].get_ref()[..]);
+    assert_eq!(b"hint \"b\";", &reread.files["b.sqf"].get_ref()[..]);
+    assert_eq!(2, reread.headers.len());
+    assert_eq!(Some(&"foo".to_string()), reread.header_extensions.get("prefix"));
+    assert!(reread.header_extensions.get("key").is_none());
+
+    assert_eq!(b"hint \"b\";", &PBO::extract_file(&mut std::io::Cursor::new(&buffer), "b.sqf").unwrap()[..]);
+
+    let offsets = PBO::entry_offsets(&mut std::io::Cursor::new(&buffer)).unwrap();
+    assert_eq!(vec!["a.sqf", "b.sqf"], offsets.iter().map(|(n, _, _)| n.as_str()).collect::<Vec<_>>());
+    let (_, offset, size) = &offsets[0];
+    assert_eq!(b"hint \"a\";", &buffer[*offset as usize..(*offset + u64::from(*size)) as usize]);
+
+    let dir = tempdir().unwrap();
+    cmd_unpack(&mut std::io::Cursor::new(&buffer), dir.path().to_path_buf(), &[]).unwrap();
+    assert_eq!("hint \"b\";", std::fs::read_to_string(dir.path().join("b.sqf")).unwrap());
+
+    // a junk header claiming more data than there is
+    let position = buffer.windows(6).position(|w| w == b"a.sqf\0").unwrap();
+    let mut junk: Vec<u8> = b"\x02junk\0".to_vec();
+    junk.extend_from_slice(&[0; 16]);
+    junk.extend_from_slice(&0x1000_0000u32.to_le_bytes());
+    buffer.splice(position..position, junk);
+
+    let error = PBO::extract_file(&mut std::io::Cursor::new(&buffer), "b.sqf").err().unwrap();
+    assert!(error.to_string().contains("extends past the end of the PBO"));
+    let error = PBO::read(&mut std::io::Cursor::new(&buffer)).err().unwrap();
+    assert!(error.to_string().contains("Unexpected end of PBO while skipping the data of invalid header"));
+}
+
+#[test]
+fn test_pbo_get_text() {
+    let mut pbo = PBO::new();
+    pbo.add_file("functions\\fn_init.sqf".to_string(), b"\xef\xbb\xbfhint \"Caf\xc3\xa9\";".to_vec());
+    pbo.add_file("invalid.sqf".to_string(), b"hint \"\xff\";".to_vec());
+
+    assert_eq!("hint \"Café\";", pbo.get_text("functions/fn_init.sqf").unwrap());
+    assert_eq!("hint \"\u{fffd}\";", pbo.get_text("invalid.sqf").unwrap());
+    assert!(pbo.get_text("missing.sqf").is_none());
+}
+
+#[test]
+fn test_pbo_cat_glob() {
+    let mut pbo = PBO::new();
+    pbo.add_file("scripts\\b.sqf".to_string(), b"b".to_vec());
+    pbo.add_file("scripts\\A.sqf".to_string(), b"a".to_vec());
+    pbo.add_file("scripts\\c.hpp".to_string(), b"c".to_vec());
+    pbo.add_file("other.sqf".to_string(), b"other".to_vec());
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_cat(&mut pbo.to_cursor().unwrap(), &mut output, "scripts/*.sqf", "\n").unwrap();
+    assert_eq!(b"a\nb", &output[..]);
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_cat(&mut pbo.to_cursor().unwrap(), &mut output, "*.sqf", "").unwrap();
+    assert_eq!(b"otherab", &output[..]);
+
+    let mut output: Vec<u8> = Vec::new();
+    let error = cmd_cat(&mut pbo.to_cursor().unwrap(), &mut output, "data/*.paa", "").unwrap_err();
+    assert!(error.to_string().contains("No file matching \"data/*.paa\""));
+}
+
+#[test]
+fn test_pbo_cat_missing() {
+    let mut pbo = PBO::new();
+    pbo.add_file("config.cpp".to_string(), b"class CfgPatches {};".to_vec());
+    pbo.add_file("functions\\fn_init.sqf".to_string(), b"init".to_vec());
+    pbo.add_file("functions\\fn_exit.sqf".to_string(), b"exit".to_vec());
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_cat(&mut pbo.to_cursor().unwrap(), &mut output, "Functions/fn_init.sqf", "").unwrap();
+    assert_eq!(b"init", &output[..]);
+
+    let error = cmd_cat(&mut pbo.to_cursor().unwrap(), &mut Vec::new(), "functions\\fn_inti.sqf", "").unwrap_err();
+    assert!(error.to_string().ends_with("File \"functions\\fn_inti.sqf\" not found in PBO. Did you mean: functions\\fn_init.sqf, functions\\fn_exit.sqf?"));
+
+    let error = cmd_cat(&mut pbo.to_cursor().unwrap(), &mut Vec::new(), "fn_init.sqf", "").unwrap_err();
+    assert!(error.to_string().contains("Did you mean: functions\\fn_init.sqf?"));
+
+    let error = cmd_cat(&mut pbo.to_cursor().unwrap(), &mut Vec::new(), "mod.cpp", "").unwrap_err();
+    assert!(error.to_string().ends_with("File \"mod.cpp\" not found in PBO."));
+}
+
+#[test]
+fn test_pbo_cat_glob_headers() {
+    let mut pbo = PBO::new();
+    pbo.add_file("scripts\\b.sqf".to_string(), b"hint \"b\";\n".to_vec());
+    pbo.add_file("scripts\\a.sqf".to_string(), b"hint \"a\";".to_vec());
+    pbo.add_file("config.cpp".to_string(), b"class CfgPatches {};".to_vec());
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_cat_glob(&mut pbo.to_cursor().unwrap(), &mut output, "*.sqf").unwrap();
+    assert_eq!("==> scripts\\a.sqf <==\nhint \"a\";\n\n==> scripts\\b.sqf <==\nhint \"b\";\n", String::from_utf8(output).unwrap());
+
+    assert!(cmd_cat_glob(&mut pbo.to_cursor().unwrap(), &mut Vec::new(), "*.hpp").is_err());
+}
+
+#[test]
+fn test_pbo_header_extension_order() {
+    let keys: Vec<String> = (0..20).map(|i| format!("key{:02}", i)).rev().collect();
+
+    let mut outputs: Vec<Vec<u8>> = Vec::new();
+    for _ in 0..2 {
+        let mut pbo = PBO::new();
+        pbo.add_file("script.sqf".to_string(), b"hint \"foo\";".to_vec());
+
+        for key in &keys {
+            pbo.set_header_extension(key, "value");
+        }
+        pbo.set_header_extension("prefix", "foo");
+
+        outputs.push(pbo.to_cursor().unwrap().into_inner());
+    }
+
+    assert_eq!(outputs[0], outputs[1]);
+
+    // prefix first, then in insertion order
+    let expected: String = keys.iter().map(|k| format!("{}\0value\0", k)).collect();
+    let expected = format!("prefix\0foo\0{}\0", expected);
+    assert_eq!(expected.as_bytes(), &outputs[0][21..21 + expected.len()]);
+
+    // round trip
+    let reread = PBO::read(&mut std::io::Cursor::new(&outputs[0])).unwrap();
+    assert_eq!(outputs[0], reread.to_cursor().unwrap().into_inner());
+}
+
+#[test]
+fn test_pbo_modcpp() {
+    let mut pbo = PBO::new();
+    pbo.set_header_extension("prefix", "x\\foo\\addons\\bar");
+    pbo.set_header_extension("product", "Foo \"Mod\"");
+    pbo.set_header_extension("author", "Foo Team");
+    pbo.set_header_extension("version", "1.2.3");
+    pbo.add_file("script.sqf".to_string(), b"hint \"foo\";".to_vec());
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_modcpp(&mut pbo.to_cursor().unwrap(), &mut output).unwrap();
+
+    assert_eq!("name = \"Foo \"\"Mod\"\"\";\nauthor = \"Foo Team\";\nversion = \"1.2.3\";\n", String::from_utf8(output).unwrap());
+
+    let mut header_extensions = linked_hash_map::LinkedHashMap::new();
+    header_extensions.insert("prefix".to_string(), "x\\foo\\addons\\bar".to_string());
+    assert_eq!("name = \"x\\foo\\addons\\bar\";\n", mod_cpp(&header_extensions).to_string().unwrap());
+}
+
+#[test]
+fn test_pbo_file_order() {
+    let mut pbo = PBO::new();
+    pbo.add_file("b.sqf".to_string(), b"b".to_vec());
+    pbo.add_file("C.sqf".to_string(), b"c".to_vec());
+    pbo.add_file("a.sqf".to_string(), b"a".to_vec());
+
+    let reread = PBO::read(&mut pbo.to_cursor().unwrap()).unwrap();
+    let names: Vec<&str> = reread.headers.iter().map(|h| h.filename.as_str()).collect();
+    assert_eq!(vec!["a.sqf", "b.sqf", "C.sqf"], names);
+
+    pbo.file_order = FileOrder::Insertion;
+    let reread = PBO::read(&mut pbo.to_cursor().unwrap()).unwrap();
+    let names: Vec<&str> = reread.headers.iter().map(|h| h.filename.as_str()).collect();
+    assert_eq!(vec!["b.sqf", "C.sqf", "a.sqf"], names);
+    assert_eq!(b"a", &reread.files.get("a.sqf").unwrap().get_ref()[..]);
+}
+
+#[test]
+fn test_pbo_packing_method() {
+    for method in &[PackingMethod::Uncompressed, PackingMethod::Compressed, PackingMethod::Product, PackingMethod::Encrypted, PackingMethod::Unknown(42)] {
+        assert_eq!(*method, PackingMethod::from_u32(method.to_u32()));
+    }
+
+    assert_eq!(PackingMethod::Product, PackingMethod::from_u32(u32::from_le_bytes(*b"sreV")));
+    assert_eq!(PackingMethod::Compressed, PackingMethod::from_u32(u32::from_le_bytes(*b"srpC")));
+    assert_eq!(PackingMethod::Encrypted, PackingMethod::from_u32(u32::from_le_bytes(*b"rcnE")));
+    assert_eq!(PackingMethod::Unknown(0x1234), PackingMethod::from_u32(0x1234));
+    assert_eq!(0x1234, PackingMethod::Unknown(0x1234).to_u32());
+
+    assert_eq!("lzss", PackingMethod::Compressed.to_string());
+    assert_eq!("0x00001234", PackingMethod::Unknown(0x1234).to_string());
+
+    let mut pbo = PBO::new();
+    pbo.add_file("script.sqf".to_string(), "private _foo = 1;\n".repeat(100).into_bytes());
+    pbo.compress = true;
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_inspect(&mut pbo.to_cursor().unwrap(), &mut output, false).unwrap();
+    assert!(String::from_utf8(output).unwrap().lines().any(|l| l.starts_with("script.sqf") && l.contains(" lzss ")));
+}
+
+#[test]
+fn test_pbo_unpack_pack_header_extensions() {
+    let mut pbo = PBO::new();
+    pbo.set_header_extension("prefix", "x\\foo\\addons\\bar");
+    pbo.set_header_extension("version", "1.2.3");
+    pbo.set_header_extension("description", "a=b, c=d");
+    pbo.set_header_extension("empty", "");
+    pbo.set_header_extension("author", "Someone");
+    pbo.add_file("script.sqf".to_string(), b"hint \"foo\";".to_vec());
+
+    let dir = tempdir().unwrap();
+    let target = dir.path().join("unpacked");
+    cmd_unpack(&mut pbo.to_cursor().unwrap(), target.clone(), &[]).unwrap();
+
+    let mut repacked: Vec<u8> = Vec::new();
+    let options = PackOptions { omit_version_extension: true, ..Default::default() };
+    cmd_pack(target, &mut repacked, &Vec::new(), &Vec::new(), &options).unwrap();
+
+    let reread = PBO::read(&mut std::io::Cursor::new(repacked)).unwrap();
+    let original: Vec<(&String, &String)> = pbo.header_extensions.iter().collect();
+    let restored: Vec<(&String, &String)> = reread.header_extensions.iter().collect();
+    assert_eq!(original, restored);
+    assert_eq!(vec!["script.sqf"], reread.files.keys().collect::<Vec<_>>());
+}
+
+#[test]
+fn test_pbo_inspect_json() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir_all(&addon).unwrap();
+    File::create(addon.join("$PBOPREFIX$")).unwrap().write_all(b"x\\foo\\addons\\bar").unwrap();
+    File::create(addon.join("script.sqf")).unwrap().write_all(b"hint \"foo\";").unwrap();
+
+    let pbo = PBO::from_directory(addon, false, &Vec::new(), &Vec::new()).unwrap();
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_inspect_json(&mut pbo.to_cursor().unwrap(), &mut output).unwrap();
+    let result: serde_json::Value = serde_json::from_slice(&output).unwrap();
+
+    assert_eq!("x\\foo\\addons\\bar", result["header_extensions"]["prefix"]);
+    let file = &result["files"][0];
+    assert_eq!("script.sqf", file["filename"]);
+    assert!(file["timestamp"].as_u64().unwrap() > 0);
+    assert_eq!(0, file["reserved"]);
+    assert_eq!(11, file["original_size"]);
+    assert_eq!("none", file["method"]);
+
+    let mut pbo = PBO::new();
+    pbo.add_file("script.sqf".to_string(), "private _foo = 1;\n".repeat(100).into_bytes());
+    pbo.add_file("data.txt".to_string(), b"data".to_vec());
+    pbo.compress = true;
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_inspect_json(&mut pbo.to_cursor().unwrap(), &mut output).unwrap();
+    let result: serde_json::Value = serde_json::from_slice(&output).unwrap();
+
+    assert_eq!(2, result["files"].as_array().unwrap().len());
+    let file = result["files"].as_array().unwrap().iter().find(|f| f["filename"] == "script.sqf").unwrap();
+    assert_eq!("lzss", file["method"]);
+    assert!(file["data_size"].as_u64().unwrap() < 1800);
+}
+
+#[test]
+fn test_pbo_fail_on_empty_prefix() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir_all(&addon).unwrap();
+    File::create(addon.join("script.sqf")).unwrap().write_all(b"hint \"foo\";").unwrap();
+
+    let options = PackOptions { require_prefix: true, ..Default::default() };
+
+    let mut output: Vec<u8> = Vec::new();
+    let error = cmd_pack(addon.clone(), &mut output, &Vec::new(), &Vec::new(), &options).unwrap_err();
+    assert!(error.to_string().contains("No prefix set"));
+
+    // without the option the folder name is used
+    cmd_pack(addon.clone(), &mut output, &Vec::new(), &Vec::new(), &PackOptions::default()).unwrap();
+    assert!(PBO::from_directory_with_options(addon.clone(), false, &Vec::new(), &Vec::new(), &PackOptions::default()).unwrap().prefix_from_folder_name);
+
+    File::create(addon.join("$PBOPREFIX$")).unwrap().write_all(b"  \nversion=1").unwrap();
+    assert!(cmd_pack(addon.clone(), &mut output, &Vec::new(), &Vec::new(), &options).is_err());
+
+    cmd_pack(addon.clone(), &mut output, &["prefix=x\\foo".to_string()], &Vec::new(), &options).unwrap();
+
+    File::create(addon.join("$PBOPREFIX$")).unwrap().write_all(b"x\\foo\\addons\\bar\n").unwrap();
+    cmd_pack(addon.clone(), &mut output, &Vec::new(), &Vec::new(), &options).unwrap();
+    assert!(!PBO::from_directory_with_options(addon, false, &Vec::new(), &Vec::new(), &PackOptions::default()).unwrap().prefix_from_folder_name);
+}
+
+#[test]
+fn test_pbo_build_validate_configs() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir_all(addon.join("data")).unwrap();
+
+    File::create(addon.join("config.cpp")).unwrap().write_all(b"\
+class CfgPatches {
+    class foo {
+        units[] = {};
+        requiredAddons[] = {\"bar\", {1, 2.5, \"x\"}};
+        version = 1.2;
+    };
+};
+class Base;
+class CfgVehicles {
+    class Car;
+    delete Tank;
+    class foo_car: Car {
+        displayName = \"Foo\";
+        speeds[] += {-1, 0x10};
+        class Empty {};
+    };
+};").unwrap();
+    File::create(addon.join("data").join("material.rvmat")).unwrap().write_all(b"ambient[] = {1, 1, 1, 1};\nclass Stage1 { texture = \"foo.paa\"; };").unwrap();
+
+    let options = PackOptions { validate_configs: true, ..Default::default() };
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_build(addon, &mut output, &Vec::new(), &Vec::new(), &Vec::new(), &options).unwrap();
+
+    let pbo = PBO::read(&mut std::io::Cursor::new(output)).unwrap();
+    assert!(pbo.files.contains_key("config.bin"));
+}
+
+#[test]
+fn test_pbo_build_classifier() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir_all(&addon).unwrap();
+
+    File::create(addon.join("data.myext")).unwrap().write_all(b"class Foo { bar = 1; };").unwrap();
+    File::create(addon.join("material.rvmat")).unwrap().write_all(b"ambient[] = {1, 1, 1, 1};").unwrap();
+
+    let mut classifier = ExtensionClassifier::default();
+    classifier.set("myext", FileAction::Rapify);
+    let options = PackOptions { classifier, ..Default::default() };
+
+    let pbo = PBO::from_directory_with_options(addon.clone(), true, &Vec::new(), &Vec::new(), &options).unwrap();
+    assert!(pbo.files["data.myext"].get_ref().starts_with(b"\0raP"));
+    assert!(pbo.files["material.rvmat"].get_ref().starts_with(b"\0raP"));
+
+    // rules read from a config file override the defaults
+    let rules = dir.path().join("rules.cpp");
+    File::create(&rules).unwrap().write_all(b"rapify[] = {\"myext\"};\ncopy[] = {\".RVMAT\"};").unwrap();
+    let options = PackOptions { classifier: ExtensionClassifier::read(&rules).unwrap(), ..Default::default() };
+
+    let pbo = PBO::from_directory_with_options(addon, true, &Vec::new(), &Vec::new(), &options).unwrap();
+    assert!(pbo.files["data.myext"].get_ref().starts_with(b"\0raP"));
+    assert_eq!(&b"ambient[] = {1, 1, 1, 1};"[..], &pbo.files["material.rvmat"].get_ref()[..]);
+
+    File::create(&rules).unwrap().write_all(b"rapify = \"myext\";").unwrap();
+    assert!(ExtensionClassifier::read(&rules).is_err());
+}
+
+#[test]
+fn test_pbo_build_all_changed() {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    let dir = tempdir().unwrap();
+    let root = dir.path().join("addons");
+    let output = dir.path().join("release");
+    for name in &["foo", "bar"] {
+        std::fs::create_dir_all(root.join(name)).unwrap();
+        File::create(root.join(name).join("script.sqf")).unwrap().write_all(b"hint \"foo\";").unwrap();
+    }
+
+    let (built, _) = cmd_build_all(root.clone(), output.clone(), &Vec::new(), &Vec::new(), &Vec::new(), &PackOptions::default(), None).unwrap();
+    assert_eq!(vec![output.join("bar.pbo"), output.join("foo.pbo")], built);
+
+    let old = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    File::options().write(true).open(root.join("bar").join("script.sqf")).unwrap().set_modified(old).unwrap();
+    File::open(root.join("bar")).unwrap().set_modified(old).unwrap();
+    File::options().write(true).open(output.join("bar.pbo")).unwrap().set_modified(old + Duration::from_secs(60)).unwrap();
+    File::options().write(true).open(output.join("foo.pbo")).unwrap().set_modified(old + Duration::from_secs(60)).unwrap();
+
+    // only foo changed since its PBO was written
+    let (built, _) = cmd_build_all(root.clone(), output.clone(), &Vec::new(), &Vec::new(), &Vec::new(), &PackOptions::default(), None).unwrap();
+    assert_eq!(vec![output.join("foo.pbo")], built);
+    assert_eq!(old + Duration::from_secs(60), output.join("bar.pbo").metadata().unwrap().modified().unwrap());
+
+    let (built, _) = cmd_build_all(root.clone(), output.clone(), &Vec::new(), &Vec::new(), &Vec::new(), &PackOptions::default(), Some(SystemTime::now() + Duration::from_secs(3600))).unwrap();
+    assert!(built.is_empty());
+
+    let (built, _) = cmd_build_all(root.clone(), output.clone(), &Vec::new(), &Vec::new(), &Vec::new(), &PackOptions::default(), Some(old - Duration::from_secs(1))).unwrap();
+    assert_eq!(2, built.len());
+
+    // deleting a file only changes the folder it was in
+    File::options().write(true).open(root.join("bar").join("script.sqf")).unwrap().set_modified(old).unwrap();
+    std::fs::create_dir_all(root.join("bar").join("functions")).unwrap();
+    File::create(root.join("bar").join("functions").join("fn_foo.sqf")).unwrap().set_modified(old).unwrap();
+    File::open(root.join("bar").join("functions")).unwrap().set_modified(old).unwrap();
+    File::open(root.join("bar")).unwrap().set_modified(old).unwrap();
+    File::options().write(true).open(output.join("bar.pbo")).unwrap().set_modified(old + Duration::from_secs(60)).unwrap();
+
+    std::fs::remove_file(root.join("bar").join("functions").join("fn_foo.sqf")).unwrap();
+    let (built, _) = cmd_build_all(root, output.clone(), &Vec::new(), &Vec::new(), &Vec::new(), &PackOptions::default(), None).unwrap();
+    assert_eq!(vec![output.join("bar.pbo")], built);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_pbo_build_all_invalid_folder_name() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = tempdir().unwrap();
+    let root = dir.path().join("addons");
+    let output = dir.path().join("release");
+    for name in &[OsStr::new("foo"), OsStr::from_bytes(b"b\xffr")] {
+        std::fs::create_dir_all(root.join(name)).unwrap();
+        File::create(root.join(name).join("script.sqf")).unwrap().write_all(b"hint \"foo\";").unwrap();
+    }
+
+    let (built, _) = cmd_build_all(root, output.clone(), &Vec::new(), &Vec::new(), &Vec::new(), &PackOptions::default(), None).unwrap();
+    assert_eq!(vec![output.join("foo.pbo")], built);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_pbo_pack_symlinks() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir_all(&addon).unwrap();
+
+    File::create(addon.join("script.sqf")).unwrap().write_all(b"hint \"foo\";").unwrap();
+    File::create(dir.path().join("shared.sqf")).unwrap().write_all(b"hint \"shared\";").unwrap();
+    std::os::unix::fs::symlink(dir.path().join("shared.sqf"), addon.join("linked.sqf")).unwrap();
+
+    let pbo = PBO::from_directory_with_options(addon.clone(), false, &Vec::new(), &Vec::new(), &PackOptions::default()).unwrap();
+    assert_eq!(&b"hint \"shared\";"[..], &pbo.files["linked.sqf"].get_ref()[..]);
+
+    let options = PackOptions { symlinks: "skip".parse().unwrap(), ..Default::default() };
+    let pbo = PBO::from_directory_with_options(addon.clone(), false, &Vec::new(), &Vec::new(), &options).unwrap();
+    assert!(pbo.files.contains_key("script.sqf"));
+    assert!(!pbo.files.contains_key("linked.sqf"));
+
+    let options = PackOptions { symlinks: SymlinkMode::Error, ..Default::default() };
+    let error = PBO::from_directory_with_options(addon, false, &Vec::new(), &Vec::new(), &options).err().unwrap();
+    assert!(error.to_string().contains("linked.sqf\" is a symbolic link."));
+}
+
+#[test]
+fn test_pbo_build_profile() {
+    let dir = tempdir().unwrap();
+    let addon = addon_with_script(dir.path());
+    File::create(addon.join("config.cpp")).unwrap().write_all(b"class CfgPatches { class Foo {}; };").unwrap();
+
+    let profile = cmd_build(addon, &mut Vec::new(), &Vec::new(), &Vec::new(), &Vec::new(), &PackOptions::default()).unwrap();
+
+    let mut output: Vec<u8> = Vec::new();
+    profile.write(&mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+
+    let labels: Vec<&str> = output.lines().map(|l| l.rsplit_once(' ').map(|(a, _)| a).unwrap().trim()).collect();
+    assert_eq!(vec!["file walking", "preprocessing", "rapifying", "binarizing", "writing"], labels);
+    assert!(output.lines().all(|l| l.ends_with('s')));
+}
+
+#[test]
+fn test_pbo_exclude_globs() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir_all(addon.join("data").join("ui")).unwrap();
+
+    for name in &["a", "ab", "logo.psd", "script.sqf", "script.sqf.bak", "data/texture.psd", "data/ui/icon.psd", "data/ui/icon.paa", "data/x1.paa"] {
+        File::create(addon.join(name)).unwrap().write_all(b"data").unwrap();
+    }
+
+    let names = |excludes: &[&str]| -> Vec<String> {
+        let excludes: Vec<String> = excludes.iter().map(|e| e.to_string()).collect();
+        let pbo = PBO::from_directory(addon.clone(), false, &excludes, &Vec::new()).unwrap();
+        let mut names: Vec<String> = pbo.files.keys().cloned().collect();
+        names.sort();
+        names
+    };
+
+    // ** also matches files in the root
+    assert_eq!(vec!["a", "ab", "data\\ui\\icon.paa", "data\\x1.paa", "script.sqf", "script.sqf.bak"], names(&["**/*.psd"]));
+    assert_eq!(vec!["a", "ab", "data\\texture.psd", "data\\x1.paa", "logo.psd", "script.sqf", "script.sqf.bak"], names(&["data/**/icon.*"]));
+
+    // ? matches exactly one character, but no separator
+    assert_eq!(vec!["ab", "data\\texture.psd", "data\\ui\\icon.paa", "data\\ui\\icon.psd", "logo.psd", "script.sqf", "script.sqf.bak"], names(&["?", "data\\x?.paa"]));
+    assert_eq!(9, names(&["data?ui?icon.paa"]).len());
+
+    // leading and trailing *
+    assert_eq!(vec!["a", "ab", "data\\x1.paa", "logo.psd", "script.sqf"], names(&["*.bak", "*\\ui\\*", "data\\t*"]));
+    assert_eq!(vec!["a", "ab", "logo.psd"], names(&["script*", "*.paa", "*/*"]));
+
+    // short names and empty patterns
+    assert_eq!(8, names(&["", "a?", "a?b"]).len());
+    assert!(names(&["*"]).is_empty());
+}
+
+#[test]
+fn test_pbo_version_extension() {
+    let dir = tempdir().unwrap();
+    let addon = addon_with_script(dir.path());
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_build(addon.clone(), &mut output, &Vec::new(), &Vec::new(), &Vec::new(), &PackOptions::default()).unwrap();
+    let pbo = PBO::read(&mut std::io::Cursor::new(output)).unwrap();
+    assert_eq!(Some(&env!("CARGO_PKG_VERSION").to_string()), pbo.header_extensions.get("armake"));
+
+    // an explicitly given value is kept
+    let mut output: Vec<u8> = Vec::new();
+    cmd_pack(addon.clone(), &mut output, &["armake=custom".to_string()], &Vec::new(), &PackOptions::default()).unwrap();
+    let pbo = PBO::read(&mut std::io::Cursor::new(output)).unwrap();
+    assert_eq!(Some(&"custom".to_string()), pbo.header_extensions.get("armake"));
+
+    let mut output: Vec<u8> = Vec::new();
+    let options = PackOptions { omit_version_extension: true, ..Default::default() };
+    cmd_pack(addon, &mut output, &Vec::new(), &Vec::new(), &options).unwrap();
+    let pbo = PBO::read(&mut std::io::Cursor::new(output)).unwrap();
+    assert!(pbo.header_extensions.get("armake").is_none());
+}
+
+#[test]
+fn test_pbo_unpack_excludes() {
+    let mut pbo = PBO::new();
+    pbo.header_extensions.insert("prefix".to_string(), "x\\foo\\addons\\bar".to_string());
+    pbo.add_file("config.cpp".to_string(), b"class CfgPatches {};".to_vec());
+    pbo.add_file("data\\bar_co.paa".to_string(), b"texture".to_vec());
+    pbo.add_file("data\\bar.rvmat".to_string(), b"material".to_vec());
+    pbo.add_file("script.sqf".to_string(), b"hint \"foo\";".to_vec());
+
+    let dir = tempdir().unwrap();
+    let target = dir.path().join("unpacked");
+    cmd_unpack(&mut pbo.to_cursor().unwrap(), target.clone(), &["*.paa".to_string(), "script.sqf".to_string()]).unwrap();
+
+    assert!(!target.join("data").join("bar_co.paa").exists());
+    assert!(!target.join("script.sqf").exists());
+    assert_eq!("material", std::fs::read_to_string(target.join("data").join("bar.rvmat")).unwrap());
+    assert_eq!("class CfgPatches {};", std::fs::read_to_string(target.join("config.cpp")).unwrap());
+    assert_eq!("prefix=x\\foo\\addons\\bar\n", std::fs::read_to_string(target.join("$PBOPREFIX$")).unwrap());
+}
+
+#[test]
+fn test_pbo_reproducible() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir_all(addon.join("data")).unwrap();
+    File::create(addon.join("$PBOPREFIX$")).unwrap().write_all(b"x/foo/addons/bar").unwrap();
+    File::create(addon.join("script.sqf")).unwrap().write_all(b"hint \"foo\";").unwrap();
+    File::create(addon.join("data").join("bar.rvmat")).unwrap().write_all(b"material").unwrap();
+
+    let options = PackOptions { reproducible: true, compress: true, ..Default::default() };
+    let build = |headerext: &[String]| {
+        let mut output: Vec<u8> = Vec::new();
+        cmd_pack(addon.clone(), &mut output, headerext, &Vec::new(), &options).unwrap();
+        output
+    };
+
+    let first = build(&["b=1".to_string(), "a=2".to_string()]);
+
+    File::options().write(true).open(addon.join("script.sqf")).unwrap().set_modified(UNIX_EPOCH + Duration::from_secs(1_000_000)).unwrap();
+    let second = build(&["a=2".to_string(), "b=1".to_string()]);
+
+    assert_eq!(first, second);
+
+    let pbo = PBO::read(&mut std::io::Cursor::new(first)).unwrap();
+    assert!(pbo.headers.iter().all(|h| h.timestamp == 0));
+    assert_eq!(vec!["prefix", "a", "armake", "b"], pbo.header_extensions.keys().collect::<Vec<_>>());
+    assert_eq!(Some(&"x\\foo\\addons\\bar".to_string()), pbo.header_extensions.get("prefix"));
+    assert!(pbo.files.contains_key("data\\bar.rvmat"));
+}
+
+#[test]
+fn test_pbo_checksum() {
+    let mut pbo = PBO::new();
+    pbo.header_extensions.insert("prefix".to_string(), "x\\foo\\addons\\bar".to_string());
+    pbo.add_file("script.sqf".to_string(), "private _foo = 1;\n".repeat(100).into_bytes());
+    pbo.add_file("data\\bar.rvmat".to_string(), b"material".to_vec());
+    pbo.compress = true;
+
+    let written = pbo.to_cursor().unwrap().into_inner();
+    assert_eq!(&written[written.len() - 20..], &pbo.checksum()[..]);
+
+    let read = PBO::read_checked(&mut std::io::Cursor::new(&written)).unwrap();
+    assert_eq!(read.checksum, Some(pbo.checksum()));
+}
+
+#[test]
+fn test_pbo_inspect_ratio() {
+    let mut pbo = PBO::new();
+    pbo.add_file("script.sqf".to_string(), "private _foo = 1;\n".repeat(100).into_bytes());
+    pbo.add_file("texture.paa".to_string(), vec![0x42; 1000]);
+    pbo.compress = true;
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_inspect(&mut pbo.to_cursor().unwrap(), &mut output, false).unwrap();
+    let output = String::from_utf8(output).unwrap();
+
+    let script = output.lines().find(|l| l.starts_with("script.sqf")).unwrap();
+    let columns: Vec<&str> = script.split_whitespace().collect();
+    let data_size: u64 = columns[3].parse().unwrap();
+    assert_eq!("1800", columns[2]);
+    assert_eq!(format!("{}%", data_size * 100 / 1800), columns[4]);
+
+    // uncompressed entries have no ratio
+    let texture = output.lines().find(|l| l.starts_with("texture.paa")).unwrap();
+    assert_eq!(4, texture.split_whitespace().count());
+
+    let total = output.lines().last().unwrap();
+    let columns: Vec<&str> = total.split_whitespace().collect();
+    assert_eq!(vec!["Total".to_string(), "2800".to_string(), (data_size + 1000).to_string(), format!("{}%", (data_size + 1000) * 100 / 2800)], columns);
+}
+
+#[test]
+fn test_pbo_update() {
+    let mut pbo = PBO::new();
+    pbo.set_header_extension("prefix", "x\\foo\\addons\\bar");
+    pbo.set_header_extension("version", "1.2.3");
+    pbo.add_file("config.bin".to_string(), b"old config".to_vec());
+    pbo.add_file("script.sqf".to_string(), "private _foo = 1;\n".repeat(100).into_bytes());
+    pbo.add_file("data.txt".to_string(), b"data".to_vec());
+    pbo.compress = true;
+    let original = pbo.to_cursor().unwrap().into_inner();
+
+    let dir = tempdir().unwrap();
+    let config = dir.path().join("config.bin");
+    File::create(&config).unwrap().write_all(b"new config").unwrap();
+
+    let mut output: Vec<u8> = Vec::new();
+    let replacements = vec![format!("Config.bin={}", config.to_str().unwrap()), format!("data/config.bin={}", config.to_str().unwrap())];
+    cmd_update(&mut std::io::Cursor::new(&original), &mut output, &replacements, &["data.txt".to_string()]).unwrap();
+
+    let updated = PBO::read_checked(&mut std::io::Cursor::new(&output)).unwrap();
+    assert_eq!(pbo.header_extensions, updated.header_extensions);
+    assert_eq!(vec!["config.bin", "script.sqf", "data\\config.bin"], updated.files.keys().collect::<Vec<_>>());
+    assert_eq!(b"new config", &updated.get_file("config.bin").unwrap().get_ref()[..]);
+
+    // the compressed script is copied without recompressing it
+    let raw = |data: &[u8]| {
+        let (_, offset, size) = PBO::entry_offsets(&mut std::io::Cursor::new(data)).unwrap().into_iter().find(|(n, _, _)| n == "script.sqf").unwrap();
+        data[offset as usize..offset as usize + size as usize].to_vec()
+    };
+    assert_eq!(raw(&original), raw(&output));
+
+    let error = cmd_update(&mut std::io::Cursor::new(&original), &mut Vec::new(), &[], &["missing.sqf".to_string()]).unwrap_err();
+    assert!(error.to_string().contains("File \"missing.sqf\" not found in PBO."));
+}
+
+#[test]
+fn test_pbo_nested_nobin() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir_all(addon.join("rapified")).unwrap();
+    std::fs::create_dir_all(addon.join("raw").join("nested")).unwrap();
+    File::create(addon.join("$PBOPREFIX$")).unwrap().write_all(b"x\\foo\\addons\\bar").unwrap();
+    File::create(addon.join("raw").join("$NOBIN$")).unwrap();
+    for folder in &[addon.join("rapified"), addon.join("raw"), addon.join("raw").join("nested")] {
+        File::create(folder.join("config.cpp")).unwrap().write_all(b"class CfgPatches {};").unwrap();
+    }
+
+    let pbo = PBO::from_directory(addon, true, &Vec::new(), &Vec::new()).unwrap();
+
+    assert!(pbo.files.contains_key("rapified\\config.bin"));
+    assert!(!pbo.files.contains_key("rapified\\config.cpp"));
+    assert_eq!(b"class CfgPatches {};", &pbo.files["raw\\config.cpp"].get_ref()[..]);
+    assert_eq!(b"class CfgPatches {};", &pbo.files["raw\\nested\\config.cpp"].get_ref()[..]);
+    assert!(pbo.files.contains_key("raw\\$NOBIN$"));
+}
+
+#[test]
+fn test_pbo_header_extension_arguments() {
+    let dir = tempdir().unwrap();
+    let addon = addon_with_script(dir.path());
+
+    let headerext = vec!["version=1.2.3".to_string(), "description=a=b, c=d".to_string()];
+    let mut output: Vec<u8> = Vec::new();
+    cmd_pack(addon.clone(), &mut output, &headerext, &Vec::new(), &PackOptions::default()).unwrap();
+    let pbo = PBO::read(&mut std::io::Cursor::new(output)).unwrap();
+    assert_eq!(Some(&"1.2.3".to_string()), pbo.header_extensions.get("version"));
+    assert_eq!(Some(&"a=b, c=d".to_string()), pbo.header_extensions.get("description"));
+
+    for invalid in &["foo", "=bar"] {
+        let error = cmd_build(addon.clone(), &mut Vec::new(), &[invalid.to_string()], &Vec::new(), &Vec::new(), &PackOptions::default()).unwrap_err();
+        assert_eq!(format!("Invalid header extension \"{}\", expected \"key=value\".", invalid), error.to_string());
+    }
+}
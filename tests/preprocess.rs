@@ -104,6 +104,14 @@ fn test_proprocess_bom() {
     assert_eq!("blub", output.trim());
 }
 
+#[test]
+fn test_preprocess_short_input() {
+    for input in &["", "a", "é"] {
+        let (output, _) = preprocess(input.to_string(), None, &Vec::new()).unwrap();
+        assert_eq!(*input, output.trim());
+    }
+}
+
 #[test]
 fn test_preprocess_lineorigins() {
     let input = String::from("\
@@ -122,3 +130,392 @@ class test\\
     assert_eq!(5, info.line_origins.len());
     assert_eq!(8, info.line_origins[2].0);
 }
+
+#[test]
+fn test_preprocess_include_root() {
+    let dir = tempdir().unwrap();
+    let root = dir.path().join("root");
+    create_dir(&root).unwrap();
+
+    File::create(dir.path().join("secret.h")).unwrap().write_all(b"secret\n").unwrap();
+    File::create(root.join("allowed.h")).unwrap().write_all(b"allowed\n").unwrap();
+    File::create(root.join("main.cpp")).unwrap();
+
+    let options = PreprocessOptions {
+        include_roots: vec![root.clone()],
+        ..Default::default()
+    };
+
+    let input = String::from("#include \"allowed.h\"\n");
+    let (output, _) = preprocess_with_options(input, Some(root.join("main.cpp")), &Vec::new(), &options).unwrap();
+    assert_eq!("allowed", output.trim());
+
+    let input = String::from("#include \"..\\secret.h\"\n");
+    let result = preprocess_with_options(input, Some(root.join("main.cpp")), &Vec::new(), &options);
+    assert!(result.unwrap_err().to_string().contains("outside of the include roots"));
+}
+
+#[test]
+fn test_preprocess_unknown_directive() {
+    armake2::error::set_muted_warnings(Some(std::collections::HashSet::new()));
+
+    let input = String::from("#foobar baz\nx = 1;\n");
+
+    // passed through as a regular line without the option
+    let (output, info) = preprocess(input.clone(), None, &Vec::new()).unwrap();
+    assert_eq!("foobar baz\nx = 1;", output.trim());
+    assert_eq!(vec![1, 2], info.line_origins.iter().take(2).map(|o| o.0).collect::<Vec<u32>>());
+
+    let options = PreprocessOptions {
+        allow_unknown_directives: true,
+        ..Default::default()
+    };
+
+    let (output, info) = preprocess_with_options(input, None, &Vec::new(), &options).unwrap();
+    assert_eq!("x = 1;", output.trim());
+    assert_eq!(2, info.line_origins[0].0);
+}
+
+#[test]
+fn test_preprocess_optional_include() {
+    armake2::error::set_muted_warnings(Some(std::collections::HashSet::new()));
+
+    let options = PreprocessOptions {
+        optional_includes: vec!["\\x\\cba\\addons\\*".to_string()],
+        ..Default::default()
+    };
+
+    let input = String::from("#include \"\\x\\cba\\addons\\compat\\script_compat.hpp\"\nx = 1;\n");
+    let (output, _) = preprocess_with_options(input, None, &Vec::new(), &options).unwrap();
+    assert_eq!("x = 1;", output.trim());
+
+    let input = String::from("#include \"\\x\\ace\\addons\\main\\script_macros.hpp\"\nx = 1;\n");
+    assert!(preprocess_with_options(input, None, &Vec::new(), &options).is_err());
+}
+
+#[test]
+fn test_preprocess_encoding() {
+    let input: &[u8] = b"#define NAME \"Caf\xe9\"\nname = NAME;\n";
+
+    let options = PreprocessOptions {
+        encoding: armake2::io::TextEncoding::Cp1252,
+        ..Default::default()
+    };
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_preprocess(&mut std::io::Cursor::new(input), &mut output, None, &Vec::new(), &options).unwrap();
+    let end = output.iter().rposition(|b| *b != b'\n').unwrap() + 1;
+    assert_eq!(&b"name = \"Caf\xe9\";"[..], &output[..end]);
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_preprocess(&mut std::io::Cursor::new(input), &mut output, None, &Vec::new(), &PreprocessOptions::default()).unwrap();
+    assert_eq!("name = \"Café\";", String::from_utf8(output).unwrap().trim());
+}
+
+#[test]
+fn test_preprocess_newline() {
+    let input: &[u8] = b"#define FOO 1\r\nfoo = FOO;\r\nbar = 2;\nbaz = 3;\r\n";
+
+    for (newline, expected) in &[(armake2::io::Newline::Lf, "\n"), (armake2::io::Newline::Crlf, "\r\n")] {
+        let options = PreprocessOptions {
+            newline: Some(*newline),
+            ..Default::default()
+        };
+
+        let mut output: Vec<u8> = Vec::new();
+        cmd_preprocess(&mut std::io::Cursor::new(input), &mut output, None, &Vec::new(), &options).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.contains(&format!("foo = 1;{}bar = 2;{}baz = 3;", expected, expected)));
+        assert_eq!(output.matches('\n').count(), output.matches(*expected).count());
+    }
+}
+
+#[test]
+fn test_preprocess_multiline_define() {
+    // parameter list continued over several lines
+    let input = String::from("#define ADD(a, \\\n    b) a + b\nx = ADD(1, 2);\n");
+    let (output, info) = preprocess(input, None, &Vec::new()).unwrap();
+    assert_eq!("x = 1 + 2;", output.trim());
+    assert_eq!(3, info.line_origins[0].0);
+
+    // whitespace after the continuation backslash
+    let input = String::from("#define CLASS(name) \\ \n    class name { \\\t\n        x = 1; \\\n    }\nCLASS(foo);\ny = 2;\n");
+    let (output, info) = preprocess(input, None, &Vec::new()).unwrap();
+    assert_eq!("class foo {         x = 1;     };\ny = 2;", output.trim());
+    assert_eq!(5, info.line_origins[0].0);
+    assert_eq!(6, info.line_origins[1].0);
+
+    // comments spanning lines in a definition are only counted once
+    let input = String::from("#define FOO 1 /* a\nb */\nx = FOO;\n");
+    let (_, info) = preprocess(input, None, &Vec::new()).unwrap();
+    assert_eq!(3, info.line_origins[0].0);
+}
+
+#[test]
+fn test_preprocess_keep_comments() {
+    let options = PreprocessOptions {
+        keep_comments: true,
+        ..Default::default()
+    };
+
+    let input = String::from("#define FOO 1 // not kept\n/* a\nb */ x = FOO; // c\ny = 2;\n");
+    let (output, info) = preprocess_with_options(input, None, &Vec::new(), &options).unwrap();
+    assert_eq!("/* a\nb */ x = 1 ; // c\ny = 2;", output.trim());
+    assert_eq!(2, info.line_origins[0].0);
+    assert_eq!(3, info.line_origins[1].0);
+    assert_eq!(4, info.line_origins[2].0);
+}
+
+#[test]
+fn test_preprocess_line_file() {
+    let dir = tempdir().unwrap();
+    let main = dir.path().join("main.sqf");
+    let header = dir.path().join("header.hpp");
+
+    File::create(&header).unwrap().write_all(b"#define LOG(x) [x, __FILE__, __LINE__]\nheader = __LINE__;\n").unwrap();
+
+    let input = String::from("#include \"header.hpp\"\n\nLOG(1);\nfile = __FILE__;\n");
+    File::create(&main).unwrap().write_all(input.as_bytes()).unwrap();
+    let (output, _) = preprocess(input, Some(main.clone()), &Vec::new()).unwrap();
+
+    let lines: Vec<&str> = output.lines().filter(|l| !l.is_empty()).collect();
+    assert_eq!("header = 2;", lines[0]);
+    assert_eq!(format!("[1, \"{}\", 3];", main.to_str().unwrap()), lines[1]);
+    assert_eq!(format!("file = \"{}\";", main.to_str().unwrap()), lines[2]);
+}
+
+#[test]
+fn test_preprocess_line_after_skipped_lines() {
+    let input = String::from("\
+#ifdef UNDEFINED
+    a = 1;
+    b = 2;
+#endif
+line = __LINE__;
+");
+
+    let (output, info) = preprocess(input, None, &Vec::new()).unwrap();
+    let index = output.lines().position(|l| l == "line = 5;").unwrap();
+    assert_eq!(5, info.line_origins[index].0);
+}
+
+#[test]
+fn test_preprocess_if() {
+    let input = String::from("\
+#define __ARMA_VERSION__ 200
+#if __ARMA_VERSION__ >= 200 && (1 + 2 * 3) == 7
+    a = 1;
+#else
+    a = 2;
+#endif
+#if 0
+    b = 1;
+#elif UNDEFINED_VERSION > 1
+    b = 2;
+#elif __ARMA_VERSION__ % 3 == 2
+    b = 3;
+#elif 1
+    b = 4;
+#else
+    b = 5;
+#endif
+c = 1;
+");
+
+    let (output, _) = preprocess(input, None, &Vec::new()).unwrap();
+    let lines: Vec<&str> = output.lines().map(|l| l.trim()).filter(|l| !l.is_empty()).collect();
+    assert_eq!(vec!["a = 1;", "b = 3;", "c = 1;"], lines);
+}
+
+#[test]
+fn test_preprocess_if_defined() {
+    let input = String::from("\
+#define FOO
+#if defined(FOO) && !defined BAR
+    #if defined(BAR) || 0
+        a = 1;
+    #elif 1
+        a = 2;
+    #endif
+#elif 1
+    a = 3;
+#endif
+#if !defined(FOO)
+    b = 1;
+#elif defined (BAR)
+    b = 2;
+#else
+    b = 3;
+#endif
+");
+
+    let (output, _) = preprocess(input, None, &Vec::new()).unwrap();
+    let lines: Vec<&str> = output.lines().map(|l| l.trim()).filter(|l| !l.is_empty()).collect();
+    assert_eq!(vec!["a = 2;", "b = 3;"], lines);
+
+    let input = String::from("#if 1 +\nx = 1;\n#endif\n");
+    assert!(preprocess(input, None, &Vec::new()).is_err());
+}
+
+#[test]
+fn test_preprocess_pragma_once() {
+    let dir = tempdir().unwrap();
+    File::create(dir.path().join("once.hpp")).unwrap().write_all(b"#pragma once\nonce = 1;\n").unwrap();
+    File::create(dir.path().join("twice.hpp")).unwrap().write_all(b"twice = 1;\n").unwrap();
+
+    let main = dir.path().join("main.hpp");
+    let input = String::from("#include \"once.hpp\"\n#include \"twice.hpp\"\n#include \"once.hpp\"\n#include \"twice.hpp\"\n");
+    File::create(&main).unwrap().write_all(input.as_bytes()).unwrap();
+
+    let (output, _) = preprocess(input, Some(main), &Vec::new()).unwrap();
+    assert_eq!(1, output.matches("once = 1;").count());
+    assert_eq!(2, output.matches("twice = 1;").count());
+}
+
+#[test]
+fn test_preprocess_include_cycle() {
+    let dir = tempdir().unwrap();
+    File::create(dir.path().join("a.hpp")).unwrap().write_all(b"#include \"b.hpp\"\n").unwrap();
+    File::create(dir.path().join("b.hpp")).unwrap().write_all(b"#include \"a.hpp\"\n").unwrap();
+
+    let main = dir.path().join("main.hpp");
+    let input = String::from("#include \"a.hpp\"\n");
+    File::create(&main).unwrap().write_all(input.as_bytes()).unwrap();
+
+    let error = preprocess(input, Some(main), &Vec::new()).unwrap_err().to_string();
+    assert!(error.contains("Include cycle detected"));
+    assert!(error.contains("a.hpp -> "));
+}
+
+#[test]
+fn test_preprocess_variadic() {
+    let input = String::from("\
+#define LOG(level, fmt, ...) diag_log format [fmt, level, __VA_ARGS__]
+#define ARR(...) [__VA_ARGS__]
+#define QUOTE(x) #x
+#define MESSAGE(...) QUOTE(__VA_ARGS__)
+LOG(1, \"%1 %2 %3\", _foo, _bar);
+LOG(2, \"%1\");
+a = ARR();
+b = ARR(1, (2, 3), 4);
+c = MESSAGE(hello);
+d = ARR;
+");
+
+    let (output, _) = preprocess(input, None, &Vec::new()).unwrap();
+    let lines: Vec<&str> = output.lines().filter(|l| !l.is_empty()).collect();
+    assert_eq!(vec![
+        "diag_log format [\"%1 %2 %3\", 1, _foo,_bar];",
+        "diag_log format [\"%1\", 2, ];",
+        "a = [];",
+        "b = [1,(2, 3),4];",
+        "c = \"hello\";",
+        "d = ARR;",
+    ], lines);
+}
+
+#[test]
+fn test_preprocess_redefinition_warning() {
+    armake2::error::set_muted_warnings(Some(std::collections::HashSet::new()));
+
+    let input = String::from("\
+#define FOO 1
+#define FOO  1 // same value
+#define BAR(x) x
+#define BAR(x) x /* same value */
+#define FOO 2
+x = FOO;
+");
+
+    let (output, _) = preprocess(input, None, &Vec::new()).unwrap();
+    assert_eq!("x = 2;", output.trim());
+    assert_eq!(Some(&1), armake2::error::warnings_raised().get("macro-redefinition"));
+}
+
+#[test]
+fn test_preprocess_skipped_macros() {
+    // lines in false conditionals are dropped without expanding them, so the arguments to FOO
+    // that can't be parsed don't matter and __COUNTER__ isn't advanced
+    let input = String::from("\
+#define FOO(a, b) a
+#define UNIQUE(x) x##_##__COUNTER__
+#ifdef UNDEFINED
+x = FOO(\"a,b\");
+y = UNIQUE(var);
+#endif
+#if 0
+z = __COUNTER__;
+#else
+z = UNIQUE(var);
+#endif
+");
+
+    let (output, _) = preprocess(input, None, &Vec::new()).unwrap();
+    assert_eq!("z = var_0;", output.trim());
+}
+
+#[test]
+fn test_preprocess_counter() {
+    let input = String::from("\
+#define UNIQUE(x) x##_##__COUNTER__
+a = __COUNTER__;
+b = __COUNTER__;
+c = UNIQUE(var);
+#ifdef UNDEFINED
+d = __COUNTER__;
+#endif
+e = __COUNTER__;
+");
+
+    let (output, _) = preprocess(input.clone(), None, &Vec::new()).unwrap();
+    assert_eq!("a = 0;\nb = 1;\nc = var_2;\ne = 3;", output.trim());
+
+    // every run starts at 0
+    let (again, _) = preprocess(input, None, &Vec::new()).unwrap();
+    assert_eq!(output, again);
+}
+
+#[test]
+fn test_preprocess_unterminated_conditional() {
+    let input = String::from("#ifdef FOO\n#endif\n\n#ifndef BAR\nx = 1;\n");
+    let error = preprocess(input, Some(PathBuf::from("script.sqf")), &Vec::new()).unwrap_err();
+    assert_eq!("#ifndef in line 4 of \"script.sqf\" is missing an #endif.", error.to_string());
+
+    let input = String::from("#ifdef FOO\n#endif\nx = 1;\n#endif\n");
+    let error = preprocess(input, None, &Vec::new()).unwrap_err();
+    assert_eq!("#endif without #if in line 4.", error.to_string());
+
+    let input = String::from("#else\n");
+    assert!(preprocess(input, None, &Vec::new()).is_err());
+}
+
+#[test]
+fn test_preprocess_line_map() {
+    let dir = tempdir().unwrap();
+    let main = dir.path().join("main.hpp");
+    let header = dir.path().join("header.hpp");
+    let map_path = dir.path().join("main.map.json");
+
+    File::create(&header).unwrap().write_all(b"#define FOO 1\nfoo = FOO;").unwrap();
+    let input: &[u8] = b"// header\n#include \"header.hpp\"\n\nbar = 2;\n";
+    File::create(&main).unwrap().write_all(input).unwrap();
+
+    let options = PreprocessOptions {
+        line_map: Some(map_path.clone()),
+        ..Default::default()
+    };
+
+    let mut output: Vec<u8> = Vec::new();
+    cmd_preprocess(&mut std::io::Cursor::new(input), &mut output, Some(main.clone()), &Vec::new(), &options).unwrap();
+    let output = String::from_utf8(output).unwrap();
+
+    let map: serde_json::Value = serde_json::from_reader(File::open(&map_path).unwrap()).unwrap();
+    let index = output.lines().position(|l| l == "bar = 2;").unwrap();
+    assert_eq!(main.to_str().unwrap(), map[index]["file"]);
+    assert_eq!(4, map[index]["line"]);
+
+    let index = output.lines().position(|l| l == "foo = 1;").unwrap();
+    assert_eq!(header.canonicalize().unwrap().to_str().unwrap(), map[index]["file"]);
+    assert_eq!(2, map[index]["line"]);
+}
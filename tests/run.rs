@@ -0,0 +1,52 @@
+use std::fs::{File};
+use std::io::{Write};
+use std::process::{Command};
+
+use tempfile::{tempdir};
+
+use armake2::pbo::*;
+
+#[test]
+fn test_run_build_headerext() {
+    let dir = tempdir().unwrap();
+    let addon = dir.path().join("addon");
+    std::fs::create_dir(&addon).unwrap();
+
+    File::create(addon.join("script.sqf")).unwrap().write_all(b"hint \"foo\";").unwrap();
+
+    let target = dir.path().join("addon.pbo");
+    let status = Command::new(env!("CARGO_BIN_EXE_armake2"))
+        .arg("build")
+        .args(&["-e", "key=value", "--headerext", "other=1"])
+        .arg(&addon)
+        .arg(&target)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let pbo = PBO::read(&mut File::open(target).unwrap()).unwrap();
+    assert_eq!(Some(&"value".to_string()), pbo.header_extensions.get("key"));
+    assert_eq!(Some(&"1".to_string()), pbo.header_extensions.get("other"));
+}
+
+#[test]
+fn test_run_rapify_warning_line() {
+    let dir = tempdir().unwrap();
+    let source = dir.path().join("config.cpp");
+    File::create(&source).unwrap().write_all(b"name = bar;\nclass CfgPatches {\n    class foo {\n        name = bar;\n    };\n};\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_armake2"))
+        .arg("rapify")
+        .arg(&source)
+        .arg(dir.path().join("config.bin"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // warnings point to the line of the value, including in the first line
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let lines: Vec<&str> = stderr.lines().filter(|l| l.contains("unquoted-string")).collect();
+    assert_eq!(2, lines.len());
+    assert!(lines[0].contains(&format!("{}:1:", source.to_str().unwrap())), "{}", stderr);
+    assert!(lines[1].contains(&format!("{}:4:", source.to_str().unwrap())), "{}", stderr);
+}